@@ -0,0 +1,1372 @@
+use std::path::{Path, PathBuf};
+
+use crate::Type as RuntimeType;
+use crate::error::{Error, ErrorKind};
+use crate::sectionizer::Section;
+use crate::tokenizer::{PlacedToken, Token, string_to_tokens};
+
+pub use crate::tokenizer::Span;
+
+/// The statements of one file, in the order they're written.
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub path: PathBuf,
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Identifier {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VarKind {
+    /// Defined with `::`.
+    Const,
+    /// Defined with `:=` or `: type =`.
+    Mutable,
+}
+
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub span: Span,
+    pub kind: StatementKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum StatementKind {
+    /// `use file`
+    Use {
+        file: Identifier,
+    },
+    Blob {
+        name: Identifier,
+        fields: Vec<Field>,
+        methods: Vec<(Identifier, Function)>,
+    },
+    Enum {
+        name: Identifier,
+        /// The variants and the types of their payloads.
+        variants: Vec<(Identifier, Option<Type>)>,
+    },
+    Definition {
+        ident: Identifier,
+        kind: VarKind,
+        /// The type written out, if any.
+        ty: Option<Type>,
+        value: Expression,
+    },
+    /// `target = value`, or `target += value` and friends
+    /// when `op` is given.
+    Assignment {
+        op: Option<BinOp>,
+        target: Expression,
+        value: Expression,
+    },
+    /// An else-if is an if-statement alone in `fail`.
+    If {
+        condition: Expression,
+        pass: Vec<Statement>,
+        fail: Vec<Statement>,
+    },
+    /// `for init, condition, step { body }`
+    For {
+        init: Option<Box<Statement>>,
+        condition: Expression,
+        step: Box<Statement>,
+        body: Vec<Statement>,
+    },
+    /// `for name in iterable { body }`
+    ForIn {
+        name: Identifier,
+        iterable: Expression,
+        body: Vec<Statement>,
+    },
+    /// `for condition { body }`, or `for { body }` which
+    /// loops until something jumps out of it.
+    Loop {
+        condition: Option<Expression>,
+        body: Vec<Statement>,
+    },
+    Match {
+        value: Expression,
+        arms: Vec<MatchArm>,
+        /// The body of the else-arm.
+        fallback: Option<Vec<Statement>>,
+    },
+    Break,
+    Continue,
+    Ret {
+        value: Option<Expression>,
+    },
+    Yield {
+        value: Option<Expression>,
+    },
+    Block {
+        statements: Vec<Statement>,
+    },
+    Print {
+        value: Expression,
+    },
+    /// `<!>`
+    Unreachable,
+    StatementExpression {
+        value: Expression,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: Identifier,
+    pub ty: Type,
+    /// Always a literal.
+    pub default: Option<Expression>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub span: Span,
+    pub pattern: Pattern,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `name: type`
+    Type(Identifier, Type),
+    /// A literal the value is compared to.
+    Literal(Expression),
+    /// `Enum.Variant`, or `Enum.Variant(binding)` which
+    /// gives the payload a name.
+    Variant {
+        ty: Identifier,
+        variant: Identifier,
+        binding: Option<Identifier>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub span: Span,
+    pub type_params: Vec<Identifier>,
+    pub params: Vec<(Identifier, Type)>,
+    /// Void when no return type is written.
+    pub ret: Type,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    /// `<=>`
+    AssertEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expression {
+    pub span: Span,
+    pub kind: ExpressionKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExpressionKind {
+    /// A name, which can be a variable, a constant, a blob,
+    /// an enum or a namespace.
+    Read(Identifier),
+    /// `value.field`, also used for reaching into namespaces
+    /// and for the variants of enums.
+    Access(Box<Expression>, Identifier),
+    Index(Box<Expression>, Box<Expression>),
+    Call(Box<Expression>, Vec<Expression>),
+    /// `Blob { field: value }`
+    Instance {
+        blob: Box<Expression>,
+        fields: Vec<(Identifier, Expression)>,
+    },
+    /// `spawn f(args)`
+    Spawn(Box<Expression>, Vec<Expression>),
+    Function(Box<Function>),
+    Binary(BinOp, Box<Expression>, Box<Expression>),
+    Unary(UnOp, Box<Expression>),
+    /// `value as type`
+    Cast(Box<Expression>, Type),
+    Tuple(Vec<Expression>),
+    List(Vec<Expression>),
+    Dict(Vec<(Expression, Expression)>),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Nil,
+}
+
+/// A type as it's written in the source.
+#[derive(Debug, Clone)]
+pub struct Type {
+    pub span: Span,
+    pub kind: TypeKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum TypeKind {
+    /// One of the built in types, like `int` or `str`.
+    Resolved(RuntimeType),
+    /// A blob, an enum or a type parameter, possibly
+    /// reached through namespaces like `math.Vec2`.
+    UserDefined(Vec<Identifier>),
+    Union(Vec<Type>),
+    Fn(Vec<Type>, Box<Type>),
+    Tuple(Vec<Type>),
+    List(Box<Type>),
+    Dict(Box<Type>, Box<Type>),
+}
+
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
+enum Prec {
+    No,
+    Assert,
+    BoolOr,
+    BoolAnd,
+    Comp,
+    Term,
+    Factor,
+    Cast,
+    Index,
+}
+
+impl Prec {
+    fn next(&self) -> Self {
+        match self {
+            Prec::No => Prec::Assert,
+            Prec::Assert => Prec::BoolOr,
+            Prec::BoolOr => Prec::BoolAnd,
+            Prec::BoolAnd => Prec::Comp,
+            Prec::Comp => Prec::Term,
+            Prec::Term => Prec::Factor,
+            Prec::Factor => Prec::Cast,
+            Prec::Cast | Prec::Index => Prec::Index,
+        }
+    }
+}
+
+fn precedence(token: &Token) -> Prec {
+    match token {
+        Token::LeftBracket => Prec::Index,
+        Token::As => Prec::Cast,
+        Token::Star | Token::Slash => Prec::Factor,
+        Token::Minus | Token::Plus => Prec::Term,
+        Token::EqualEqual
+            | Token::Greater
+            | Token::GreaterEqual
+            | Token::Less
+            | Token::LessEqual
+            | Token::NotEqual
+            => Prec::Comp,
+        Token::And => Prec::BoolAnd,
+        Token::Or => Prec::BoolOr,
+        Token::AssertEqual => Prec::Assert,
+        _ => Prec::No,
+    }
+}
+
+macro_rules! syntax_error {
+    ($parser:expr, $( $msg:expr ),* ) => {
+        {
+            let msg = format!($( $msg ),*);
+            $parser.error(msg);
+            return Err(());
+        }
+    };
+}
+
+macro_rules! expect {
+    ($parser:expr, $exp:pat, $( $msg:expr ),* ) => {
+        match $parser.peek() {
+            $exp => { $parser.eat(); },
+            _ => syntax_error!($parser, $( $msg ),*),
+        }
+    };
+}
+
+type ParseResult<T> = Result<T, ()>;
+
+/// The type parameters, parameters and return type of a function.
+type Signature = (Vec<Identifier>, Vec<(Identifier, Type)>, Type);
+
+struct Parser<'a> {
+    tokens: &'a [PlacedToken],
+    curr: usize,
+    path: &'a Path,
+    errors: Vec<Error>,
+    /// Set in the heads of if-statements, loops and matches, where
+    /// a '{' starts the body and never a blob literal.
+    no_instance: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn new(path: &'a Path, tokens: &'a [PlacedToken]) -> Self {
+        Self {
+            tokens,
+            curr: 0,
+            path,
+            errors: Vec::new(),
+            no_instance: false,
+        }
+    }
+
+    fn peek_at(&self, at: usize) -> Token {
+        self.tokens.get(self.curr + at)
+            .map(|(token, _)| token.clone())
+            .unwrap_or(Token::EOF)
+    }
+
+    fn peek(&self) -> Token {
+        self.peek_at(0)
+    }
+
+    fn eat(&mut self) -> Token {
+        let token = self.peek();
+        if token == Token::GitConflictBegin {
+            let start = self.span();
+            while !matches!(self.peek(), Token::GitConflictEnd | Token::EOF) {
+                self.curr += 1;
+            }
+            self.errors.push(Error {
+                kind: ErrorKind::GitConflictError(start.line, self.line()),
+                file: self.path.to_path_buf(),
+                span: start,
+                message: None,
+                notes: Vec::new(),
+                stack: Vec::new(),
+            });
+        }
+        self.curr += 1;
+        token
+    }
+
+    /// The span of the current token.
+    fn span(&self) -> Span {
+        if self.tokens.is_empty() {
+            Span::default()
+        } else {
+            self.tokens[std::cmp::min(self.curr, self.tokens.len() - 1)].1
+        }
+    }
+
+    fn line(&self) -> usize {
+        self.span().line
+    }
+
+    /// The span from `start` to the end of the last eaten token.
+    /// Nodes that continue on later lines only cover their first line.
+    fn span_from(&self, start: Span) -> Span {
+        match self.curr.checked_sub(1).and_then(|last| self.tokens.get(last)) {
+            Some((_, end)) if end.line == start.line && end.col_end > start.col_start => {
+                Span { col_end: end.col_end, ..start }
+            }
+            _ => start,
+        }
+    }
+
+    /// Like [Parser::span_from], but starts at `lhs` if the operator
+    /// at `op` is on the same line.
+    fn span_after(&self, lhs: Span, op: Span) -> Span {
+        self.span_from(if lhs.line == op.line { lhs } else { op })
+    }
+
+    fn error(&mut self, message: String) {
+        // Later errors on the same line are almost always caused by the first.
+        if matches!(self.errors.last(), Some(last) if last.span.line == self.line()) {
+            return;
+        }
+        self.errors.push(Error {
+            kind: ErrorKind::SyntaxError(self.line(), self.peek()),
+            file: self.path.to_path_buf(),
+            span: self.span(),
+            message: Some(message),
+            notes: Vec::new(),
+            stack: Vec::new(),
+        });
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Token::Newline {
+            self.eat();
+        }
+    }
+
+    /// Skips to the start of the next line, skipping blocks that start
+    /// on this line as a whole. Stops before a '}' that closes the
+    /// block the error is in.
+    fn recover(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                Token::EOF => return,
+                Token::Newline if depth == 0 => {
+                    self.eat();
+                    return;
+                }
+                Token::RightBrace if depth == 0 => return,
+                Token::LeftBrace => depth += 1,
+                Token::RightBrace => depth -= 1,
+                _ => {}
+            }
+            self.eat();
+        }
+    }
+
+    /// Runs the parse function with blob literals allowed or not.
+    fn with_instances<T>(&mut self, allowed: bool, f: impl FnOnce(&mut Self) -> T) -> T {
+        let old = std::mem::replace(&mut self.no_instance, !allowed);
+        let res = f(self);
+        self.no_instance = old;
+        res
+    }
+
+    fn identifier(&mut self, what: &str) -> ParseResult<Identifier> {
+        let span = self.span();
+        match self.peek() {
+            Token::Identifier(name) => {
+                self.eat();
+                Ok(Identifier { name, span })
+            }
+            _ => syntax_error!(self, "Expected {}", what),
+        }
+    }
+
+    /// Parses statements until the end of the tokens.
+    fn outer_statements(&mut self) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek() == Token::EOF {
+                break;
+            }
+            match self.outer_statement() {
+                Ok(statement) => {
+                    statements.push(statement);
+                    if !matches!(self.peek(), Token::Newline | Token::EOF) {
+                        self.error(String::from("Expect newline or EOF after expression"));
+                        self.recover_outer();
+                    }
+                }
+                Err(_) => self.recover_outer(),
+            }
+        }
+        statements
+    }
+
+    /// Skips lines until one that looks like the start of an outer statement.
+    fn recover_outer(&mut self) {
+        self.recover();
+        while self.peek() == Token::RightBrace || !matches!(
+            (self.peek_at(0), self.peek_at(1)),
+            (Token::EOF, _)
+                | (Token::Newline, _)
+                | (Token::Use, Token::Identifier(_))
+                | (Token::Identifier(_), Token::ColonColon)
+                | (Token::Identifier(_), Token::ColonEqual)
+                | (Token::Identifier(_), Token::Colon)
+        ) {
+            if self.peek() == Token::RightBrace {
+                // Nothing to close out here.
+                self.eat();
+            }
+            self.recover();
+        }
+    }
+
+    fn outer_statement(&mut self) -> ParseResult<Statement> {
+        let span = self.span();
+        let kind = match (self.peek_at(0), self.peek_at(1), self.peek_at(2)) {
+            (Token::Use, ..) => {
+                self.eat();
+                StatementKind::Use { file: self.identifier("a file name after 'use'")? }
+            }
+
+            (Token::Identifier(_), Token::ColonColon, Token::Blob) => self.blob_statement()?,
+
+            (Token::Identifier(_), Token::ColonColon, Token::Enum) => self.enum_statement()?,
+
+            (Token::Identifier(_), Token::ColonColon, _)
+                | (Token::Identifier(_), Token::ColonEqual, _)
+                | (Token::Identifier(_), Token::Colon, _) => self.definition()?,
+
+            (a, b, c) => {
+                syntax_error!(self, "Unknown outer token sequence: {:?} {:?} {:?}. Expected 'use', function, blob or variable", a, b, c);
+            }
+        };
+        Ok(Statement { span: self.span_from(span), kind })
+    }
+
+    /// Parses `name := value`, `name :: value` and `name: type = value`.
+    fn definition(&mut self) -> ParseResult<StatementKind> {
+        let ident = self.identifier("a name")?;
+        let (kind, ty) = match self.eat() {
+            Token::ColonColon => (VarKind::Const, None),
+            Token::ColonEqual => (VarKind::Mutable, None),
+            Token::Colon => {
+                let ty = self.parse_type()?;
+                expect!(self, Token::Equal, "Expected assignment");
+                (VarKind::Mutable, Some(ty))
+            }
+            _ => syntax_error!(self, "Expected ':=', '::' or ':' in definition"),
+        };
+        let value = self.expression()?;
+        Ok(StatementKind::Definition { ident, kind, ty, value })
+    }
+
+    fn blob_statement(&mut self) -> ParseResult<StatementKind> {
+        let name = self.identifier("a name for the blob")?;
+        expect!(self, Token::ColonColon, "Expected '::' when declaring a blob");
+        expect!(self, Token::Blob, "Expected 'blob' when declaring a blob");
+        expect!(self, Token::LeftBrace, "Expected 'blob' body. AKA '{{'");
+
+        let mut fields = Vec::new();
+        let mut methods = Vec::new();
+        loop {
+            match self.peek() {
+                Token::RightBrace | Token::EOF => break,
+                Token::Newline => { self.eat(); continue; }
+                _ => {}
+            }
+
+            let field = self.identifier("identifier for field")?;
+            if self.peek() == Token::ColonColon {
+                self.eat();
+                if self.peek() != Token::Fn {
+                    syntax_error!(self, "Expected a method after '::'");
+                }
+                methods.push((field, self.function()?));
+                continue;
+            }
+
+            expect!(self, Token::Colon, "Expected ':' after field name");
+            let ty = self.parse_type()?;
+            let default = if self.peek() == Token::Equal {
+                self.eat();
+                Some(self.literal()?)
+            } else {
+                None
+            };
+            fields.push(Field { name: field, ty, default });
+        }
+        expect!(self, Token::RightBrace, "Expected '}}' after 'blob' body");
+        Ok(StatementKind::Blob { name, fields, methods })
+    }
+
+    fn enum_statement(&mut self) -> ParseResult<StatementKind> {
+        let name = self.identifier("a name for the enum")?;
+        expect!(self, Token::ColonColon, "Expected '::' when declaring an enum");
+        expect!(self, Token::Enum, "Expected 'enum' when declaring an enum");
+        expect!(self, Token::LeftBrace, "Expected 'enum' body. AKA '{{'");
+
+        let mut variants = Vec::new();
+        loop {
+            match self.peek() {
+                Token::RightBrace | Token::EOF => break,
+                Token::Newline | Token::Comma => { self.eat(); continue; }
+                _ => {}
+            }
+
+            let variant = self.identifier("identifier for variant")?;
+            let payload = if self.peek() == Token::LeftParen {
+                self.eat();
+                let ty = self.parse_type()?;
+                expect!(self, Token::RightParen, "Expected ')' after payload type");
+                Some(ty)
+            } else {
+                None
+            };
+            variants.push((variant, payload));
+        }
+        expect!(self, Token::RightBrace, "Expected '}}' after 'enum' body");
+        Ok(StatementKind::Enum { name, variants })
+    }
+
+    /// Parses the statements of a block, including the braces.
+    fn block(&mut self) -> ParseResult<Vec<Statement>> {
+        expect!(self, Token::LeftBrace, "Expected '{{' at start of block");
+        let mut statements = Vec::new();
+        loop {
+            self.skip_newlines();
+            if matches!(self.peek(), Token::RightBrace | Token::EOF) {
+                break;
+            }
+            match self.statement() {
+                Ok(statement) => {
+                    statements.push(statement);
+                    if !matches!(self.peek(), Token::Newline | Token::RightBrace) {
+                        self.error(String::from("Expect newline after statement"));
+                        self.recover();
+                    }
+                }
+                Err(_) => self.recover(),
+            }
+        }
+        expect!(self, Token::RightBrace, "Expected '}}' at end of block");
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> ParseResult<Statement> {
+        let span = self.span();
+        let kind = match (self.peek_at(0), self.peek_at(1)) {
+            (Token::Print, _) => {
+                self.eat();
+                StatementKind::Print { value: self.expression()? }
+            }
+
+            (Token::Identifier(_), Token::ColonColon)
+                | (Token::Identifier(_), Token::ColonEqual)
+                | (Token::Identifier(_), Token::Colon) => self.definition()?,
+
+            (Token::Yield, _) => {
+                self.eat();
+                StatementKind::Yield { value: self.optional_expression()? }
+            }
+
+            (Token::Ret, _) => {
+                self.eat();
+                StatementKind::Ret { value: self.optional_expression()? }
+            }
+
+            (Token::If, _) => self.if_statement()?,
+
+            (Token::For, _) => self.for_loop()?,
+
+            (Token::Match, _) => self.match_statement()?,
+
+            (Token::Break, _) => {
+                self.eat();
+                StatementKind::Break
+            }
+
+            (Token::Continue, _) => {
+                self.eat();
+                StatementKind::Continue
+            }
+
+            (Token::Unreachable, _) => {
+                self.eat();
+                StatementKind::Unreachable
+            }
+
+            (Token::LeftBrace, _) => StatementKind::Block { statements: self.block()? },
+
+            _ => {
+                let target = self.expression()?;
+                let op = match self.peek() {
+                    Token::Equal => None,
+                    Token::PlusEqual => Some(BinOp::Add),
+                    Token::MinusEqual => Some(BinOp::Sub),
+                    Token::StarEqual => Some(BinOp::Mul),
+                    Token::SlashEqual => Some(BinOp::Div),
+                    _ => {
+                        return Ok(Statement {
+                            span,
+                            kind: StatementKind::StatementExpression { value: target },
+                        });
+                    }
+                };
+                if !matches!(target.kind, ExpressionKind::Read(_)
+                                        | ExpressionKind::Access(..)
+                                        | ExpressionKind::Index(..)) {
+                    syntax_error!(self, "Cannot assign to this expression");
+                }
+                self.eat();
+                let value = self.expression()?;
+                StatementKind::Assignment { op, target, value }
+            }
+        };
+        Ok(Statement { span: self.span_from(span), kind })
+    }
+
+    /// An expression, unless the line or block ends.
+    fn optional_expression(&mut self) -> ParseResult<Option<Expression>> {
+        if matches!(self.peek(), Token::Newline | Token::RightBrace | Token::EOF) {
+            Ok(None)
+        } else {
+            Ok(Some(self.expression()?))
+        }
+    }
+
+    fn if_statement(&mut self) -> ParseResult<StatementKind> {
+        expect!(self, Token::If, "Expected 'if' at start of if-statement");
+        let condition = self.with_instances(false, Self::expression)?;
+        let pass = self.block()?;
+        let fail = if self.peek() == Token::Else {
+            self.eat();
+            match self.peek() {
+                Token::If => {
+                    let span = self.span();
+                    vec![Statement { span, kind: self.if_statement()? }]
+                }
+                Token::LeftBrace => self.block()?,
+                _ => syntax_error!(self, "Epected 'if' or '{{' after else"),
+            }
+        } else {
+            Vec::new()
+        };
+        Ok(StatementKind::If { condition, pass, fail })
+    }
+
+    fn for_loop(&mut self) -> ParseResult<StatementKind> {
+        expect!(self, Token::For, "Expected 'for' at start of for-loop");
+
+        match (self.peek_at(0), self.peek_at(1)) {
+            (Token::Identifier(_), Token::In) => {
+                let name = self.identifier("loop variable")?;
+                self.eat();
+                let iterable = self.with_instances(false, Self::expression)?;
+                let body = self.block()?;
+                Ok(StatementKind::ForIn { name, iterable, body })
+            }
+
+            (Token::Identifier(_), Token::ColonEqual) | (Token::Comma, _) => {
+                let (init, condition, step) = self.with_instances(false, |parser| {
+                    let init = if parser.peek() == Token::Comma {
+                        None
+                    } else {
+                        let span = parser.span();
+                        Some(Box::new(Statement { span, kind: parser.definition()? }))
+                    };
+                    expect!(parser, Token::Comma, "Expect ',' between initalizer and loop expression");
+                    let condition = parser.expression()?;
+                    expect!(parser, Token::Comma, "Expect ',' between initalizer and loop expression");
+                    let step = Box::new(parser.statement()?);
+                    Ok((init, condition, step))
+                })?;
+                let body = self.block()?;
+                Ok(StatementKind::For { init, condition, step, body })
+            }
+
+            (Token::LeftBrace, _) => {
+                let body = self.block()?;
+                Ok(StatementKind::Loop { condition: None, body })
+            }
+
+            _ => {
+                let condition = Some(self.with_instances(false, Self::expression)?);
+                let body = self.block()?;
+                Ok(StatementKind::Loop { condition, body })
+            }
+        }
+    }
+
+    fn match_statement(&mut self) -> ParseResult<StatementKind> {
+        expect!(self, Token::Match, "Expected 'match' at start of match-statement");
+        let value = self.with_instances(false, Self::expression)?;
+        expect!(self, Token::LeftBrace, "Expected '{{' after value to match");
+
+        let mut arms = Vec::new();
+        let mut fallback = None;
+        let mut misplaced = false;
+        loop {
+            match (self.peek_at(0), self.peek_at(1), self.peek_at(2)) {
+                (Token::Newline, ..) => {
+                    self.eat();
+                    continue;
+                }
+                (Token::RightBrace, ..) | (Token::EOF, ..) => break,
+                _ if fallback.is_some() && !misplaced => {
+                    // The arms after it are still parsed, they might have errors too.
+                    self.error(String::from("The else-arm has to be the last arm of a match"));
+                    misplaced = true;
+                    continue;
+                }
+                (Token::Else, ..) => {
+                    self.eat();
+                    fallback = Some(self.block()?);
+                }
+                (Token::Identifier(_), Token::Colon, _) => {
+                    let span = self.span();
+                    let name = self.identifier("a name")?;
+                    self.eat();
+                    let ty = self.parse_type()?;
+                    let body = self.block()?;
+                    arms.push(MatchArm { span, pattern: Pattern::Type(name, ty), body });
+                }
+                (Token::Identifier(_), Token::Dot, Token::Identifier(_)) => {
+                    let span = self.span();
+                    let ty = self.identifier("an enum")?;
+                    self.eat();
+                    let variant = self.identifier("a variant")?;
+                    let binding = if self.peek() == Token::LeftParen {
+                        self.eat();
+                        let binding = self.identifier("a name for the payload")?;
+                        expect!(self, Token::RightParen, "Expected ')' after payload name");
+                        Some(binding)
+                    } else {
+                        None
+                    };
+                    let body = self.block()?;
+                    arms.push(MatchArm { span, pattern: Pattern::Variant { ty, variant, binding }, body });
+                }
+                _ => {
+                    let span = self.span();
+                    let literal = self.literal()?;
+                    let body = self.block()?;
+                    arms.push(MatchArm { span, pattern: Pattern::Literal(literal), body });
+                }
+            }
+
+            if !matches!(self.peek(), Token::Newline | Token::RightBrace) {
+                syntax_error!(self, "Expected newline after match-arm");
+            }
+        }
+        expect!(self, Token::RightBrace, "Expected '}}' after match-arms");
+        Ok(StatementKind::Match { value, arms, fallback })
+    }
+
+    /// Parses a literal value, a negative number is also a literal.
+    fn literal(&mut self) -> ParseResult<Expression> {
+        let span = self.span();
+        let negative = if self.peek() == Token::Minus {
+            self.eat();
+            true
+        } else {
+            false
+        };
+        let kind = match (self.peek(), negative) {
+            (Token::Int(i), _) => ExpressionKind::Int(if negative { -i } else { i }),
+            (Token::Float(f), _) => ExpressionKind::Float(if negative { -f } else { f }),
+            (Token::Bool(b), false) => ExpressionKind::Bool(b),
+            (Token::String(s), false) => ExpressionKind::Str(s),
+            (Token::Nil, false) => ExpressionKind::Nil,
+            _ => syntax_error!(self, "Expected a literal"),
+        };
+        self.eat();
+        Ok(Expression { span: self.span_from(span), kind })
+    }
+
+    fn function(&mut self) -> ParseResult<Function> {
+        let span = self.span();
+        expect!(self, Token::Fn, "Expected 'fn' at start of function");
+        let (type_params, params, ret) = match self.signature(span) {
+            Ok(signature) => signature,
+            Err(_) => {
+                // The body is still parsed, so the errors in it are found too.
+                while !matches!(self.peek(), Token::LeftBrace | Token::Newline | Token::EOF) {
+                    self.eat();
+                }
+                if self.peek() == Token::LeftBrace {
+                    let _ = self.with_instances(true, Self::block);
+                }
+                return Err(());
+            }
+        };
+        let body = self.with_instances(true, Self::block)?;
+        Ok(Function { span, type_params, params, ret, body })
+    }
+
+    fn signature(&mut self, span: Span) -> ParseResult<Signature> {
+
+        let mut type_params = Vec::new();
+        if self.peek() == Token::Less {
+            self.eat();
+            loop {
+                type_params.push(self.identifier("a type parameter")?);
+                if self.peek() == Token::Comma {
+                    self.eat();
+                } else {
+                    expect!(self, Token::Greater, "Expected '>' after type parameters");
+                    break;
+                }
+            }
+        }
+
+        let mut params = Vec::new();
+        let mut ret = Type { span, kind: TypeKind::Resolved(RuntimeType::Void) };
+        loop {
+            match self.peek() {
+                Token::Identifier(_) => {
+                    let name = self.identifier("a parameter")?;
+                    expect!(self, Token::Colon, "Expected ':' after parameter name");
+                    params.push((name, self.parse_type()?));
+                    if !matches!(self.peek(), Token::Arrow | Token::LeftBrace) {
+                        expect!(self, Token::Comma, "Expected ',' after parameter");
+                    }
+                }
+                Token::LeftBrace => break,
+                Token::Arrow => {
+                    self.eat();
+                    ret = self.parse_type()?;
+                    break;
+                }
+                _ => syntax_error!(self, "Expected '->' or more paramters in function definition"),
+            }
+        }
+        Ok((type_params, params, ret))
+    }
+
+    /// Entry point for all expression parsing.
+    fn expression(&mut self) -> ParseResult<Expression> {
+        if self.peek() == Token::Fn {
+            let span = self.span();
+            let function = self.function()?;
+            Ok(Expression { span: self.span_from(span), kind: ExpressionKind::Function(Box::new(function)) })
+        } else {
+            self.parse_precedence(Prec::No)
+        }
+    }
+
+    fn parse_precedence(&mut self, prec: Prec) -> ParseResult<Expression> {
+        let mut expr = self.prefix()?;
+        loop {
+            let next = precedence(&self.peek());
+            if next == Prec::No || next < prec {
+                break;
+            }
+            expr = self.infix(expr)?;
+        }
+        Ok(expr)
+    }
+
+    fn prefix(&mut self) -> ParseResult<Expression> {
+        let span = self.span();
+        let kind = match self.peek() {
+            Token::Identifier(_) => return self.variable_expression(),
+            Token::LeftParen => return self.grouping_or_tuple(),
+            Token::LeftBracket => return self.list(),
+            Token::LeftBrace => return self.dict(),
+            Token::Minus | Token::Bang => {
+                let op = if self.eat() == Token::Minus { UnOp::Neg } else { UnOp::Not };
+                let value = self.parse_precedence(Prec::Factor)?;
+                ExpressionKind::Unary(op, Box::new(value))
+            }
+            Token::Spawn => {
+                self.eat();
+                match self.parse_precedence(Prec::Index)? {
+                    Expression { kind: ExpressionKind::Call(callee, args), .. } => {
+                        ExpressionKind::Spawn(callee, args)
+                    }
+                    _ => syntax_error!(self, "Expected a function call after 'spawn'"),
+                }
+            }
+            Token::Int(i) => { self.eat(); ExpressionKind::Int(i) }
+            Token::Float(f) => { self.eat(); ExpressionKind::Float(f) }
+            Token::Bool(b) => { self.eat(); ExpressionKind::Bool(b) }
+            Token::String(s) => { self.eat(); ExpressionKind::Str(s) }
+            Token::Nil => { self.eat(); ExpressionKind::Nil }
+            _ => syntax_error!(self, "Invalid expression"),
+        };
+        Ok(Expression { span: self.span_from(span), kind })
+    }
+
+    fn infix(&mut self, lhs: Expression) -> ParseResult<Expression> {
+        let span = self.span();
+        let op = match self.eat() {
+            Token::LeftBracket => {
+                let index = self.with_instances(true, Self::expression)?;
+                expect!(self, Token::RightBracket, "Expected ']' around index");
+                let expr = Expression {
+                    span: self.span_after(lhs.span, span),
+                    kind: ExpressionKind::Index(Box::new(lhs), Box::new(index)),
+                };
+                return self.fields_and_calls(expr);
+            }
+            Token::As => {
+                let ty = self.parse_type()?;
+                let span = self.span_after(lhs.span, span);
+                return Ok(Expression { span, kind: ExpressionKind::Cast(Box::new(lhs), ty) });
+            }
+            Token::Plus => BinOp::Add,
+            Token::Minus => BinOp::Sub,
+            Token::Star => BinOp::Mul,
+            Token::Slash => BinOp::Div,
+            Token::EqualEqual => BinOp::Equal,
+            Token::NotEqual => BinOp::NotEqual,
+            Token::Less => BinOp::Less,
+            Token::Greater => BinOp::Greater,
+            Token::LessEqual => BinOp::LessEqual,
+            Token::GreaterEqual => BinOp::GreaterEqual,
+            Token::AssertEqual => BinOp::AssertEqual,
+            Token::And => BinOp::And,
+            Token::Or => BinOp::Or,
+            _ => syntax_error!(self, "Illegal operator"),
+        };
+        let rhs = self.parse_precedence(precedence(&self.tokens[self.curr - 1].0).next())?;
+        let span = self.span_after(lhs.span, span);
+        Ok(Expression { span, kind: ExpressionKind::Binary(op, Box::new(lhs), Box::new(rhs)) })
+    }
+
+    fn variable_expression(&mut self) -> ParseResult<Expression> {
+        let ident = self.identifier("a name")?;
+        let mut expr = Expression { span: ident.span, kind: ExpressionKind::Read(ident) };
+
+        // A path of names, like `a.b.C`, can be followed by a blob literal.
+        while self.peek() == Token::Dot {
+            self.eat();
+            let field = self.identifier("fieldname after '.'")?;
+            let span = self.span_after(expr.span, field.span);
+            expr = Expression { span, kind: ExpressionKind::Access(Box::new(expr), field) };
+        }
+        if self.blob_literal_ahead() {
+            expr = self.blob_literal(expr)?;
+        }
+        self.fields_and_calls(expr)
+    }
+
+    fn blob_literal_ahead(&self) -> bool {
+        let mut i = 1;
+        while self.peek_at(i) == Token::Newline {
+            i += 1;
+        }
+        !self.no_instance
+            && self.peek() == Token::LeftBrace
+            && matches!((self.peek_at(i), self.peek_at(i + 1)), (Token::Identifier(_), Token::Colon))
+    }
+
+    /// Parses the fields of a blob literal, like `{ x: 1.0, y: 2.0 }`.
+    fn blob_literal(&mut self, blob: Expression) -> ParseResult<Expression> {
+        let span = self.span();
+        expect!(self, Token::LeftBrace, "Expected '{{' at start of blob literal");
+        let mut fields = Vec::new();
+        loop {
+            match self.peek() {
+                Token::RightBrace | Token::EOF => break,
+                Token::Newline | Token::Comma => { self.eat(); }
+                Token::Identifier(_) => {
+                    let field = self.identifier("a field")?;
+                    expect!(self, Token::Colon, "Expected ':' after field name");
+                    let value = self.with_instances(true, Self::expression)?;
+                    fields.push((field, value));
+                    if !matches!(self.peek(), Token::Comma | Token::Newline | Token::RightBrace) {
+                        syntax_error!(self, "Expected ',' or '}}' after field");
+                    }
+                }
+                _ => syntax_error!(self, "Expected field name in blob literal"),
+            }
+        }
+        expect!(self, Token::RightBrace, "Expected '}}' after blob literal");
+        let span = self.span_after(blob.span, span);
+        Ok(Expression { span, kind: ExpressionKind::Instance { blob: Box::new(blob), fields } })
+    }
+
+    /// Parses field accesses and calls on the given value.
+    fn fields_and_calls(&mut self, mut expr: Expression) -> ParseResult<Expression> {
+        loop {
+            let lhs = expr.span;
+            let span = self.span();
+            let kind = match self.peek() {
+                Token::Dot => {
+                    self.eat();
+                    let field = self.identifier("fieldname after '.'")?;
+                    ExpressionKind::Access(Box::new(expr), field)
+                }
+                Token::LeftParen | Token::Bang => {
+                    let args = self.with_instances(true, Self::arguments)?;
+                    ExpressionKind::Call(Box::new(expr), args)
+                }
+                _ => return Ok(expr),
+            };
+            expr = Expression { span: self.span_after(lhs, span), kind };
+        }
+    }
+
+    /// The arguments of a call, either `(a, b)` or `! a, b`
+    /// which lasts until the end of the line.
+    fn arguments(&mut self) -> ParseResult<Vec<Expression>> {
+        let mut args = Vec::new();
+        match self.eat() {
+            Token::LeftParen => {
+                loop {
+                    self.skip_newlines();
+                    match self.peek() {
+                        Token::EOF => syntax_error!(self, "Unexpected EOF in function call"),
+                        Token::RightParen => {
+                            self.eat();
+                            break;
+                        }
+                        _ => {
+                            args.push(self.expression()?);
+                            self.skip_newlines();
+                            if self.peek() != Token::RightParen {
+                                expect!(self, Token::Comma, "Expected ',' after argument");
+                            }
+                        }
+                    }
+                }
+            }
+            Token::Bang => {
+                while !matches!(self.peek(), Token::Newline | Token::RightBrace | Token::EOF) {
+                    args.push(self.expression()?);
+                    if self.peek() == Token::Comma {
+                        self.eat();
+                    }
+                }
+            }
+            _ => syntax_error!(self, "Invalid function call. Expected '!' or '('"),
+        }
+        Ok(args)
+    }
+
+    fn grouping_or_tuple(&mut self) -> ParseResult<Expression> {
+        let span = self.span();
+        expect!(self, Token::LeftParen, "Expected '(' for grouping or tuple.");
+        let (mut values, trailing_comma) = self.with_instances(true, |parser| {
+            let mut values = Vec::new();
+            let trailing_comma = loop {
+                parser.skip_newlines();
+                match parser.peek() {
+                    Token::RightParen | Token::EOF => break false,
+                    _ => {
+                        values.push(parser.expression()?);
+                        parser.skip_newlines();
+                        match parser.peek() {
+                            Token::Comma => {
+                                parser.eat();
+                                parser.skip_newlines();
+                                if parser.peek() == Token::RightParen {
+                                    break true;
+                                }
+                            }
+                            Token::RightParen => {}
+                            _ => syntax_error!(parser, "Expected ',' or ')' after tuple element"),
+                        }
+                    }
+                }
+            };
+            Ok((values, trailing_comma))
+        })?;
+        expect!(self, Token::RightParen, "Expected ')' after tuple");
+        if values.len() == 1 && !trailing_comma {
+            Ok(values.pop().unwrap())
+        } else {
+            Ok(Expression { span: self.span_from(span), kind: ExpressionKind::Tuple(values) })
+        }
+    }
+
+    fn list(&mut self) -> ParseResult<Expression> {
+        let span = self.span();
+        expect!(self, Token::LeftBracket, "Expected '[' at start of list");
+        let values = self.with_instances(true, |parser| {
+            let mut values = Vec::new();
+            loop {
+                parser.skip_newlines();
+                match parser.peek() {
+                    Token::RightBracket | Token::EOF => break,
+                    Token::Comma => syntax_error!(parser, "Lists must begin with an element or ']'"),
+                    _ => {
+                        values.push(parser.expression()?);
+                        parser.skip_newlines();
+                        match parser.peek() {
+                            Token::Comma => { parser.eat(); }
+                            Token::RightBracket => {}
+                            _ => syntax_error!(parser, "Expected ',' or ']' after list element"),
+                        }
+                    }
+                }
+            }
+            Ok(values)
+        })?;
+        expect!(self, Token::RightBracket, "Expected ']' after list");
+        Ok(Expression { span: self.span_from(span), kind: ExpressionKind::List(values) })
+    }
+
+    fn dict(&mut self) -> ParseResult<Expression> {
+        let span = self.span();
+        expect!(self, Token::LeftBrace, "Expected '{{' at start of dict");
+        let values = self.with_instances(true, |parser| {
+            let mut values = Vec::new();
+            loop {
+                parser.skip_newlines();
+                match parser.peek() {
+                    Token::RightBrace | Token::EOF => break,
+                    Token::Comma => syntax_error!(parser, "Dicts must begin with an element or '}}'"),
+                    _ => {
+                        let key = parser.expression()?;
+                        expect!(parser, Token::Colon, "Expected ':' after dict key");
+                        let value = parser.expression()?;
+                        values.push((key, value));
+                        match parser.peek() {
+                            Token::Comma => { parser.eat(); }
+                            Token::RightBrace | Token::Newline => {}
+                            _ => syntax_error!(parser, "Expected ',' or '}}' after dict element"),
+                        }
+                    }
+                }
+            }
+            Ok(values)
+        })?;
+        expect!(self, Token::RightBrace, "Expected '}}' after dict");
+        Ok(Expression { span: self.span_from(span), kind: ExpressionKind::Dict(values) })
+    }
+
+    fn parse_type(&mut self) -> ParseResult<Type> {
+        let span = self.span();
+        let mut tys = vec![self.parse_simple_type()?];
+        loop {
+            match self.peek() {
+                Token::QuestionMark => {
+                    self.eat();
+                    tys.push(Type { span, kind: TypeKind::Resolved(RuntimeType::Void) });
+                    break;
+                }
+                Token::Pipe => {
+                    self.eat();
+                    tys.push(self.parse_simple_type()?);
+                }
+                _ => break,
+            }
+        }
+        if tys.len() == 1 {
+            Ok(tys.pop().unwrap())
+        } else {
+            Ok(Type { span: self.span_from(span), kind: TypeKind::Union(tys) })
+        }
+    }
+
+    fn parse_simple_type(&mut self) -> ParseResult<Type> {
+        let span = self.span();
+        let kind = match self.peek() {
+            Token::Fn => {
+                self.eat();
+                let mut params = Vec::new();
+                let ret = loop {
+                    match self.peek() {
+                        Token::Identifier(_) | Token::Fn | Token::LeftBracket | Token::LeftParen => {
+                            params.push(self.parse_type()?);
+                            if self.peek() == Token::Comma {
+                                self.eat();
+                            }
+                        }
+                        Token::Arrow => {
+                            self.eat();
+                            // Nothing after the arrow means nothing is returned.
+                            let void = Type { span: self.span(), kind: TypeKind::Resolved(RuntimeType::Void) };
+                            break if matches!(self.peek(), Token::Identifier(_)
+                                                         | Token::Fn
+                                                         | Token::LeftBracket
+                                                         | Token::LeftParen) {
+                                self.parse_type()?
+                            } else {
+                                void
+                            };
+                        }
+                        Token::Comma | Token::Equal => {
+                            break Type { span, kind: TypeKind::Resolved(RuntimeType::Void) };
+                        }
+                        token => syntax_error!(self, "Function type signature contains non-type {:?}", token),
+                    }
+                };
+                TypeKind::Fn(params, Box::new(ret))
+            }
+
+            Token::LeftParen => {
+                self.eat();
+                let mut elements = Vec::new();
+                loop {
+                    if self.peek() == Token::RightParen {
+                        self.eat();
+                        break;
+                    }
+                    elements.push(self.parse_type()?);
+                    if self.peek() == Token::RightParen {
+                        self.eat();
+                        break;
+                    }
+                    expect!(self, Token::Comma, "Expect comma efter element in tuple");
+                }
+                TypeKind::Tuple(elements)
+            }
+
+            Token::LeftBracket => {
+                self.eat();
+                let ty = self.parse_type()?;
+                if self.peek() == Token::Colon {
+                    self.eat();
+                    let value = self.parse_type()?;
+                    expect!(self, Token::RightBracket, "Expected ']' after dict type");
+                    TypeKind::Dict(Box::new(ty), Box::new(value))
+                } else {
+                    expect!(self, Token::RightBracket, "Expected ']' after list type");
+                    TypeKind::List(Box::new(ty))
+                }
+            }
+
+            Token::Identifier(name) => {
+                let builtin = match name.as_str() {
+                    "void" => Some(RuntimeType::Void),
+                    "int" => Some(RuntimeType::Int),
+                    "float" => Some(RuntimeType::Float),
+                    "bool" => Some(RuntimeType::Bool),
+                    "str" => Some(RuntimeType::String),
+                    "coroutine" => Some(RuntimeType::Coroutine),
+                    "range" => Some(RuntimeType::Range),
+                    _ => None,
+                };
+                if let Some(builtin) = builtin {
+                    self.eat();
+                    TypeKind::Resolved(builtin)
+                } else {
+                    let mut path = vec![self.identifier("a type")?];
+                    while self.peek() == Token::Dot {
+                        self.eat();
+                        path.push(self.identifier("a type after '.'")?);
+                    }
+                    TypeKind::UserDefined(path)
+                }
+            }
+
+            _ => syntax_error!(self, "Expected a type"),
+        };
+        Ok(Type { span: self.span_from(span), kind })
+    }
+}
+
+/// Parses the source of a single file. The path is never read, it's
+/// used for error reporting, and files pulled in with `use` are left alone.
+pub fn parse(source: &str, path: &Path) -> Result<Module, Vec<Error>> {
+    let tokens = string_to_tokens(source);
+    let mut parser = Parser::new(path, &tokens);
+    let statements = parser.outer_statements();
+
+    if parser.errors.is_empty() {
+        Ok(Module { path: path.to_path_buf(), statements })
+    } else {
+        Err(parser.errors)
+    }
+}
+
+/// Parses the sections of all files into one module per file, in the
+/// order the files are sectionized.
+pub(crate) fn parse_sections(sections: &[Section]) -> Result<Vec<Module>, Vec<Error>> {
+    let mut modules: Vec<Module> = Vec::new();
+    let mut errors = Vec::new();
+    for section in sections {
+        if !matches!(modules.last(), Some(module) if module.path == section.path) {
+            modules.push(Module { path: section.path.clone(), statements: Vec::new() });
+        }
+        let module = modules.last_mut().unwrap();
+
+        let mut parser = Parser::new(&section.path, &section.tokens);
+        module.statements.append(&mut parser.outer_statements());
+        errors.append(&mut parser.errors);
+    }
+
+    if errors.is_empty() {
+        Ok(modules)
+    } else {
+        Err(errors)
+    }
+}
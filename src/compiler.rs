@@ -3,71 +3,30 @@ use std::cell::RefCell;
 use std::collections::{HashMap, HashSet, hash_map::Entry};
 use std::rc::Rc;
 
-use crate::{Blob, Block, Op, Prog, RustFunction, Type, Value};
-use crate::error::{Error, ErrorKind};
-use crate::sectionizer::Section;
-use crate::tokenizer::Token;
-
-macro_rules! nextable_enum {
-    ( $name:ident { $( $thing:ident ),* $( , )? } ) => {
-        #[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
-        enum $name {
-            $( $thing, )*
-        }
-
-        impl $name {
-            pub fn next(&self) -> Self {
-                *[$( $name::$thing, )*].iter()
-                    .find(|x| { x > &self })
-                    .unwrap_or(self)
-            }
-        }
-    };
-}
+use crate::{Blob, Block, Enum, Op, Prog, RustFunction, Type, Value};
+use crate::ast::{self, BinOp, Expression, ExpressionKind, Field, Function, Identifier, MatchArm, Module, Pattern, Statement, StatementKind, TypeKind, UnOp, VarKind};
+use crate::error::{closest, did_you_mean, Error, ErrorKind};
+use crate::sectionizer::use_path;
+use crate::typechecker::Inferred;
+use crate::tokenizer::{Span, Token};
 
 macro_rules! error {
-    ($thing:expr, $( $msg:expr ),* ) => {
+    ($compiler:expr, $ident:expr, $( $msg:expr ),* ) => {
         {
-            let msg = Some(format!($( $msg ),*).into());
-            let err = ErrorKind::SyntaxError($thing.line(), $thing.peek());
-            $thing.error(err, msg);
-        }
-    };
-}
-
-macro_rules! expect {
-    ($thing:expr, $exp_head:pat $( | $exp_rest:pat ),* , $( $msg:expr ),*) => {
-        match $thing.peek() {
-            $exp_head $( | $exp_rest )* => { $thing.eat(); true },
-            _ => { error!($thing, $( $msg ),*); false } ,
+            let ident: &Identifier = $ident;
+            $compiler.name_error(&ident.name, ident.span, format!($( $msg ),*));
         }
     };
 }
 
-macro_rules! rest_of_line_contains {
-    ($compiler:expr, $head:pat $( | $tail:pat )* ) => {
-        {
-            let mut i = 0;
-            while match $compiler.peek_at(i) {
-                Token::Newline | Token::EOF => false,
-                $head $( | $tail )* => false,
-                _ => true,
-            } {
-                i += 1;
-            }
-            matches!($compiler.peek_at(i), $head $( | $tail )*)
-        }
-    }
-}
-
 macro_rules! push_frame {
-    ($compiler:expr, $block:expr, $code:tt) => {
+    ($compiler:expr, $span:expr, $code:tt) => {
         {
             $compiler.frames_mut().push(Frame::new());
 
             // Return value stored as a variable
             let var = Variable::new("", true, Type::Unknown);
-            $compiler.define(var).unwrap();
+            $compiler.define(var, $span).unwrap();
 
             $code
 
@@ -75,27 +34,15 @@ macro_rules! push_frame {
             // 0-th slot is the function itself.
             for var in frame.stack.iter().skip(1) {
                 if !(var.read || var.upvalue) {
-                    let e = ErrorKind::SyntaxError(
-                        var.line,
-                        Token::Identifier(var.name.clone()
-                    ));
-                    $compiler.error_on_line(
-                        e,
-                        var.line,
-                        Some(format!("Unused value '{}'", var.name))
-                    );
+                    $compiler.name_error(&var.name, var.span, format!("Unused value '{}'", var.name));
                 }
-                $compiler.panic = false;
             }
-            // The 0th slot is the return value, which is passed out
-            // from functions, and should not be popped.
-            0
         }
     };
 }
 
 macro_rules! push_scope {
-    ($compiler:expr, $block:expr, $code:tt) => {
+    ($compiler:expr, $block:expr, $span:expr, $code:tt) => {
         let ss = $compiler.stack().len();
         $compiler.frame_mut().scope += 1;
 
@@ -106,49 +53,29 @@ macro_rules! push_scope {
         let mut errors = Vec::new();
         for var in $compiler.frame().stack.iter().skip(ss).rev() {
             if !(var.read || var.upvalue) {
-                let e = ErrorKind::SyntaxError(
-                    var.line,
-                    Token::Identifier(var.name.clone()
-                ));
-                errors.push((
-                    e,
-                    var.line,
-                    format!("Unused value: '{}'", var.name),)
-                );
+                errors.push((var.name.clone(), var.span));
             }
             if var.captured {
-                add_op($compiler, $block, Op::PopUpvalue);
+                $block.add(Op::PopUpvalue, $span);
             } else {
-                add_op($compiler, $block, Op::Pop);
+                $block.add(Op::Pop, $span);
             }
         }
 
-        for (e, l, m) in errors.iter() {
-            $compiler.error_on_line(e.clone(), *l, Some(m.clone()));
-            $compiler.panic = false;
+        for (name, span) in errors.iter() {
+            $compiler.name_error(name, *span, format!("Unused value: '{}'", name));
         }
         $compiler.stack_mut().truncate(ss);
     };
 }
 
-nextable_enum!(Prec {
-    No,
-    Assert,
-    BoolOr,
-    BoolAnd,
-    Comp,
-    Term,
-    Factor,
-    Index,
-});
-
 #[derive(Clone, Debug)]
 struct Variable {
     name: String,
     typ: Type,
     scope: usize,
     slot: usize,
-    line: usize,
+    span: Span,
 
     outer_slot: usize,
     outer_upvalue: bool,
@@ -167,7 +94,7 @@ impl Variable {
             typ,
             scope: 0,
             slot: 0,
-            line: 0,
+            span: Span::default(),
 
             outer_slot: 0,
             outer_upvalue: false,
@@ -179,6 +106,15 @@ impl Variable {
             read: false,
         }
     }
+
+    /// Takes up the slot of a global defined in another file.
+    fn placeholder(slot: usize) -> Self {
+        Self {
+            slot,
+            read: true,
+            ..Self::new("", false, Type::Unknown)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -300,63 +236,119 @@ impl CompilerContext {
 
 #[derive(Debug, Clone)]
 enum Name {
-    Slot(usize, usize),
-    Unknown(usize, usize),
+    Slot(usize, Span),
+    /// Used before it's defined, if it ever is. Also holds the closest
+    /// variable that was in scope where the name was first used.
+    Unknown(usize, Span, Option<String>),
+    Namespace(PathBuf),
+}
+
+/// The names that can be read from the frames and namespace.
+fn readable_names<'a>(frames: &'a [Frame], namespace: &'a Namespace) -> impl Iterator<Item = &'a String> {
+    let variables = frames.iter()
+        .flat_map(|frame| frame.stack.iter().chain(frame.upvalues.iter()))
+        .filter(|var| var.active)
+        .map(|var| &var.name);
+    let constants = namespace.iter()
+        .filter(|(_, name)| !matches!(name, Name::Unknown(..)))
+        .map(|(name, _)| name);
+    // Globals from other files get names that can't be written.
+    variables.chain(constants).filter(|name| !name.is_empty() && !name.contains('.'))
+}
+
+/// What a name, or a path of names like `a.b.c`, refers to.
+enum Reference {
+    /// The value is already on the stack.
+    Value,
     Namespace(PathBuf),
+    Enum(Rc<Enum>),
+    /// A variant of an enum, which might still need its payload.
+    Variant(Rc<Enum>, usize),
 }
 
 pub(crate) struct Compiler {
-    current_token: usize,
-    current_section: usize,
-    sections: Vec<Section>,
+    current_file: PathBuf,
 
     contextes: HashMap<PathBuf, CompilerContext>,
 
-    panic: bool,
     errors: Vec<Error>,
 
     blocks: Vec<Rc<RefCell<Block>>>,
     blob_id: usize,
 
+    /// The type parameters of the functions being compiled.
+    type_params: Vec<String>,
+
     functions: HashMap<String, (usize, RustFunction)>,
 
     strings: Vec<String>,
 
     constants: Vec<Value>,
     values: HashMap<Value, usize>,
+
+    /// Variables are only checked while running in strict mode,
+    /// so the checks are only compiled in for it.
+    strict: bool,
+
+    /// The types of variables defined without writing out a type.
+    inferred: Inferred,
 }
 
-/// Helper function for adding operations to the given block.
-fn add_op(compiler: &Compiler, block: &mut Block, op: Op) -> usize {
-    block.add(op, compiler.line())
+/// The ops that compute the binary operator.
+fn binary_ops(op: BinOp) -> &'static [Op] {
+    match op {
+        BinOp::Add => &[Op::Add],
+        BinOp::Sub => &[Op::Sub],
+        BinOp::Mul => &[Op::Mul],
+        BinOp::Div => &[Op::Div],
+        BinOp::AssertEqual => &[Op::Equal, Op::Assert],
+        BinOp::Equal => &[Op::Equal],
+        BinOp::Less => &[Op::Less],
+        BinOp::Greater => &[Op::Greater],
+        BinOp::NotEqual => &[Op::Equal, Op::Not],
+        BinOp::LessEqual => &[Op::Greater, Op::Not],
+        BinOp::GreaterEqual => &[Op::Less, Op::Not],
+        // These short circuit, so they're compiled to jumps.
+        BinOp::And | BinOp::Or => &[],
+    }
 }
 
-impl Compiler {
-    pub(crate) fn new(sections: Vec<Section>) -> Self {
-        let contextes = sections
-            .iter()
-            .map(|section| (section.path.to_path_buf(), CompilerContext::new()))
-            .collect();
+/// The value of a literal, the parser makes sure nothing else
+/// is used where literals are expected.
+fn literal(expr: &Expression) -> Value {
+    match &expr.kind {
+        ExpressionKind::Int(i) => Value::Int(*i),
+        ExpressionKind::Float(f) => Value::Float(*f),
+        ExpressionKind::Bool(b) => Value::Bool(*b),
+        ExpressionKind::Str(s) => Value::String(Rc::new(s.clone())),
+        _ => Value::Nil,
+    }
+}
 
+impl Compiler {
+    pub(crate) fn new() -> Self {
         Self {
-            current_token: 0,
-            current_section: 0,
-            sections,
+            current_file: PathBuf::new(),
 
-            contextes,
+            contextes: HashMap::new(),
 
-            panic: false,
             errors: vec![],
 
             blocks: Vec::new(),
             blob_id: 0,
 
+            type_params: Vec::new(),
+
             functions: HashMap::new(),
 
             strings: Vec::new(),
 
             constants: vec![],
             values: HashMap::new(),
+
+            strict: false,
+
+            inferred: Inferred::new(),
         }
     }
 
@@ -366,14 +358,14 @@ impl Compiler {
         id
     }
 
-    fn add_namespace(&mut self, name: String) {
-        let path = Path::new(&format!("{}.sy", name)).to_path_buf();
-        match self.names_mut().entry(name.clone()) {
+    fn add_namespace(&mut self, ident: &Identifier) {
+        let path = use_path(self.current_file(), &ident.name);
+        match self.names_mut().entry(ident.name.clone()) {
             Entry::Vacant(v) => {
                 v.insert(Name::Namespace(path));
             }
             Entry::Occupied(_) => {
-                error!(self, "Namespace {} already present", name);
+                error!(self, ident, "Namespace {} already present", ident.name);
             }
         }
     }
@@ -406,12 +398,8 @@ impl Compiler {
         self.strings.len() - 1
     }
 
-    fn section(&self) -> &Section {
-        &self.sections[self.current_section]
-    }
-
     fn current_file(&self) -> &Path {
-        &self.section().path
+        &self.current_file
     }
 
     fn current_context(&self) -> &CompilerContext {
@@ -475,337 +463,74 @@ impl Compiler {
         &mut self.frame_mut().stack
     }
 
-    /// Used to recover from a panic so the rest of the code can be parsed.
-    fn clear_panic(&mut self) {
-        if self.panic {
-            self.panic = false;
-
-            while match self.peek() {
-                Token::EOF | Token::Newline => false,
-                _ => true,
-            } {
-                self.eat();
-            }
-            self.eat();
-        }
-    }
-
-    fn error(&mut self, kind: ErrorKind, message: Option<String>) {
-        self.error_on_line(kind, self.line(), message);
-    }
-
-    fn error_on_line(&mut self, kind: ErrorKind, line: usize, message: Option<String>) {
-        if self.panic { return }
-        self.panic = true;
+    fn error_on_line(&mut self, kind: ErrorKind, span: Span, message: Option<String>) {
         self.errors.push(Error {
             kind,
             file: self.current_file().to_path_buf(),
-            line,
+            span,
             message,
+            notes: Vec::new(),
+            stack: Vec::new(),
         });
     }
 
-    fn init_section(&mut self, section: usize) {
-        self.current_token = 0;
-        self.current_section = section;
-    }
-
-    fn peek(&self) -> Token {
-        self.peek_at(0)
-    }
-
-    fn peek_at(&self, at: usize) -> Token {
-        if self.section().tokens.len() <= self.current_token + at {
-            crate::tokenizer::Token::EOF
-        } else {
-            self.section().tokens[self.current_token + at].0.clone()
-        }
+    /// An error about the name written at the given span.
+    fn name_error(&mut self, name: &str, span: Span, message: String) {
+        let kind = ErrorKind::SyntaxError(span.line, Token::Identifier(String::from(name)));
+        self.error_on_line(kind, span, Some(message));
     }
 
-    // TODO(ed): Const generics
-    fn peek_four(&self) -> (Token, Token, Token, Token) {
-        (self.peek_at(0), self.peek_at(1), self.peek_at(2), self.peek_at(3))
-    }
-
-    fn eat(&mut self) -> Token {
-        let t = self.peek();
-        self.current_token += 1;
-        match t {
-            Token::GitConflictBegin => {
-                self.current_token -= 1;
-                let start = self.line();
-                self.current_token += 1;
-                while !matches!(self.eat(), Token::GitConflictEnd) {}
-                self.panic = false;
-                self.error_on_line(ErrorKind::GitConflictError(start, self.line()), start, None);
-                self.panic = true;
-            }
-            _ => {}
-        }
-        t
-    }
-
-    /// The line of the current token.
-    fn line(&self) -> usize {
-        if self.section().tokens.len() == 0 {
-            0
-        } else {
-            self.section().tokens[std::cmp::min(self.current_token, self.section().tokens.len() - 1)].1
+    /// Adds a note to the last error, with the candidate closest to `name`.
+    fn suggest(&mut self, name: &str, candidates: &[String]) {
+        let note = did_you_mean(name, candidates.iter().map(String::as_str));
+        if let Some(error) = self.errors.last_mut() {
+            error.notes.extend(note);
         }
     }
 
-    fn precedence(&self, token: Token) -> Prec {
-        match token {
-            Token::LeftBracket => Prec::Index,
-
-            Token::Star | Token::Slash => Prec::Factor,
-
-            Token::Minus | Token::Plus => Prec::Term,
-
-            Token::EqualEqual
-                | Token::Greater
-                | Token::GreaterEqual
-                | Token::Less
-                | Token::LessEqual
-                | Token::NotEqual
-                => Prec::Comp,
-
-            Token::And => Prec::BoolAnd,
-            Token::Or => Prec::BoolOr,
-
-            Token::AssertEqual => Prec::Assert,
-
-            _ => Prec::No,
-        }
-    }
-
-    fn prefix(&mut self, token: Token, block: &mut Block) -> bool {
-        match token {
-            Token::Identifier(_) => self.variable_expression(block),
-            Token::LeftParen => self.grouping_or_tuple(block),
-            Token::Minus => self.unary(block),
-            Token::LeftBracket => self.list(block),
-
-            Token::Float(_)
-                | Token::Int(_)
-                | Token::Bool(_)
-                | Token::String(_)
-                | Token::Nil => self.value(block),
-
-            Token::Bang => self.unary(block),
-
-            _ => { return false; },
-        }
-        return true;
-    }
-
-    fn infix(&mut self, token: Token, block: &mut Block) -> bool {
-        match token {
-            Token::Minus
-                | Token::Plus
-                | Token::Slash
-                | Token::Star
-                | Token::AssertEqual
-                | Token::EqualEqual
-                | Token::Greater
-                | Token::GreaterEqual
-                | Token::Less
-                | Token::LessEqual
-                | Token::NotEqual
-                => self.binary(block),
-
-            Token::And | Token::Or
-                => self.binary_bool(block),
-
-            Token::LeftBracket => self.index(block),
-
-            _ => { return false; },
-        }
-        return true;
-    }
-
-    fn value(&mut self, block: &mut Block) {
-        let value = match self.eat() {
-            Token::Float(f) => { Value::Float(f) },
-            Token::Int(i) => { Value::Int(i) }
-            Token::Bool(b) => { Value::Bool(b) }
-            Token::Nil => { Value::Nil }
-            Token::String(s) => { Value::String(Rc::from(s)) }
-            _ => { error!(self, "Cannot parse value"); Value::Bool(false) }
-        };
-        let constant = self.add_constant(value);
-        add_op(self, block, Op::Constant(constant));
-    }
-
-    fn list(&mut self, block: &mut Block) {
-        expect!(self, Token::LeftBracket, "Expected '[' at start of list");
-        let mut num_args = 0;
-        loop {
-            match self.peek() {
-                Token::RightBracket | Token::EOF => {
-                    break;
-                }
-                Token::Newline => {
-                    self.eat();
-                }
-                Token::Comma => {
-                    error!(self, "Lists must begin with an element or ']'");
-                    return;
-                }
-                _ => {
-                    self.expression(block);
-                    num_args += 1;
-                    match self.peek() {
-                        Token::Comma => {
-                            self.eat();
-                            if matches!(self.peek(), Token::RightBracket) {
-                                break;
-                            }
-                        },
-                        Token::RightBracket => {},
-                        _ => {
-                            error!(self, "Expected ',' or ']' after list element");
-                            return;
-                        },
-                    }
-                }
-            }
-        };
-        expect!(self, Token::RightBracket, "Expected ']' after list");
-        add_op(self, block, Op::List(num_args));
-    }
-
-    fn grouping_or_tuple(&mut self, block: &mut Block) {
-        expect!(self, Token::LeftParen, "Expected '(' for grouping or tuple.");
-
-        let mut num_args = 0;
-        let trailing_comma = loop {
-            match self.peek() {
-                Token::RightParen | Token::EOF => {
-                    break false;
-                }
-                Token::Newline => {
-                    self.eat();
-                }
-                _ => {
-                    self.expression(block);
-                    num_args += 1;
-                    match self.peek() {
-                        Token::Comma => {
-                            self.eat();
-                            if matches!(self.peek(), Token::RightParen) {
-                                break true;
-                            }
-                        },
-                        Token::RightParen => {},
-                        _ => {
-                            error!(self, "Expected ',' or ')' after tuple element");
-                            return;
-                        },
-                    }
-                }
-            }
-        };
-        if (trailing_comma && num_args == 1) || num_args != 1 {
-            add_op(self, block, Op::Tuple(num_args));
-        }
-        expect!(self, Token::RightParen, "Expected ')' after tuple");
-    }
-
-    fn index(&mut self, block: &mut Block) {
-        expect!(self, Token::LeftBracket, "Expected '[' around index");
-
-        self.expression(block);
-        add_op(self, block, Op::Index);
-
-        expect!(self, Token::RightBracket, "Expected ']' around index");
-    }
-
-    fn unary(&mut self, block: &mut Block) {
-        let op = match self.eat() {
-            Token::Minus => Op::Neg,
-            Token::Bang => Op::Not,
-            _ => { error!(self, "Invalid unary operator"); Op::Neg },
-        };
-        self.parse_precedence(block, Prec::Factor);
-        add_op(self, block, op);
-    }
-
-    fn binary_bool(&mut self, block: &mut Block) {
-        let op = self.eat();
-
-        match op {
-            Token::And => {
-                add_op(self, block, Op::Copy);
-                let jump = add_op(self, block, Op::Illegal);
-
-                self.parse_precedence(block, self.precedence(op.clone()).next());
-
-                block.patch(Op::JmpFalse(block.curr()), jump);
-            }
-
-            Token::Or => {
-                add_op(self, block, Op::Copy);
-                let skipp = add_op(self, block, Op::Illegal);
-                let jump = add_op(self, block, Op::Illegal);
-                block.patch(Op::JmpFalse(block.curr()), skipp);
-
-                self.parse_precedence(block, self.precedence(op.clone()).next());
-
-                block.patch(Op::Jmp(block.curr()), jump);
-            }
-
-            _ => { error!(self, "Illegal operator"); }
-        }
+    /// The names that can be read where the compiler is, including
+    /// the external functions.
+    fn names_in_scope(&self) -> Vec<String> {
+        readable_names(self.frames(), self.names())
+            .chain(self.functions.keys())
+            .cloned()
+            .collect()
     }
 
-    fn binary(&mut self, block: &mut Block) {
-        let op = self.eat();
-
-        self.parse_precedence(block, self.precedence(op.clone()).next());
-
-        let op: &[Op] = match op {
-            Token::Plus => &[Op::Add],
-            Token::Minus => &[Op::Sub],
-            Token::Star => &[Op::Mul],
-            Token::Slash => &[Op::Div],
-            Token::AssertEqual => &[Op::Equal, Op::Assert],
-            Token::EqualEqual => &[Op::Equal],
-            Token::Less => &[Op::Less],
-            Token::Greater => &[Op::Greater],
-            Token::NotEqual => &[Op::Equal, Op::Not],
-            Token::LessEqual => &[Op::Greater, Op::Not],
-            Token::GreaterEqual => &[Op::Less, Op::Not],
-            _ => { error!(self, "Illegal operator"); &[] }
-        };
-        block.add_from(op, self.line());
+    /// The names that can be read from the file at `path` with `file.name`.
+    fn names_in_file(&self, path: &Path) -> Vec<String> {
+        let context = &self.contextes[path];
+        readable_names(&context.frames[..1], &context.namespace).cloned().collect()
     }
 
-    /// Entry point for all expression parsing.
-    fn expression(&mut self, block: &mut Block) {
-        match self.peek_four() {
-            (Token::Fn, ..) => { self.function(block, None); },
-            _ => self.parse_precedence(block, Prec::No),
+    fn find_namespace(&self, name: &str) -> Option<PathBuf> {
+        match self.names().get(name) {
+            Some(Name::Namespace(path)) => Some(path.clone()),
+            _ => None,
         }
     }
 
-    fn parse_precedence(&mut self, block: &mut Block, precedence: Prec) {
-        if !self.prefix(self.peek(), block) {
-            error!(self, "Invalid expression");
-        }
-
-        while precedence <= self.precedence(self.peek()) {
-            if !self.infix(self.peek(), block) {
-                break;
-            }
-        }
-    }
+    /// Makes the global variable `name` in the file at `path` reachable
+    /// from the current file, through the placeholder in its slot.
+    fn import_global(&mut self, path: &Path, name: &str) -> Option<Variable> {
+        let context = self.contextes.get_mut(path).unwrap();
+        let var = context.frames[0].find_local(name)?;
+        context.frames[0].stack[var.slot].read = true;
 
-    fn find_namespace(&self, name: &str) -> Option<&Namespace> {
-        match self.names().get(name) {
-            Some(Name::Namespace(path)) => Some(&self.contextes.get(path).unwrap().namespace),
-            _ => None,
+        // The name can't be written in code, so it won't collide.
+        let name = format!("{}.{}", path.display(), name);
+        let placeholder = &mut self.current_context_mut().frames[0].stack[var.slot];
+        if placeholder.name != name {
+            *placeholder = Variable {
+                name: name.clone(),
+                read: true,
+                captured: false,
+                ..var
+            };
         }
+        let var = self.find_variable(&name).unwrap();
+        self.mark_read(self.frames().len() - 1, &var);
+        Some(var)
     }
 
     fn find_and_capture_variable<'i, I>(name: &str, mut iterator: I) -> Option<Variable>
@@ -842,14 +567,14 @@ impl Compiler {
         Self::find_and_capture_variable(name, self.frames_mut().iter_mut().rev())
     }
 
-    fn find_constant(&mut self, name: &str) -> usize {
+    fn find_constant(&mut self, name: &str, span: Span) -> usize {
         match self.names_mut().entry(name.to_string()) {
             Entry::Occupied(entry) => {
                 match entry.get() {
                     Name::Slot(i, _) => { return *i; },
-                    Name::Unknown(i, _) => { return *i; },
+                    Name::Unknown(i, ..) => { return *i; },
                     _ => {
-                        error!(self, "Tried to find constant '{}' but it was a namespace", name);
+                        self.name_error(name, span, format!("Tried to find constant '{}' but it was a namespace", name));
                         return 0;
                     }
                 }
@@ -857,305 +582,413 @@ impl Compiler {
             Entry::Vacant(_) => {},
         };
 
+        let closest = closest(name, readable_names(self.frames(), &Namespace::new()).map(String::as_str))
+            .map(String::from);
         let slot = self.add_constant(Value::Unknown);
-        let line = self.line();
-        self.names_mut().insert(name.to_string(), Name::Unknown(slot, line));
+        self.names_mut().insert(name.to_string(), Name::Unknown(slot, span, closest));
         slot
     }
 
-    fn named_constant(&mut self, name: String, value: Value) -> usize {
-        let line = self.line();
+    fn named_constant(&mut self, name: String, span: Span, value: Value) -> usize {
         match self.names_mut().entry(name.clone()) {
             Entry::Occupied(mut entry) => {
-                let slot = if let Name::Unknown(slot, _) = entry.get() {
+                let slot = if let Name::Unknown(slot, ..) = entry.get() {
                     *slot
                 } else {
-                    error!(self, "Constant named \"{}\" already has a value", name);
+                    self.name_error(&name, span, format!("Constant named \"{}\" already has a value", name));
                     return 0;
                 };
-                entry.insert(Name::Slot(slot, line));
+                entry.insert(Name::Slot(slot, span));
                 self.constants[slot] = value;
                 return slot;
             },
             Entry::Vacant(_) => {},
         }
         let slot = self.add_constant(value);
-        self.names_mut().insert(name, Name::Slot(slot, line));
+        self.names_mut().insert(name, Name::Slot(slot, span));
         slot
     }
 
-    fn forward_constant(&mut self, name: String) -> usize {
-        let line = self.line();
+    fn forward_constant(&mut self, name: String, span: Span) -> usize {
         let slot = self.add_constant(Value::Unknown);
         match self.names_mut().entry(name.clone()) {
             Entry::Occupied(_) => {
-                error!(self, "Constant named \"{}\" already has a value", name);
-                0
+                self.name_error(&name, span, format!("Constant named \"{}\" already has a value", name));
             },
             Entry::Vacant(entry) => {
-                entry.insert(Name::Unknown(slot, line));
-                slot
+                entry.insert(Name::Unknown(slot, span, None));
             },
         }
+        slot
     }
 
-    fn call_maybe(&mut self, block: &mut Block) -> bool {
-        if matches!(self.peek(), Token::Bang | Token::LeftParen) {
-            self.call(block);
-            true
+    fn read_variable(&mut self, var: &Variable, span: Span, block: &mut Block) {
+        if var.upvalue {
+            block.add(Op::ReadUpvalue(var.slot), span);
         } else {
-            false
+            block.add(Op::ReadLocal(var.slot), span);
         }
     }
 
-    fn call(&mut self, block: &mut Block) {
-        let mut arity = 0;
-        match self.peek() {
-            Token::LeftParen => {
-                self.eat();
-                loop {
-                    match self.peek() {
-                        Token::EOF => {
-                            error!(self, "Unexpected EOF in function call");
-                            break;
-                        }
-                        Token::RightParen => {
-                            self.eat();
-                            break;
-                        }
-                        _ => {
-                            self.expression(block);
-                            arity += 1;
-                            if !matches!(self.peek(), Token::RightParen) {
-                                expect!(self, Token::Comma, "Expected ',' after argument");
+    /// Reads a constant, unless it's an enum which needs a variant.
+    fn read_constant(&mut self, slot: usize, span: Span, block: &mut Block) -> Reference {
+        if let Value::Ty(Type::Enum(e)) = &self.constants[slot] {
+            return Reference::Enum(Rc::clone(e));
+        }
+        block.add(Op::Constant(slot), span);
+        Reference::Value
+    }
+
+    /// Compiles names and paths of names, which can't be turned into
+    /// values until it's known what they're used for.
+    fn reference(&mut self, expr: &Expression, block: &mut Block) -> Reference {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Read(ident) => {
+                let name = &ident.name;
+                if let Some(path) = self.find_namespace(name) {
+                    return Reference::Namespace(path);
+                }
+
+                // Global functions take precedence
+                if let Some(slot) = self.find_extern_function(name) {
+                    let function = self.add_constant(Value::ExternFunction(slot));
+                    block.add(Op::Constant(function), span);
+                    return Reference::Value;
+                }
+
+                if let Some(var) = self.find_variable(name) {
+                    self.mark_read(self.frames().len() - 1, &var);
+                    self.read_variable(&var, span, block);
+                    return Reference::Value;
+                }
+
+                // Constants are filled in if they're declared later.
+                let slot = self.find_constant(name, span);
+                self.read_constant(slot, span, block)
+            }
+
+            ExpressionKind::Access(object, field) => {
+                match self.reference(object, block) {
+                    Reference::Namespace(path) => {
+                        match self.contextes[&path].namespace.get(&field.name).cloned() {
+                            Some(Name::Slot(slot, _)) | Some(Name::Unknown(slot, ..)) => {
+                                self.read_constant(slot, span, block)
+                            }
+                            Some(Name::Namespace(inner)) => Reference::Namespace(inner),
+                            None => {
+                                if let Some(var) = self.import_global(&path, &field.name) {
+                                    self.read_variable(&var, span, block);
+                                } else {
+                                    error!(self, field, "Cannot find '{}' in '{}'", field.name, path.display());
+                                    let candidates = self.names_in_file(&path);
+                                    self.suggest(&field.name, &candidates);
+                                }
+                                Reference::Value
                             }
                         }
                     }
-                    if self.panic {
-                        break;
+                    Reference::Enum(e) => {
+                        if let Some(variant) = e.variant(&field.name) {
+                            Reference::Variant(e, variant)
+                        } else {
+                            error!(self, field, "'{}' has no variant named '{}'", e.name, field.name);
+                            let variants: Vec<_> = e.variants.iter().map(|(name, _)| name.clone()).collect();
+                            self.suggest(&field.name, &variants);
+                            Reference::Value
+                        }
+                    }
+                    reference => {
+                        self.value(reference, object, block);
+                        let field = self.intern_string(field.name.clone());
+                        block.add(Op::Get(field), span);
+                        Reference::Value
                     }
                 }
-            },
+            }
 
-            Token::Bang => {
-                self.eat();
-                loop {
-                    match self.peek() {
-                        Token::EOF => {
-                            error!(self, "Unexpected EOF in function call");
-                            break;
-                        }
-                        Token::Newline => {
-                            break;
-                        }
-                        _ => {
-                            self.expression(block);
-                            arity += 1;
-                            if matches!(self.peek(), Token::Comma) {
-                                self.eat();
-                            }
-                        }
+            _ => {
+                self.expression(expr, block);
+                Reference::Value
+            }
+        }
+    }
+
+    /// Puts what the expression refers to on the stack.
+    fn value(&mut self, reference: Reference, expr: &Expression, block: &mut Block) {
+        let ident = match (&reference, &expr.kind) {
+            (Reference::Value, _) => return,
+            (_, ExpressionKind::Read(ident)) | (_, ExpressionKind::Access(_, ident)) => ident,
+            _ => unreachable!("Only names refer to something"),
+        };
+        match reference {
+            Reference::Value => {}
+            Reference::Namespace(_) => {
+                error!(self, ident, "Expected '.' after namespace '{}'", ident.name);
+            }
+            Reference::Enum(e) => {
+                error!(self, ident, "Expected '.' after enum '{}'", e.name);
+            }
+            Reference::Variant(e, variant) => {
+                if e.variants[variant].1.is_some() {
+                    error!(self, ident, "'{}.{}' needs a payload", e.name, ident.name);
+                }
+                let variant = self.add_constant(Value::Variant(e, variant, Box::new(Value::Nil)));
+                block.add(Op::Constant(variant), expr.span);
+            }
+        }
+    }
+
+    fn call(&mut self, span: Span, callee: &Expression, args: &[Expression], block: &mut Block) {
+        match self.reference(callee, block) {
+            Reference::Variant(e, variant) => {
+                let name = &e.variants[variant].0;
+                match (&e.variants[variant].1, args) {
+                    (Some(_), [payload]) => {
+                        let variant = self.add_constant(Value::Variant(Rc::clone(&e), variant, Box::new(Value::Nil)));
+                        block.add(Op::Constant(variant), span);
+                        self.expression(payload, block);
+                        block.add(Op::Variant, span);
                     }
-                    if self.panic {
-                        break;
+                    (Some(_), _) => {
+                        self.name_error(name, span, format!("'{}.{}' takes exactly one payload", e.name, name));
+                    }
+                    (None, _) => {
+                        self.name_error(name, span, format!("'{}.{}' doesn't have a payload", e.name, name));
                     }
                 }
             }
+            reference => {
+                self.value(reference, callee, block);
+                for arg in args.iter() {
+                    self.expression(arg, block);
+                }
+                block.add(Op::Call(args.len()), span);
+            }
+        }
+    }
 
-            _ => {
-                error!(self, "Invalid function call. Expected '!' or '('");
+    /// Compiles a blob literal, like `A { x: 1.0, y: 2.0 }`.
+    fn instance(&mut self, span: Span, blob: &Expression, fields: &[(Identifier, Expression)], block: &mut Block) {
+        let reference = self.reference(blob, block);
+        self.value(reference, blob, block);
+
+        let mut given = HashSet::new();
+        for (field, value) in fields.iter() {
+            if !given.insert(&field.name) {
+                error!(self, field, "Field '{}' is given more than once", field.name);
             }
+            let name = self.add_constant(Value::String(Rc::new(field.name.clone())));
+            block.add(Op::Constant(name), field.span);
+            self.expression(value, block);
         }
+        block.add(Op::Construct(fields.len()), span);
+    }
 
-        add_op(self, block, Op::Call(arity));
+    fn binary_bool(&mut self, span: Span, op: BinOp, a: &Expression, b: &Expression, block: &mut Block) {
+        self.expression(a, block);
+        block.add(Op::Copy(1), span);
+        match op {
+            BinOp::And => {
+                let jump = block.add(Op::Illegal, span);
+                block.add(Op::Pop, span);
+                self.expression(b, block);
+                block.patch(Op::JmpFalse(block.curr()), jump);
+            }
+            BinOp::Or => {
+                let skip = block.add(Op::Illegal, span);
+                let jump = block.add(Op::Illegal, span);
+                block.patch(Op::JmpFalse(block.curr()), skip);
+                block.add(Op::Pop, span);
+                self.expression(b, block);
+                block.patch(Op::Jmp(block.curr()), jump);
+            }
+            _ => unreachable!("Only '&&' and '||' short circuit"),
+        }
     }
 
-    // TODO(ed): de-complexify
-    fn function(&mut self, block: &mut Block, in_name: Option<&str>) {
-        expect!(self, Token::Fn, "Expected 'fn' at start of function");
+    /// Entry point for all expression compiling.
+    fn expression(&mut self, expr: &Expression, block: &mut Block) {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Read(_) | ExpressionKind::Access(..) => {
+                let reference = self.reference(expr, block);
+                self.value(reference, expr, block);
+            }
+
+            ExpressionKind::Index(object, index) => {
+                self.expression(object, block);
+                self.expression(index, block);
+                block.add(Op::Index, span);
+            }
+
+            ExpressionKind::Call(callee, args) => self.call(span, callee, args, block),
+
+            ExpressionKind::Instance { blob, fields } => self.instance(span, blob, fields, block),
+
+            ExpressionKind::Spawn(callee, args) => {
+                self.expression(callee, block);
+                for arg in args.iter() {
+                    self.expression(arg, block);
+                }
+                block.add(Op::Spawn(args.len()), span);
+            }
+
+            ExpressionKind::Function(function) => {
+                let function = self.function(function, None, None);
+                block.add(Op::Constant(function), span);
+            }
+
+            ExpressionKind::Binary(op @ BinOp::And, a, b)
+                | ExpressionKind::Binary(op @ BinOp::Or, a, b) => {
+                self.binary_bool(span, *op, a, b, block);
+            }
+
+            ExpressionKind::Binary(op, a, b) => {
+                self.expression(a, block);
+                self.expression(b, block);
+                block.add_from(binary_ops(*op), span);
+            }
+
+            ExpressionKind::Unary(op, value) => {
+                self.expression(value, block);
+                let op = match op {
+                    UnOp::Neg => Op::Neg,
+                    UnOp::Not => Op::Not,
+                };
+                block.add(op, span);
+            }
+
+            ExpressionKind::Cast(value, ty) => {
+                self.expression(value, block);
+                let ty = self.resolve_type(ty);
+                let ty = self.add_constant(Value::Ty(ty));
+                block.add(Op::Cast(ty), span);
+            }
+
+            ExpressionKind::Tuple(values) => {
+                for value in values.iter() {
+                    self.expression(value, block);
+                }
+                block.add(Op::Tuple(values.len()), span);
+            }
+
+            ExpressionKind::List(values) => {
+                for value in values.iter() {
+                    self.expression(value, block);
+                }
+                block.add(Op::List(values.len()), span);
+            }
+
+            ExpressionKind::Dict(values) => {
+                for (key, value) in values.iter() {
+                    self.expression(key, block);
+                    self.expression(value, block);
+                }
+                block.add(Op::Dict(values.len()), span);
+            }
+
+            ExpressionKind::Int(_)
+                | ExpressionKind::Float(_)
+                | ExpressionKind::Bool(_)
+                | ExpressionKind::Str(_)
+                | ExpressionKind::Nil => {
+                let constant = self.add_constant(literal(expr));
+                block.add(Op::Constant(constant), span);
+            }
+        }
+    }
 
+    // TODO(ed): de-complexify
+    /// Compiles a function and returns the slot of its constant.
+    /// Methods are given the type of their receiver, which is
+    /// passed in as `self`.
+    fn function(&mut self, function: &Function, in_name: Option<&str>, receiver: Option<Type>) -> usize {
+        let span = function.span;
         let name = if let Some(name) = in_name {
             String::from(name)
         } else {
-            format!("λ {}@{:03}", self.current_file().display(), self.line())
+            format!("λ {}@{:03}", self.current_file().display(), span.line)
         };
 
-        let mut args = Vec::new();
-        let mut return_type = Type::Void;
+        let num_type_params = self.type_params.len();
+        for param in function.type_params.iter() {
+            if self.type_params[num_type_params..].contains(&param.name) {
+                error!(self, param, "Type parameter '{}' is declared twice", param.name);
+            }
+            self.type_params.push(param.name.clone());
+        }
+
+        let mut args: Vec<_> = receiver.iter().cloned().collect();
+        for (_, ty) in function.params.iter() {
+            let ty = self.resolve_type(ty);
+            args.push(ty);
+        }
+        let return_type = self.resolve_type(&function.ret);
+
         let mut function_block = Block::new(&name, self.current_file());
 
         let block_id = self.blocks.len();
         let temp_block = Block::new(&name, self.current_file());
         self.blocks.push(Rc::new(RefCell::new(temp_block)));
 
-        let _ret = push_frame!(self, function_block, {
-            loop {
-                match self.peek() {
-                    Token::Identifier(name) => {
-                        self.eat();
-                        expect!(self, Token::Colon, "Expected ':' after parameter name");
-                        if let Ok(typ) = self.parse_type() {
-                            args.push(typ.clone());
-                            let mut var = Variable::new(&name, true, typ);
-                            var.read = true;
-                            if let Ok(slot) = self.define(var) {
-                                self.stack_mut()[slot].active = true;
-                            }
-                        } else {
-                            error!(self, "Failed to parse parameter type");
-                        }
-                        if !matches!(self.peek(), Token::Arrow | Token::LeftBrace) {
-                            expect!(self, Token::Comma, "Expected ',' after parameter");
-                        }
-                    }
-                    Token::LeftBrace => {
-                        break;
-                    }
-                    Token::Arrow => {
-                        self.eat();
-                        if let Ok(typ) = self.parse_type() {
-                            return_type = typ;
-                        } else {
-                            error!(self, "Failed to parse return type");
-                        }
-                        break;
-                    }
-                    _ => {
-                        error!(self, "Expected '->' or more paramters in function definition");
-                        break;
-                    }
+        push_frame!(self, span, {
+            let names = receiver.iter()
+                .map(|_| ("self", span, false))
+                .chain(function.params.iter().map(|(param, _)| (param.name.as_str(), param.span, true)));
+            for ((name, span, mutable), ty) in names.zip(args.iter()) {
+                let mut var = Variable::new(name, mutable, ty.clone());
+                var.read = true;
+                if let Ok(slot) = self.define(var, span) {
+                    self.stack_mut()[slot].active = true;
                 }
             }
 
-            self.scope(&mut function_block);
+            self.scope(&function.body, span, &mut function_block);
 
             for var in self.frame().upvalues.iter() {
                 function_block.upvalues.push((var.outer_slot, var.outer_upvalue, var.typ.clone()));
             }
         });
+        self.type_params.truncate(num_type_params);
 
+        let end = function.body.last().map(|statement| statement.span).unwrap_or(span);
         let nil = self.add_constant(Value::Nil);
         for op in function_block.ops.iter().rev() {
             match op {
                 Op::Pop | Op::PopUpvalue => {}
                 Op::Return => { break; } ,
                 _ => {
-                    add_op(self, &mut function_block, Op::Constant(nil));
-                    add_op(self, &mut function_block, Op::Return);
+                    function_block.add(Op::Constant(nil), end);
+                    function_block.add(Op::Return, end);
                     break;
                 }
             }
         }
 
         if function_block.ops.is_empty() {
-            add_op(self, &mut function_block, Op::Constant(nil));
-            add_op(self, &mut function_block, Op::Return);
+            function_block.add(Op::Constant(nil), end);
+            function_block.add(Op::Return, end);
         }
 
         function_block.ty = Type::Function(args, Box::new(return_type));
         let function_block = Rc::new(RefCell::new(function_block));
 
-        // Note(ed): We deliberately add the constant as late as possible.
-        // This behaviour is used in `constant_statement`.
         let function = Value::Function(Rc::new(Vec::new()), Rc::clone(&function_block));
         self.blocks[block_id] = function_block;
-        let constant = if in_name.is_some() {
-            self.named_constant(name, function)
+        if in_name.is_some() {
+            self.named_constant(name, span, function)
         } else {
             self.add_constant(function)
-        };
-        add_op(self, block, Op::Constant(constant));
-    }
-
-    fn variable_expression(&mut self, block: &mut Block) {
-        let name = match self.peek() {
-            Token::Identifier(name) => name,
-            _ => unreachable!(),
-        };
-
-        if let Some(_) = self.find_namespace(&name) {
-            self.eat();
-            // TODO(ed): This is a clone I would love to get rid of...
-            let mut namespace = self.find_namespace(&name).unwrap().clone();
-            loop {
-                if self.eat() != Token::Dot {
-                    error!(self, "Expect '.' after namespace");
-                    return;
-                }
-                if let Token::Identifier(field) = self.eat() {
-                    match namespace.get(&field) {
-                        Some(Name::Slot(slot, _)) | Some(Name::Unknown(slot, _)) => {
-                            add_op(self, block, Op::Constant(*slot));
-                            self.call_maybe(block);
-                            return;
-                        }
-                        Some(Name::Namespace(inner_name)) => {
-                            namespace = self.contextes
-                                .get(inner_name)
-                                .unwrap().namespace
-                                .clone();
-                        }
-                        _ => {
-                            error!(self, "Invalid namespace field");
-                        }
-                    }
-                } else {
-                    error!(self, "Expected fieldname after '.'");
-                }
-            }
-        }
-
-        self.eat();
-
-        // Global functions take precedence
-        if let Some(slot) = self.find_extern_function(&name) {
-            let string = self.add_constant(Value::ExternFunction(slot));
-            add_op(self, block, Op::Constant(string));
-            self.call(block);
-            return;
-        }
-
-        // Variables
-        if let Some(var) = self.find_variable(&name) {
-            self.mark_read(self.frames().len() - 1, &var);
-            if var.upvalue {
-                add_op(self, block, Op::ReadUpvalue(var.slot));
-            } else {
-                add_op(self, block, Op::ReadLocal(var.slot));
-            }
-            loop {
-                match self.peek() {
-                    Token::Dot => {
-                        self.eat();
-                        if let Token::Identifier(field) = self.eat() {
-                            let string = self.intern_string(String::from(field));
-                            add_op(self, block, Op::Get(string));
-                        } else {
-                            error!(self, "Expected fieldname after '.'");
-                            return;
-                        }
-                    }
-                    _ => {
-                        if !self.call_maybe(block) {
-                            return;
-                        }
-                    }
-                }
-            }
         }
-
-        // Blobs - Always returns a blob since it's filled in if it isn't used.
-        let con = self.find_constant(&name);
-        add_op(self, block, Op::Constant(con));
-        self.call_maybe(block);
     }
 
-    fn define(&mut self, mut var: Variable) -> Result<usize, ()> {
+    fn define(&mut self, mut var: Variable, span: Span) -> Result<usize, ()> {
         let frame = self.frame();
 
         if let Some(res) = frame.find_local(&var.name).or(frame.find_upvalue(&var.name)) {
             if res.scope == frame.scope {
-                error!(self, "Multiple definitions of '{}' in this block", res.name);
+                self.name_error(&res.name, span, format!("Multiple definitions of '{}' in this block", res.name));
                 return Err(());
             }
         }
@@ -1163,677 +996,805 @@ impl Compiler {
         let slot = self.stack().len();
         var.slot = slot;
         var.scope = frame.scope;
-        var.line = self.line();
+        var.span = span;
         self.stack_mut().push(var);
         Ok(slot)
     }
 
-    fn definition_statement(&mut self, name: &str, typ: Type, block: &mut Block) {
-        if self.frames().len() <= 1 {
-            // Global
-            let var = self.frame().find_outer(name);
-            if var.is_none() {
-                error!(self, "Couldn't find variable '{}' during prepass", name);
-                return;
-            }
-            let var = var.unwrap();
-            assert!(var.mutable);
+    /// Lets a strict VM check that the value on top of
+    /// the stack fits the type of a variable.
+    fn check_type(&mut self, ty: &Type, span: Span, block: &mut Block) {
+        if self.strict && !matches!(ty, Type::Unknown) {
+            let ty = self.add_constant(Value::Ty(ty.clone()));
+            block.add(Op::Define(ty), span);
+        }
+    }
 
-            self.expression(block);
-            self.stack_mut()[var.slot].active = true;
-        } else {
-            // Local
-            let var = Variable::new(name, true, typ.clone());
-            let slot = self.define(var);
-            self.expression(block);
-            let constant = self.add_constant(Value::Ty(typ));
-            add_op(self, block, Op::Define(constant));
+    /// The type of a variable, either written out or
+    /// inferred by the typechecker.
+    fn variable_type(&mut self, ty: Option<&ast::Type>, span: Span) -> Type {
+        match ty {
+            Some(ty) => self.resolve_type(ty),
+            None => self.inferred.get(&(self.current_file.clone(), span)).cloned().unwrap_or(Type::Unknown),
+        }
+    }
 
-            if let Ok(slot) = slot {
-                self.stack_mut()[slot].active = true;
-            }
+    /// Defines a local variable, which can't be used in its own value.
+    fn definition_statement(
+        &mut self,
+        ident: &Identifier,
+        kind: VarKind,
+        ty: Option<&ast::Type>,
+        value: &Expression,
+        block: &mut Block,
+    ) {
+        let ty = self.variable_type(ty, ident.span);
+        let var = Variable::new(&ident.name, kind == VarKind::Mutable, ty.clone());
+        let slot = self.define(var, ident.span);
+        self.expression(value, block);
+        self.check_type(&ty, ident.span, block);
+
+        if let Ok(slot) = slot {
+            self.stack_mut()[slot].active = true;
         }
     }
 
-    fn constant_statement(&mut self, name: &str, typ: Type, block: &mut Block) {
+    /// Globals are defined before anything is compiled, so here
+    /// they're only given their value.
+    fn global_statement(&mut self, ident: &Identifier, kind: VarKind, value: &Expression, block: &mut Block) {
         // Magical global constants
-        if self.frames().len() <= 1 && self.peek() == Token::Fn {
-            self.function(block, Some(name));
-            // Remove the function, since it's a constant and we already
-            // added it.
-            block.ops.pop().unwrap();
-            let slot = self.find_constant(name);
-            add_op(self, block, Op::Link(slot));
+        if let (VarKind::Const, ExpressionKind::Function(function)) = (kind, &value.kind) {
+            let slot = self.function(function, Some(&ident.name), None);
+            block.add(Op::Link(slot), value.span);
             if let Value::Function(_, block) = &self.constants[slot] {
                 block.borrow_mut().mark_constant();
-            } else {
-                unreachable!();
             }
             return;
         }
 
-        if self.frames().len() <= 1 {
-            // Global
-            let var = self.frame().find_outer(name);
-            if var.is_none() {
-                error!(self, "Couldn't find constant '{}' during prepass", name);
-                return;
-            }
-            let var = var.unwrap();
-            assert!(!var.mutable);
-
-            self.expression(block);
-            self.stack_mut()[var.slot].active = true;
-        } else {
-            // Local
-            let var = Variable::new(name, false, typ);
-            let slot = self.define(var);
-            self.expression(block);
-
-            if let Ok(slot) = slot {
-                self.stack_mut()[slot].active = true;
-            }
+        let var = self.frame().find_outer(&ident.name);
+        if var.is_none() {
+            error!(self, ident, "Couldn't find '{}' during prepass", ident.name);
+            return;
         }
-    }
-
-    fn assign(&mut self, block: &mut Block) {
-        let name = match self.eat() {
-            Token::Identifier(name) => name,
-            _ => {
-                error!(self, "Expected identifier in assignment");
-                return;
+        let var = var.unwrap();
+
+        self.expression(value, block);
+        self.check_type(&var.typ, ident.span, block);
+        self.stack_mut()[var.slot].active = true;
+    }
+
+    fn assignment(
+        &mut self,
+        span: Span,
+        op: Option<BinOp>,
+        target: &Expression,
+        value: &Expression,
+        block: &mut Block,
+    ) {
+        match &target.kind {
+            ExpressionKind::Read(ident) => {
+                if let Some(var) = self.find_variable(&ident.name) {
+                    self.assign_variable(var, ident, op, value, block);
+                } else {
+                    error!(self, ident, "Using undefined variable {}", ident.name);
+                    let candidates = self.names_in_scope();
+                    self.suggest(&ident.name, &candidates);
+                }
             }
-        };
-
-        let op = match self.eat() {
-            Token::Equal => None,
 
-            Token::PlusEqual => Some(Op::Add),
-            Token::MinusEqual => Some(Op::Sub),
-            Token::StarEqual => Some(Op::Mul),
-            Token::SlashEqual => Some(Op::Div),
-
-            _ => {
-                error!(self, "Expected '=' in assignment");
-                return;
+            ExpressionKind::Access(object, field) => {
+                match self.reference(object, block) {
+                    Reference::Namespace(path) => {
+                        if self.contextes[&path].namespace.contains_key(&field.name) {
+                            error!(self, field, "Cannot assign to constant '{}'", field.name);
+                        } else if let Some(var) = self.import_global(&path, &field.name) {
+                            self.assign_variable(var, field, op, value, block);
+                        } else {
+                            error!(self, field, "Cannot find '{}' in '{}'", field.name, path.display());
+                            let candidates = self.names_in_file(&path);
+                            self.suggest(&field.name, &candidates);
+                        }
+                    }
+                    reference => {
+                        self.value(reference, object, block);
+                        let field = self.intern_string(field.name.clone());
+                        if let Some(op) = op {
+                            block.add(Op::Copy(1), span);
+                            block.add(Op::Get(field), span);
+                            self.expression(value, block);
+                            block.add_from(binary_ops(op), span);
+                        } else {
+                            self.expression(value, block);
+                        }
+                        block.add(Op::Set(field), span);
+                    }
+                }
             }
-        };
 
-        if let Some(var) = self.find_variable(&name) {
-            if !var.mutable {
-                // TODO(ed): Maybe a better error than "SyntaxError".
-                error!(self, "Cannot assign to constant '{}'", var.name);
-            }
-            if let Some(op) = op {
-                if var.upvalue {
-                    add_op(self, block, Op::ReadUpvalue(var.slot));
+            ExpressionKind::Index(object, index) => {
+                self.expression(object, block);
+                self.expression(index, block);
+                if let Some(op) = op {
+                    block.add(Op::Copy(2), span);
+                    block.add(Op::Index, span);
+                    self.expression(value, block);
+                    block.add_from(binary_ops(op), span);
                 } else {
-                    add_op(self, block, Op::ReadLocal(var.slot));
+                    self.expression(value, block);
                 }
-                self.expression(block);
-                add_op(self, block, op);
-            } else {
-                self.expression(block);
+                block.add(Op::AssignIndex, span);
             }
 
-            if var.upvalue {
-                add_op(self, block, Op::AssignUpvalue(var.slot));
-            } else {
-                add_op(self, block, Op::AssignLocal(var.slot));
-            }
-        } else {
-            error!(self, "Using undefined variable {}", name);
+            _ => unreachable!("The parser only allows assigning to names, fields and indices"),
         }
     }
 
-    fn scope(&mut self, block: &mut Block) {
-        if !expect!(self, Token::LeftBrace, "Expected '{{' at start of block") {
-            return;
+    fn assign_variable(
+        &mut self,
+        var: Variable,
+        ident: &Identifier,
+        op: Option<BinOp>,
+        value: &Expression,
+        block: &mut Block,
+    ) {
+        let span = ident.span;
+        if !var.mutable {
+            // TODO(ed): Maybe a better error than "SyntaxError".
+            error!(self, ident, "Cannot assign to constant '{}'", ident.name);
+        }
+        if let Some(op) = op {
+            self.read_variable(&var, span, block);
+            self.expression(value, block);
+            block.add_from(binary_ops(op), span);
+        } else {
+            self.expression(value, block);
         }
+        self.check_type(&var.typ, span, block);
 
-        push_scope!(self, block, {
-            while !matches!(self.peek(), Token::RightBrace | Token::EOF) {
-                self.statement(block);
-                match self.peek() {
-                    Token::Newline => { self.eat(); },
-                    Token::RightBrace => { break; },
-                    _ => { error!(self, "Expect newline after statement"); },
-                }
+        if var.upvalue {
+            block.add(Op::AssignUpvalue(var.slot), span);
+        } else {
+            block.add(Op::AssignLocal(var.slot), span);
+        }
+    }
+
+    fn scope(&mut self, statements: &[Statement], span: Span, block: &mut Block) {
+        let end = statements.last().map(|statement| statement.span).unwrap_or(span);
+        push_scope!(self, block, end, {
+            for statement in statements.iter() {
+                self.statement(statement, block);
             }
         });
-
-        expect!(self, Token::RightBrace, "Expected '}}' at end of block");
     }
 
-    fn if_statment(&mut self, block: &mut Block) {
-        expect!(self, Token::If, "Expected 'if' at start of if-statement");
-        self.expression(block);
-        let jump = add_op(self, block, Op::Illegal);
-        self.scope(block);
-
-        if Token::Else == self.peek() {
-            self.eat();
+    fn if_statment(
+        &mut self,
+        span: Span,
+        condition: &Expression,
+        pass: &[Statement],
+        fail: &[Statement],
+        block: &mut Block,
+    ) {
+        self.expression(condition, block);
+        let jump = block.add(Op::Illegal, span);
+        self.scope(pass, span, block);
 
-            let else_jmp = add_op(self, block, Op::Illegal);
+        if fail.is_empty() {
             block.patch(Op::JmpFalse(block.curr()), jump);
-
-            match self.peek() {
-                Token::If => self.if_statment(block),
-                Token::LeftBrace => self.scope(block),
-                _ => error!(self, "Epected 'if' or '{{' after else"),
-            }
-            block.patch(Op::Jmp(block.curr()), else_jmp);
         } else {
+            let else_jmp = block.add(Op::Illegal, span);
             block.patch(Op::JmpFalse(block.curr()), jump);
+            self.scope(fail, span, block);
+            block.patch(Op::Jmp(block.curr()), else_jmp);
         }
     }
 
     //TODO de-complexify
-    fn for_loop(&mut self, block: &mut Block) {
-        expect!(self, Token::For, "Expected 'for' at start of for-loop");
-
-        push_scope!(self, block, {
+    fn for_in_loop(
+        &mut self,
+        span: Span,
+        name: &Identifier,
+        iterable: &Expression,
+        body: &[Statement],
+        block: &mut Block,
+    ) {
+        push_scope!(self, block, span, {
             self.frame_mut().push_loop();
-            // Definition
-            match self.peek_four() {
-                // TODO(ed): Typed definitions aswell!
-                (Token::Identifier(name), Token::ColonEqual, ..) => {
-                    self.eat();
-                    self.eat();
-                    self.definition_statement(&name, Type::Unknown, block);
-                }
 
-                (Token::Comma, ..) => {}
-
-                _ => { error!(self, "Expected definition at start of for-loop"); }
-            }
-
-            expect!(self, Token::Comma, "Expect ',' between initalizer and loop expression");
-
-            let cond = block.curr();
-            self.expression(block);
-            let cond_out = add_op(self, block, Op::Illegal);
-            let cond_cont = add_op(self, block, Op::Illegal);
-            expect!(self, Token::Comma, "Expect ',' between initalizer and loop expression");
-
-            let inc = block.curr();
-            push_scope!(self, block, {
-                self.statement(block);
+            // The collection and the position in it live in hidden locals.
+            self.expression(iterable, block);
+            let _ = self.define(Variable {
+                read: true,
+                active: true,
+                ..Variable::new("/collection", false, Type::Unknown)
+            }, span);
+            let start = self.add_constant(Value::Int(0));
+            block.add(Op::Constant(start), span);
+            let _ = self.define(Variable {
+                read: true,
+                active: true,
+                ..Variable::new("/index", false, Type::Unknown)
+            }, span);
+
+            let next = block.add(Op::Illegal, span);
+            push_scope!(self, block, span, {
+                let var = Variable::new(&name.name, false, Type::Unknown);
+                if let Ok(slot) = self.define(var, name.span) {
+                    self.stack_mut()[slot].active = true;
+                }
+                self.scope(body, span, block);
             });
-            add_op(self, block, Op::Jmp(cond));
+            block.add(Op::Jmp(next), span);
 
-            // patch_jmp!(Op::Jmp, cond_cont => block.curr());
-            block.patch(Op::Jmp(block.curr()), cond_cont);
-            self.scope(block);
-            add_op(self, block, Op::Jmp(inc));
-
-            block.patch(Op::JmpFalse(block.curr()), cond_out);
+            block.patch(Op::IterNext(block.curr()), next);
 
             let stacksize = self.frame().stack.len();
-            self.frame_mut().pop_loop(block, stacksize, inc, block.curr());
+            self.frame_mut().pop_loop(block, stacksize, next, block.curr());
         });
     }
 
-    fn parse_type(&mut self) -> Result<Type, ()> {
-        let mut tys = HashSet::new();
-        tys.insert(self.parse_simple_type()?);
-        loop {
-            match self.peek() {
-                Token::QuestionMark => {
-                    self.eat();
-                    tys.insert(Type::Void);
-                    return Ok(Type::Union(tys));
-                },
+    /// Defines the name bound in a match-arm, the value is on the stack.
+    fn bind(&mut self, name: &Identifier) {
+        if let Ok(slot) = self.define(Variable::new(&name.name, false, Type::Unknown), name.span) {
+            self.stack_mut()[slot].active = true;
+            self.stack_mut()[slot].read |= name.name == "_";
+        }
+    }
+
+    fn match_statement(
+        &mut self,
+        span: Span,
+        value: &Expression,
+        arms: &[MatchArm],
+        fallback: Option<&[Statement]>,
+        block: &mut Block,
+    ) {
+        push_scope!(self, block, span, {
+            // The value that's matched on lives in a hidden local.
+            self.expression(value, block);
+            let value = match self.define(Variable {
+                read: true,
+                active: true,
+                ..Variable::new("/match", false, Type::Unknown)
+            }, span) {
+                Ok(slot) => slot,
+                Err(_) => { return; }
+            };
 
-                Token::Pipe => {
-                    self.eat();
-                    tys.insert(self.parse_simple_type()?);
-                },
+            let mut covered = HashSet::new();
+            // The variants covered for each enum, by its id.
+            let mut variants = HashMap::new();
+            let mut ends = Vec::new();
+            for arm in arms.iter() {
+                let span = arm.span;
+                match &arm.pattern {
+                    Pattern::Type(name, ty) => {
+                        let ty = self.resolve_type(ty);
+                        covered.extend(ty.members());
+                        let ty = self.add_constant(Value::Ty(ty));
+                        block.add(Op::ReadLocal(value), span);
+                        block.add(Op::Is(ty), span);
+                        let skip = block.add(Op::Illegal, span);
+
+                        push_scope!(self, block, span, {
+                            block.add(Op::ReadLocal(value), span);
+                            self.bind(name);
+                            self.scope(&arm.body, span, block);
+                        });
+                        ends.push(block.add(Op::Illegal, span));
+
+                        block.patch(Op::JmpFalse(block.curr()), skip);
+                    }
 
-                _ => {
-                    break;
-                },
-            }
-        }
-        if tys.len() == 1 {
-            Ok(tys.iter().next().unwrap().clone())
-        } else {
-            Ok(Type::Union(tys))
-        }
-    }
-
-    fn parse_simple_type(&mut self) -> Result<Type, ()> {
-        match self.peek() {
-            Token::Fn => {
-                self.eat();
-                let mut params = Vec::new();
-                let return_type = loop {
-                    match self.peek() {
-                        Token::Identifier(_) | Token::Fn => {
-                            if let Ok(ty) = self.parse_type() {
-                                params.push(ty);
-                                if self.peek() == Token::Comma {
-                                    self.eat();
-                                }
-                            } else {
-                                error!(self, "Function type signature contains non-type {:?}", self.peek());
-                                return Err(());
-                            }
-                        }
-                        Token::Arrow => {
-                            self.eat();
-                            break self.parse_type().unwrap_or(Type::Void);
+                    Pattern::Variant { ty, variant, binding } => {
+                        let slot = self.find_constant(&ty.name, ty.span);
+                        let e = if let Value::Ty(Type::Enum(e)) = &self.constants[slot] {
+                            Rc::clone(e)
+                        } else {
+                            error!(self, ty, "Expected an enum in match-arm, but '{}' isn't one", ty.name);
+                            continue;
+                        };
+                        let index = if let Some(index) = e.variant(&variant.name) {
+                            index
+                        } else {
+                            error!(self, variant, "'{}' has no variant named '{}'", e.name, variant.name);
+                            let variants: Vec<_> = e.variants.iter().map(|(name, _)| name.clone()).collect();
+                            self.suggest(&variant.name, &variants);
+                            continue;
+                        };
+                        if binding.is_some() && e.variants[index].1.is_none() {
+                            error!(self, variant, "'{}.{}' doesn't have a payload", e.name, variant.name);
                         }
-                        Token::Comma | Token::Equal => {
-                            break Type::Void;
+                        variants.entry(e.id)
+                            .or_insert_with(|| (Rc::clone(&e), HashSet::new()))
+                            .1
+                            .insert(index);
+
+                        let variant = self.add_constant(Value::Variant(Rc::clone(&e), index, Box::new(Value::Nil)));
+                        block.add(Op::ReadLocal(value), span);
+                        block.add(Op::IsVariant(variant), span);
+                        let skip = block.add(Op::Illegal, span);
+
+                        push_scope!(self, block, span, {
+                            if let Some(binding) = binding {
+                                block.add(Op::ReadLocal(value), span);
+                                block.add(Op::Payload(variant), span);
+                                self.bind(binding);
+                            }
+                            self.scope(&arm.body, span, block);
+                        });
+                        ends.push(block.add(Op::Illegal, span));
+
+                        block.patch(Op::JmpFalse(block.curr()), skip);
+                    }
+
+                    Pattern::Literal(expr) => {
+                        let literal = literal(expr);
+                        let ty = Type::from(&literal);
+                        let nil = literal.is_nil();
+                        if nil {
+                            covered.insert(Type::Void);
                         }
-                        token => {
-                            error!(self, "Function type signature contains non-type {:?}", token);
-                            return Err(());
+                        let ty = self.add_constant(Value::Ty(ty));
+                        block.add(Op::ReadLocal(value), span);
+                        block.add(Op::Is(ty), span);
+                        let skip = block.add(Op::Illegal, span);
+
+                        // Nil is the only value of its type, so there's nothing to compare.
+                        let skip_value = if nil {
+                            None
+                        } else {
+                            block.add(Op::ReadLocal(value), span);
+                            let literal = self.add_constant(literal);
+                            block.add(Op::Constant(literal), span);
+                            block.add(Op::Equal, span);
+                            Some(block.add(Op::Illegal, span))
+                        };
+
+                        self.scope(&arm.body, span, block);
+                        ends.push(block.add(Op::Illegal, span));
+
+                        block.patch(Op::JmpFalse(block.curr()), skip);
+                        if let Some(skip_value) = skip_value {
+                            block.patch(Op::JmpFalse(block.curr()), skip_value);
                         }
                     }
-                };
-                let f = Type::Function(params, Box::new(return_type));
-                Ok(f)
-            }
-
-            Token::LeftParen => {
-                self.eat();
-                let mut element = Vec::new();
-                loop {
-                    element.push(self.parse_type()?);
-                    if self.peek() == Token::RightParen {
-                        self.eat();
-                        return Ok(Type::Tuple(element));
-                    }
-                    if !expect!(self,
-                                Token::Comma,
-                                "Expect comma efter element in tuple") {
-                        return Err(());
-                    }
                 }
             }
 
-            Token::LeftBracket => {
-                self.eat();
-                let ty = self.parse_type();
-                expect!(self, Token::RightBracket, "Expected ']' after list type");
-                return match ty {
-                    Ok(ty) => Ok(Type::List(Box::new(ty))),
-                    Err(_) => Err(()),
+            for (e, variants) in variants.values() {
+                if variants.len() == e.variants.len() {
+                    covered.insert(Type::Enum(Rc::clone(e)));
                 }
             }
 
-            Token::Identifier(x) => {
-                self.eat();
-                match x.as_str() {
-                    "void" => Ok(Type::Void),
-                    "int" => Ok(Type::Int),
-                    "float" => Ok(Type::Float),
-                    "bool" => Ok(Type::Bool),
-                    "str" => Ok(Type::String),
-                    x => {
-                        let blob = self.find_constant(x);
-                        if let Value::Blob(blob) = &self.constants[blob] {
-                            Ok(Type::Instance(Rc::clone(blob)))
-                        } else {
-                            // TODO(ed): This is kinda bad. If the type cannot
-                            // be found it tries to infer it during runtime
-                            // and doesn't verify it.
-                            Ok(Type::Unknown)
-                        }
-                    }
-                }
+            if let Some(fallback) = fallback {
+                self.scope(fallback, span, block);
+            } else {
+                let covered = self.add_constant(Value::Ty(Type::Union(covered)));
+                block.add(Op::ReadLocal(value), span);
+                block.add(Op::NoMatch(covered), span);
             }
-            _ => Err(()),
-        }
-    }
 
-    fn blob_statement(&mut self, _block: &mut Block) {
-        let name = if let Token::Identifier(name) = self.eat() {
-            name
-        } else {
-            error!(self, "Expected identifier after 'blob'");
-            return;
-        };
-        expect!(self, Token::ColonColon, "Expected '::' when declaring a blob");
-        expect!(self, Token::Blob, "Expected 'blob' when declaring a blob");
+            for end in ends {
+                block.patch(Op::Jmp(block.curr()), end);
+            }
+        });
+    }
 
-        expect!(self, Token::LeftBrace, "Expected 'blob' body. AKA '{{'");
+    /// Compiles both 'for cond { }' and the infinite 'for { }'.
+    fn conditional_loop(
+        &mut self,
+        span: Span,
+        condition: Option<&Expression>,
+        body: &[Statement],
+        block: &mut Block,
+    ) {
+        push_scope!(self, block, span, {
+            self.frame_mut().push_loop();
 
-        let mut blob = Blob::new(self.new_blob_id(), &name);
-        loop {
-            if matches!(self.peek(), Token::EOF | Token::RightBrace) { break; }
-            if matches!(self.peek(), Token::Newline) { self.eat(); continue; }
+            let start = block.curr();
+            let cond_out = condition.map(|condition| {
+                self.expression(condition, block);
+                block.add(Op::Illegal, span)
+            });
 
-            let name = if let Token::Identifier(name) = self.eat() {
-                name
-            } else {
-                error!(self, "Expected identifier for field");
-                continue;
-            };
+            self.scope(body, span, block);
+            block.add(Op::Jmp(start), span);
 
-            expect!(self, Token::Colon, "Expected ':' after field name");
+            if let Some(cond_out) = cond_out {
+                block.patch(Op::JmpFalse(block.curr()), cond_out);
+            }
 
-            let ty = if let Ok(ty) = self.parse_type() {
-                ty
-            } else {
-                error!(self, "Failed to parse blob-field type");
-                continue;
-            };
+            let stacksize = self.frame().stack.len();
+            self.frame_mut().pop_loop(block, stacksize, start, block.curr());
+        });
+    }
 
-            if let Err(_) = blob.add_field(&name, ty) {
-                error!(self, "A field named '{}' is defined twice for '{}'", name, blob.name);
+    fn for_loop(
+        &mut self,
+        span: Span,
+        init: Option<&Statement>,
+        condition: &Expression,
+        step: &Statement,
+        body: &[Statement],
+        block: &mut Block,
+    ) {
+        push_scope!(self, block, span, {
+            self.frame_mut().push_loop();
+            if let Some(init) = init {
+                self.statement(init, block);
             }
-        }
 
-        expect!(self, Token::RightBrace, "Expected '}}' after 'blob' body");
+            let cond = block.curr();
+            self.expression(condition, block);
+            let cond_out = block.add(Op::Illegal, span);
+            let cond_cont = block.add(Op::Illegal, span);
+
+            let inc = block.curr();
+            push_scope!(self, block, span, {
+                self.statement(step, block);
+            });
+            block.add(Op::Jmp(cond), span);
+
+            block.patch(Op::Jmp(block.curr()), cond_cont);
+            self.scope(body, span, block);
+            block.add(Op::Jmp(inc), span);
+
+            block.patch(Op::JmpFalse(block.curr()), cond_out);
 
-        let blob = Value::Blob(Rc::new(blob));
-        self.named_constant(name, blob);
+            let stacksize = self.frame().stack.len();
+            self.frame_mut().pop_loop(block, stacksize, inc, block.curr());
+        });
     }
 
-    fn access_dotted(&mut self, block: &mut Block) {
-        let name = match self.peek() {
-            Token::Identifier(name) => name,
-            _ => unreachable!(),
-        };
-        if let Some(_) = self.find_namespace(&name) {
-            self.expression(block);
-        } else {
-            if rest_of_line_contains!(self,
-                  Token::Equal
-                | Token::PlusEqual
-                | Token::MinusEqual
-                | Token::StarEqual
-                | Token::SlashEqual) {
-                self.blob_field(block)
-            } else {
-                self.expression(block)
+    fn resolve_type(&mut self, ty: &ast::Type) -> Type {
+        match &ty.kind {
+            TypeKind::Resolved(ty) => ty.clone(),
+            TypeKind::UserDefined(path) => self.user_type(path),
+            TypeKind::Union(tys) => {
+                let mut tys: HashSet<_> = tys.iter().map(|ty| self.resolve_type(ty)).collect();
+                if tys.len() == 1 {
+                    tys.drain().next().unwrap()
+                } else {
+                    Type::Union(tys)
+                }
+            }
+            TypeKind::Fn(params, ret) => {
+                let params = params.iter().map(|ty| self.resolve_type(ty)).collect();
+                Type::Function(params, Box::new(self.resolve_type(ret)))
+            }
+            TypeKind::Tuple(tys) => Type::Tuple(tys.iter().map(|ty| self.resolve_type(ty)).collect()),
+            TypeKind::List(ty) => Type::List(Box::new(self.resolve_type(ty))),
+            TypeKind::Dict(key, value) => {
+                Type::Dict(Box::new(self.resolve_type(key)), Box::new(self.resolve_type(value)))
             }
         }
     }
 
-    //TODO rename
-    fn blob_field(&mut self, block: &mut Block) {
-        let name = match self.eat() {
-            Token::Identifier(name) => name,
-            _ => unreachable!(),
-        };
-
-        if let Some(var) = self.find_variable(&name) {
-            self.mark_read(self.frames().len() - 1, &var);
-            if var.upvalue {
-                add_op(self, block, Op::ReadUpvalue(var.slot));
-            } else {
-                add_op(self, block, Op::ReadLocal(var.slot));
-            }
-            loop {
-                match self.peek() {
-                    Token::Dot => {
-                        self.eat();
-                        let field = if let Token::Identifier(field) = self.eat() {
-                            String::from(field)
-                        } else {
-                            error!(self, "Expected fieldname after '.'");
-                            return;
-                        };
-
-                        let field = self.intern_string(field);
-                        let op = match self.peek() {
-                            Token::Equal => {
-                                self.eat();
-                                self.expression(block);
-                                add_op(self, block, Op::Set(field));
-                                return;
-                            }
-
-                            Token::PlusEqual => Op::Add,
-                            Token::MinusEqual => Op::Sub,
-                            Token::StarEqual => Op::Mul,
-                            Token::SlashEqual => Op::Div,
+    /// Finds the blob, enum or type parameter a type refers to.
+    fn user_type(&mut self, path: &[Identifier]) -> Type {
+        let name = &path[0];
+        if path.len() == 1 && self.type_params.contains(&name.name) {
+            return Type::Generic(name.name.clone());
+        }
 
-                            _ => {
-                                add_op(self, block, Op::Get(field));
-                                continue;
-                            }
-                        };
-                        add_op(self, block, Op::Copy);
-                        add_op(self, block, Op::Get(field));
-                        self.eat();
-                        self.expression(block);
-                        add_op(self, block, op);
-                        add_op(self, block, Op::Set(field));
-                        return;
+        let slot = if let Some(mut namespace) = self.find_namespace(&name.name) {
+            let mut slot = None;
+            for (i, field) in path.iter().enumerate().skip(1) {
+                match self.contextes[&namespace].namespace.get(&field.name) {
+                    Some(Name::Slot(found, _)) | Some(Name::Unknown(found, ..)) if i + 1 == path.len() => {
+                        slot = Some(*found);
                     }
-                    Token::Newline => {
-                        return;
-                    }
-                    _ => {
-                        if !self.call_maybe(block) {
-                            error!(self, "Unexpected token when parsing blob-field");
-                            return;
-                        }
+                    Some(Name::Namespace(inner)) => {
+                        namespace = inner.clone();
                     }
+                    _ => break,
                 }
             }
+            if slot.is_none() {
+                error!(self, name, "Expected a blob from '{}'", name.name);
+            }
+            slot
         } else {
-            error!(self, "Cannot find variable '{}'", name);
-            return;
-        }
-    }
-
-    fn outer_statement(&mut self, block: &mut Block) {
-        self.clear_panic();
-        match self.peek_four() {
-            (Token::Identifier(name), Token::ColonEqual, ..) => {
-                self.eat();
-                self.eat();
-                self.definition_statement(&name, Type::Unknown, block);
-            },
+            let slot = match self.names().get(&name.name) {
+                Some(Name::Slot(slot, _)) | Some(Name::Unknown(slot, ..)) if path.len() == 1 => Some(*slot),
+                _ => None,
+            };
+            if slot.is_none() {
+                error!(self, name, "Unknown type '{}'", name.name);
+                let types: Vec<_> = self.names().keys().cloned().collect();
+                self.suggest(&name.name, &types);
+            }
+            slot
+        };
 
-            (Token::Identifier(_), Token::ColonColon, Token::Blob, ..) => {
-                self.blob_statement(block);
-            },
+        match slot.map(|slot| &self.constants[slot]) {
+            Some(Value::Blob(blob)) => Type::Instance(Rc::clone(blob)),
+            Some(Value::Ty(Type::Enum(e))) => Type::Enum(Rc::clone(e)),
+            Some(_) => {
+                error!(self, name, "'{}' is not a type", name.name);
+                Type::Unknown
+            }
+            None => Type::Unknown,
+        }
+    }
+
+    fn blob_statement(
+        &mut self,
+        name: &Identifier,
+        fields: &[Field],
+        methods: &[(Identifier, Function)],
+        block: &mut Block,
+    ) {
+        // The blob was declared before anything was compiled.
+        let slot = self.find_constant(&name.name, name.span);
+        let blob = match &self.constants[slot] {
+            Value::Blob(blob) => Rc::clone(blob),
+            _ => Rc::new(Blob::new(self.new_blob_id(), &name.name)),
+        };
 
-            (Token::Identifier(name), Token::ColonColon, ..) => {
-                self.eat();
-                self.eat();
-                self.constant_statement(&name, Type::Unknown, block);
-            },
+        for field in fields.iter() {
+            let ty = self.resolve_type(&field.ty);
+            if let Some(default) = &field.default {
+                blob.defaults.borrow_mut().insert(field.name.name.clone(), literal(default));
+            }
+            if blob.add_field(&field.name.name, ty).is_err() {
+                error!(self, &field.name, "A field named '{}' is defined twice for '{}'", field.name.name, blob.name);
+            }
+        }
 
-            (Token::Identifier(name), Token::Colon, ..) => {
-                self.eat();
-                self.eat();
-                if let Ok(typ) = self.parse_type() {
-                    expect!(self, Token::Equal, "Expected assignment");
-                    self.definition_statement(&name, typ, block);
-                } else {
-                    error!(self, "Expected type found '{:?}'", self.peek());
-                }
+        let mut added = Vec::new();
+        for (method, function) in methods.iter() {
+            if blob.fields.borrow().contains_key(&method.name) {
+                error!(self, method, "A method named '{}' has the same name as a field of '{}'", method.name, blob.name);
+                continue;
+            }
+            if blob.methods.borrow().contains_key(&method.name) {
+                error!(self, method, "A method named '{}' is defined twice for '{}'", method.name, blob.name);
+                continue;
             }
+            let slot = self.forward_constant(format!("{}.{}", blob.name, method.name), method.span);
+            // Can't fail, the name is neither a field nor a method.
+            let _ = blob.add_method(&method.name, slot);
+            added.push((method, function));
+        }
 
-            (Token::Newline, ..) => {}
+        self.named_constant(name.name.clone(), name.span, Value::Blob(Rc::clone(&blob)));
 
-            (a, b, c, d) => {
-                error!(self, "Unknown outer token sequence: {:?} {:?} {:?} {:?}", a, b, c, d)
+        // Methods are compiled when the blob is done, since they
+        // can use the blob in their signatures.
+        for (method, function) in added {
+            let method = format!("{}.{}", name.name, method.name);
+            let slot = self.function(function, Some(&method), Some(Type::Instance(Rc::clone(&blob))));
+            // Linked just like a global function.
+            block.add(Op::Link(slot), function.span);
+            if let Value::Function(_, block) = &self.constants[slot] {
+                block.borrow_mut().mark_constant();
+            }
+        }
+    }
+
+    fn enum_statement(&mut self, name: &Identifier, variants: &[(Identifier, Option<ast::Type>)]) {
+        let mut e = Enum::new(self.new_blob_id(), &name.name);
+        for (variant, payload) in variants.iter() {
+            let payload = payload.as_ref().map(|ty| self.resolve_type(ty));
+            if e.add_variant(&variant.name, payload).is_err() {
+                error!(self, variant, "A variant named '{}' is defined twice for '{}'", variant.name, e.name);
             }
         }
+        self.named_constant(name.name.clone(), name.span, Value::Ty(Type::Enum(Rc::new(e))));
     }
 
-    fn statement(&mut self, block: &mut Block) {
-        self.clear_panic();
+    fn outer_statement(&mut self, statement: &Statement, block: &mut Block) {
+        match &statement.kind {
+            StatementKind::Blob { name, fields, methods } => {
+                self.blob_statement(name, fields, methods, block);
+            }
 
-        match self.peek_four() {
-            (Token::Print, ..) => {
-                self.eat();
-                self.expression(block);
-                add_op(self, block, Op::Print);
+            StatementKind::Definition { ident, kind, value, .. } => {
+                self.global_statement(ident, *kind, value, block);
             }
 
-            (Token::Identifier(_), Token::Equal, ..) |
-            (Token::Identifier(_), Token::PlusEqual, ..) |
-            (Token::Identifier(_), Token::MinusEqual, ..) |
-            (Token::Identifier(_), Token::SlashEqual, ..) |
-            (Token::Identifier(_), Token::StarEqual, ..)
+            // Enums are compiled when they're forward declared.
+            StatementKind::Use { .. } | StatementKind::Enum { .. } => {}
 
-                => {
-                self.assign(block);
+            _ => unreachable!("The parser only allows definitions outside of functions"),
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement, block: &mut Block) {
+        let span = statement.span;
+        match &statement.kind {
+            StatementKind::Print { value } => {
+                self.expression(value, block);
+                block.add(Op::Print, span);
+            }
+
+            StatementKind::Definition { ident, kind, ty, value } => {
+                self.definition_statement(ident, *kind, ty.as_ref(), value, block);
             }
 
-            (Token::Identifier(_), Token::Dot, ..) => {
-                self.access_dotted(block);
+            StatementKind::Assignment { op, target, value } => {
+                self.assignment(span, *op, target, value, block);
             }
 
-            (Token::Identifier(name), Token::Colon, ..) => {
-                self.eat();
-                self.eat();
-                if let Ok(typ) = self.parse_type() {
-                    expect!(self, Token::Equal, "Expected assignment");
-                    self.definition_statement(&name, typ, block);
+            StatementKind::Yield { value } => {
+                if let Some(value) = value {
+                    self.expression(value, block);
                 } else {
-                    error!(self, "Expected type found '{:?}'", self.peek());
+                    let nil = self.add_constant(Value::Nil);
+                    block.add(Op::Constant(nil), span);
                 }
+                block.add(Op::Yield, span);
             }
 
-            (Token::Yield, ..) => {
-                self.eat();
-                add_op(self, block, Op::Yield);
+            StatementKind::If { condition, pass, fail } => {
+                self.if_statment(span, condition, pass, fail, block);
             }
 
-            (Token::Identifier(name), Token::ColonEqual, ..) => {
-                self.eat();
-                self.eat();
-                self.definition_statement(&name, Type::Unknown, block);
+            StatementKind::For { init, condition, step, body } => {
+                self.for_loop(span, init.as_deref(), condition, step, body, block);
             }
 
-            (Token::Identifier(name), Token::ColonColon, ..) => {
-                self.eat();
-                self.eat();
-                self.constant_statement(&name, Type::Unknown, block);
+            StatementKind::ForIn { name, iterable, body } => {
+                self.for_in_loop(span, name, iterable, body, block);
             }
 
-            (Token::If, ..) => {
-                self.if_statment(block);
+            StatementKind::Loop { condition, body } => {
+                self.conditional_loop(span, condition.as_ref(), body, block);
             }
 
-            (Token::For, ..) => {
-                self.for_loop(block);
+            StatementKind::Match { value, arms, fallback } => {
+                self.match_statement(span, value, arms, fallback.as_deref(), block);
             }
 
-            (Token::Break, ..) => {
-                self.eat();
-                let addr = add_op(self, block, Op::Illegal);
+            StatementKind::Break => {
+                let addr = block.add(Op::Illegal, span);
                 let stack_size = self.frame().stack.len();
                 if self.frame_mut().add_break(addr, stack_size).is_err() {
-                    error!(self, "Cannot place 'break' outside of loop");
+                    let kind = ErrorKind::SyntaxError(span.line, Token::Break);
+                    self.error_on_line(kind, span, Some(String::from("Cannot place 'break' outside of loop")));
                 }
             }
 
-            (Token::Continue, ..) => {
-                self.eat();
-                let addr = add_op(self, block, Op::Illegal);
+            StatementKind::Continue => {
+                let addr = block.add(Op::Illegal, span);
                 let stack_size = self.frame().stack.len();
                 if self.frame_mut().add_continue(addr, stack_size).is_err() {
-                    error!(self, "Cannot place 'continue' outside of loop");
+                    let kind = ErrorKind::SyntaxError(span.line, Token::Continue);
+                    self.error_on_line(kind, span, Some(String::from("Cannot place 'continue' outside of loop")));
                 }
             }
 
-            (Token::Ret, ..) => {
-                self.eat();
-                if self.peek() == Token::Newline {
-                    self.eat();
-                    let nil = self.add_constant(Value::Nil);
-                    add_op(self, block, Op::Constant(nil));
+            StatementKind::Ret { value } => {
+                if let Some(value) = value {
+                    self.expression(value, block);
                 } else {
-                    self.expression(block);
+                    let nil = self.add_constant(Value::Nil);
+                    block.add(Op::Constant(nil), span);
                 }
-                add_op(self, block, Op::Return);
+                block.add(Op::Return, span);
             }
 
-            (Token::Unreachable, ..) => {
-                self.eat();
-                add_op(self, block, Op::Unreachable);
+            StatementKind::Unreachable => {
+                block.add(Op::Unreachable, span);
             }
 
-            (Token::LeftBrace, ..) => {
-                self.scope(block);
+            StatementKind::Block { statements } => {
+                self.scope(statements, span, block);
             }
 
-            (Token::Newline, ..) => {}
+            StatementKind::StatementExpression { value } => {
+                self.expression(value, block);
+                block.add(Op::Pop, span);
+            }
 
-            _ => {
-                self.expression(block);
-                add_op(self, block, Op::Pop);
+            StatementKind::Use { .. }
+                | StatementKind::Blob { .. }
+                | StatementKind::Enum { .. } => {
+                unreachable!("The parser only allows these outside of functions")
             }
         }
     }
 
-    pub(crate) fn compile(&mut self, name: &str, file: &Path, functions: &[(String, RustFunction)]) -> Result<Prog, Vec<Error>> {
-        let main = Variable::new("/preamble", false, Type::Void);
-        let slot = self.define(main).unwrap();
-        self.frame_mut().stack[slot].read = true;
-
-        for section in 0..self.sections.len() {
-            self.init_section(section);
-            let section = &mut self.sections[section];
-            match (section.tokens.get(0), section.tokens.get(1), section.tokens.get(2))  {
-                (Some((Token::Use, _)),
-                 Some((Token::Identifier(name), _)), ..) => {
-                    let name = name.to_string();
-                    self.add_namespace(name);
-                }
+    /// Globals from all files live on the stack of the outermost block.
+    /// Every file gets placeholders where the other files' globals are,
+    /// so slots are the same in all files.
+    fn pad_globals(&mut self) {
+        let len = self.contextes.values()
+            .map(|context| context.frames[0].stack.len())
+            .max()
+            .unwrap_or(0);
+        for context in self.contextes.values_mut() {
+            let stack = &mut context.frames[0].stack;
+            while stack.len() < len {
+                stack.push(Variable::placeholder(stack.len()));
+            }
+        }
+    }
 
-                (Some((Token::Identifier(name), _)),
-                 Some((Token::ColonColon, _)),
-                 Some((Token::Fn, _))) => {
-                    let name = name.to_string();
-                    self.forward_constant(name);
-                }
+    fn define_global(&mut self, var: Variable, span: Span) -> Result<usize, ()> {
+        self.pad_globals();
+        self.define(var, span)
+    }
 
-                (Some((Token::Identifier(name), _)),
-                 Some((Token::ColonColon, _)),
-                 Some((Token::Blob, _))) => {
-                    let name = name.to_string();
-                    self.forward_constant(name);
-                }
+    /// Declares everything in the outermost scope of the files, so
+    /// the order they're written in doesn't matter.
+    fn prepass(&mut self, modules: &[Module]) {
+        // Blobs are declared before anything else, so all types can refer to them.
+        for module in modules.iter() {
+            self.current_file = module.path.clone();
+            for statement in module.statements.iter() {
+                match &statement.kind {
+                    StatementKind::Use { file } => {
+                        self.add_namespace(file);
+                    }
 
-                (Some((Token::Identifier(name), _)),
-                 Some((Token::Colon, _)), ..) => {
-                    let name = name.to_string();
-                    self.eat();
-                    self.eat();
-                    if let Ok(ty) = self.parse_type() {
-                        let is_mut = self.peek() == Token::Equal;
-                        let var = Variable::new(&name, is_mut, ty);
-                        let _ = self.define(var).unwrap();
-                    } else {
-                        error!(self, "Failed to parse type global '{}'", name);
+                    StatementKind::Blob { name, .. } => {
+                        let slot = self.forward_constant(name.name.clone(), name.span);
+                        let blob = Blob::new(self.new_blob_id(), &name.name);
+                        self.constants[slot] = Value::Blob(Rc::new(blob));
                     }
-                }
 
-                (Some((Token::Identifier(name), _)),
-                 Some((Token::ColonColon, _)), ..) => {
-                    let var = Variable::new(name, false, Type::Unknown);
-                    let _ = self.define(var).unwrap();
+                    _ => {}
                 }
+            }
+        }
 
-                (Some((Token::Identifier(name), _)),
-                 Some((Token::ColonEqual, _)), ..) => {
-                    let var = Variable::new(name, true, Type::Unknown);
-                    let _ = self.define(var).unwrap();
-                }
+        for module in modules.iter() {
+            self.current_file = module.path.clone();
+            for statement in module.statements.iter() {
+                match &statement.kind {
+                    StatementKind::Definition {
+                        ident,
+                        kind: VarKind::Const,
+                        value: Expression { kind: ExpressionKind::Function(_), .. },
+                        ..
+                    } => {
+                        self.forward_constant(ident.name.clone(), ident.span);
+                    }
 
+                    StatementKind::Enum { name, variants } => {
+                        self.enum_statement(name, variants);
+                    }
 
-                (None, ..) => {}
+                    StatementKind::Definition { ident, kind, ty, .. } => {
+                        let ty = self.variable_type(ty.as_ref(), ident.span);
+                        let var = Variable::new(&ident.name, *kind == VarKind::Mutable, ty);
+                        let _ = self.define_global(var, ident.span);
+                    }
 
-                (a, b, c) => {
-                    section.faulty = true;
-                    error!(self, "Unknown outer token sequence: {:?} {:?} {:?}. Expected 'use', function, blob or variable", a, b, c);
+                    _ => {}
                 }
             }
         }
 
+        self.pad_globals();
+    }
+
+    pub(crate) fn compile(
+        &mut self,
+        name: &str,
+        file: &Path,
+        modules: &[Module],
+        functions: &[(String, RustFunction)],
+        inferred: Inferred,
+        strict: bool,
+    ) -> Result<Prog, Vec<Error>> {
+        self.inferred = inferred;
+        self.strict = strict;
+        for module in modules.iter() {
+            let mut context = CompilerContext::new();
+            let mut main = Variable::new("/preamble", false, Type::Void);
+            main.read = true;
+            context.frames[0].stack.push(main);
+            self.contextes.insert(module.path.clone(), context);
+        }
+
+        self.prepass(modules);
+
         self.functions = functions
             .to_vec()
             .into_iter()
@@ -1841,59 +1802,72 @@ impl Compiler {
             .map(|(i, (s, f))| (s, (i, f)))
             .collect();
         let mut block = Block::new(name, file);
-        for section in 0..self.sections.len() {
-            self.init_section(section);
-            if self.sections[section].faulty {
-                continue;
-            }
-            while !matches!(self.peek(), Token::EOF | Token::Use) {
-                self.outer_statement(&mut block);
-                expect!(self, Token::Newline | Token::EOF,
-                        "Expect newline or EOF after expression");
+        for module in modules.iter() {
+            self.current_file = module.path.clone();
+            for statement in module.statements.iter() {
+                self.outer_statement(statement, &mut block);
             }
         }
         block.ty = Type::Function(Vec::new(), Box::new(Type::Void));
 
-        if self.names().len() != 0 {
+        for module in modules.iter() {
+            self.current_file = module.path.clone();
+
             let errors: Vec<_> = self.names().iter().filter_map(|(name, kind)|
-                if let Name::Unknown(_, line) = kind {
-                    Some((ErrorKind::SyntaxError(*line, Token::Identifier(name.clone())),
-                    *line,
-                    format!("Usage of undefined value: '{}'", name,)))
+                if let Name::Unknown(_, span, closest) = kind {
+                    Some((name.clone(), *span, closest.clone()))
                 } else {
                     None
-                }) .collect();
-            for (e, l, m) in errors.iter() {
-                self.error_on_line(e.clone(), *l, Some(m.clone()));
+                }).collect();
+            for (name, span, closest) in errors.iter() {
+                self.name_error(name, *span, format!("Usage of undefined value: '{}'", name));
+                let mut candidates = self.names_in_scope();
+                candidates.extend(closest.iter().cloned());
+                self.suggest(name, &candidates);
+            }
+
+            // Globals in used files are read from other files.
+            let frame = self.frames_mut().pop().unwrap();
+            if self.current_file() != file {
+                continue;
+            }
+            for var in frame.stack.iter().skip(1) {
+                if !(var.read || var.upvalue) {
+                    self.name_error(&var.name, var.span, format!("Unused value '{}'", var.name));
+                }
             }
         }
 
-        self.init_section(0);
-        let constant = self.find_constant("start");
-        add_op(self, &mut block, Op::Constant(constant));
-        add_op(self, &mut block, Op::Call(0));
+        self.current_file = file.to_path_buf();
+        let span = modules.iter()
+            .find(|module| module.path == file)
+            .and_then(|module| module.statements.last())
+            .map(|statement| statement.span)
+            .unwrap_or_default();
+        let constant = self.find_constant("start", span);
+        block.add(Op::Constant(constant), span);
+        block.add(Op::Call(0), span);
 
         let tmp = self.add_constant(Value::Nil);
-        add_op(self, &mut block, Op::Constant(tmp));
-        add_op(self, &mut block, Op::Return);
-
-        for var in self.frames_mut().pop().unwrap().stack.iter().skip(1) {
-            if !(var.read || var.upvalue) {
-                let e = ErrorKind::SyntaxError(var.line, Token::Identifier(var.name.clone()));
-                let m = format!("Unused value '{}'", var.name);
-                self.error_on_line(e, var.line, Some(m));
-            }
-            self.panic = false;
-        }
+        block.add(Op::Constant(tmp), span);
+        block.add(Op::Return, span);
 
         self.blocks.insert(0, Rc::new(RefCell::new(block)));
 
+        let globals = self.contextes[file].namespace.iter().filter_map(|(name, kind)|
+            if let Name::Slot(slot, _) = kind {
+                Some((name.clone(), *slot))
+            } else {
+                None
+            }).collect();
+
         if self.errors.is_empty() {
             Ok(Prog {
                 blocks: self.blocks.clone(),
                 functions: functions.iter().map(|(_, f)| *f).collect(),
                 constants: self.constants.clone(),
                 strings: self.strings.clone(),
+                globals,
             })
         } else {
             Err(self.errors.clone())
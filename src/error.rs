@@ -5,9 +5,24 @@ use std::path::PathBuf;
 
 use owo_colors::OwoColorize;
 
-use crate::{Op, Value};
+use crate::{comma_separated, Op, Value};
 use crate::Type;
-use crate::tokenizer::Token;
+use crate::tokenizer::{Span, Token};
+
+/// Paints the text, if errors are written with colours.
+fn paint(colours: bool, text: impl fmt::Display, paint: fn(&str) -> String) -> String {
+    let text = text.to_string();
+    if colours {
+        paint(&text)
+    } else {
+        text
+    }
+}
+
+/// Quotes each item and separates them with commas.
+fn quoted<T: fmt::Display>(items: impl Iterator<Item = T>) -> String {
+    comma_separated(items.map(|item| format!("'{}'", item)))
+}
 
 #[derive(Debug, Clone)]
 pub enum ErrorKind {
@@ -21,10 +36,22 @@ pub enum ErrorKind {
     ExternTypeMismatch(String, Vec<Type>),
     ValueError(Op, Vec<Value>),
     UnknownField(Value, String),
+    /// (Blob, field)
+    UninitializedField(Value, String),
+    UnknownFunction(String),
+    /// The type of the value, which includes nil
+    MaybeNil(Type),
     ArgumentCount(usize, usize),
+    CannotResume,
+    /// The types no arm of a match covers
+    NonExhaustiveMatch(Vec<Type>),
+    /// The type of a key that can change after it's put in a dict
+    MutableKey(Type),
 
     /// (Indexed value, length, index)
     IndexOutOfBounds(Value, usize, usize),
+    /// (Dict, key)
+    UnknownKey(Value, Value),
 
     AssertFailed,
     InvalidProgram,
@@ -36,71 +63,257 @@ pub enum ErrorKind {
     GitConflictError(usize, usize),
 
     FileNotFound(PathBuf),
+    /// The chain of files that use each other, starting
+    /// and ending with the same file.
+    CyclicImport(Vec<PathBuf>),
     NoFileGiven,
+    /// A function was called from Rust while the VM was running.
+    VMBusy,
+    /// The VM was run when it had nothing left to run.
+    NothingToRun,
 }
 
 #[derive(Debug, Clone)]
 pub struct Error {
     pub kind: ErrorKind,
     pub file: PathBuf,
-    pub line: usize,
+    pub span: Span,
     pub message: Option<String>,
+    /// Help on top of the message, like which name was probably meant.
+    pub notes: Vec<String>,
+    /// The calls that were running when the error happened, outermost
+    /// first. Only errors at runtime have them.
+    pub stack: Vec<StackFrame>,
+}
+
+/// A call that was running when an error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    /// The name of the function.
+    pub function: String,
+    pub file: PathBuf,
+    /// The line the call was at, where the function was called
+    /// from for all but the last frame.
+    pub line: usize,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{} in {}", self.file.display(), self.line, self.function)
+    }
+}
+
+impl ErrorKind {
+    /// The name of the kind of error, which tools can rely on.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::TypeError(..) => "TypeError",
+            ErrorKind::TypeMismatch(..) => "TypeMismatch",
+            ErrorKind::CannotInfer(..) => "CannotInfer",
+            ErrorKind::ArgumentType(..) => "ArgumentType",
+            ErrorKind::IndexError(..) => "IndexError",
+            ErrorKind::ExternTypeMismatch(..) => "ExternTypeMismatch",
+            ErrorKind::ValueError(..) => "ValueError",
+            ErrorKind::UnknownField(..) => "UnknownField",
+            ErrorKind::UninitializedField(..) => "UninitializedField",
+            ErrorKind::UnknownFunction(..) => "UnknownFunction",
+            ErrorKind::MaybeNil(..) => "MaybeNil",
+            ErrorKind::ArgumentCount(..) => "ArgumentCount",
+            ErrorKind::CannotResume => "CannotResume",
+            ErrorKind::NonExhaustiveMatch(..) => "NonExhaustiveMatch",
+            ErrorKind::MutableKey(..) => "MutableKey",
+            ErrorKind::IndexOutOfBounds(..) => "IndexOutOfBounds",
+            ErrorKind::UnknownKey(..) => "UnknownKey",
+            ErrorKind::AssertFailed => "AssertFailed",
+            ErrorKind::InvalidProgram => "InvalidProgram",
+            ErrorKind::Unreachable => "Unreachable",
+            ErrorKind::SyntaxError(..) => "SyntaxError",
+            ErrorKind::GitConflictError(..) => "GitConflictError",
+            ErrorKind::FileNotFound(..) => "FileNotFound",
+            ErrorKind::CyclicImport(..) => "CyclicImport",
+            ErrorKind::NoFileGiven => "NoFileGiven",
+            ErrorKind::VMBusy => "VMBusy",
+            ErrorKind::NothingToRun => "NothingToRun",
+        }
+    }
+    /// The code of the kind of error, like `E0002`. Codes never change
+    /// meaning, so they can be searched for and given to `--explain`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::TypeError(..) => "E0001",
+            ErrorKind::TypeMismatch(..) => "E0002",
+            ErrorKind::CannotInfer(..) => "E0003",
+            ErrorKind::ArgumentType(..) => "E0004",
+            ErrorKind::IndexError(..) => "E0005",
+            ErrorKind::ExternTypeMismatch(..) => "E0006",
+            ErrorKind::ValueError(..) => "E0007",
+            ErrorKind::UnknownField(..) => "E0008",
+            ErrorKind::UninitializedField(..) => "E0009",
+            ErrorKind::UnknownFunction(..) => "E0010",
+            ErrorKind::MaybeNil(..) => "E0011",
+            ErrorKind::ArgumentCount(..) => "E0012",
+            ErrorKind::CannotResume => "E0013",
+            ErrorKind::NonExhaustiveMatch(..) => "E0014",
+            ErrorKind::IndexOutOfBounds(..) => "E0015",
+            ErrorKind::UnknownKey(..) => "E0016",
+            ErrorKind::AssertFailed => "E0017",
+            ErrorKind::InvalidProgram => "E0018",
+            ErrorKind::Unreachable => "E0019",
+            ErrorKind::SyntaxError(..) => "E0020",
+            ErrorKind::GitConflictError(..) => "E0021",
+            ErrorKind::FileNotFound(..) => "E0022",
+            ErrorKind::CyclicImport(..) => "E0023",
+            ErrorKind::NoFileGiven => "E0024",
+            ErrorKind::VMBusy => "E0025",
+            ErrorKind::MutableKey(..) => "E0026",
+            ErrorKind::NothingToRun => "E0027",
+        }
+    }
+
+    /// Notes that follow from the error itself, like which field was
+    /// probably meant when an unknown one is used.
+    pub(crate) fn notes(&self) -> Vec<String> {
+        match self {
+            ErrorKind::UnknownField(Value::Blob(blob), field)
+                | ErrorKind::UnknownField(Value::Instance(blob, _), field) => {
+                let fields = blob.fields.borrow();
+                let methods = blob.methods.borrow();
+                did_you_mean(field, fields.keys().chain(methods.keys()).map(String::as_str))
+                    .into_iter()
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// A longer description of the error with the given code.
+pub fn explain(code: &str) -> Option<&'static str> {
+    Some(match code.to_uppercase().as_str() {
+        "E0001" => "An operator was used on types it can't be used on, like adding \
+an int to a string. Convert one of the operands, so both have a type the \
+operator accepts.",
+        "E0002" => "A value has a different type than the one that was expected, \
+like assigning a str to a variable declared as int, or returning the wrong type \
+from a function.",
+        "E0003" => "The type of a value couldn't be worked out from how it's used. \
+Add a type annotation to the declaration.",
+        "E0004" => "A function was called with arguments of the wrong types. The \
+argument types have to match the parameter types of the function.",
+        "E0005" => "A value was indexed with something it can't be indexed with, \
+like indexing a list with a string or indexing an int.",
+        "E0006" => "A function written in Rust was given arguments it doesn't \
+accept. Check which types the function takes.",
+        "E0007" => "An operator was used on values it can't be used on, while the \
+program was running.",
+        "E0008" => "A field was read or written that the blob doesn't have. Check \
+the spelling, or add the field to the blob.",
+        "E0009" => "A blob was created without giving one of its fields a value. \
+Every field that can't be nil has to be given a value.",
+        "E0010" => "A function was called by name, but no global function has \
+that name.",
+        "E0011" => "A value that may be nil was used where nil isn't allowed. \
+Check it against nil first, or give it a type without '?'.",
+        "E0012" => "A function was called with too many or too few arguments.",
+        "E0013" => "A coroutine was resumed after it had finished, or something \
+that isn't a coroutine was resumed.",
+        "E0014" => "A match doesn't have an arm for every type the value can \
+have. Add the missing arms, or an else arm.",
+        "E0015" => "A list or tuple was indexed outside of its length. Indices \
+start at 0 and have to be less than the length.",
+        "E0016" => "A dict was indexed with a key that isn't in it. Use 'has' to \
+check for the key first.",
+        "E0017" => "The two sides of a '<=>' weren't equal.",
+        "E0018" => "The VM was given bytecode it can't run, like a jump out of \
+the function, a read of a variable that doesn't exist, or a call to a function \
+before the variables it captures are declared. Programs compiled from source \
+should never cause this, so if one does it's a bug in the compiler, please \
+report it. Programs built by hand have to follow the same rules as the compiler.",
+        "E0019" => "Code marked with '<!>' as unreachable was reached.",
+        "E0020" => "The code couldn't be parsed. The message says what was \
+expected instead.",
+        "E0021" => "The file contains git conflict markers. Resolve the conflict \
+before running the file.",
+        "E0022" => "A file given to 'use', or on the command line, doesn't exist.",
+        "E0023" => "Files use each other in a cycle. Move the shared code to a \
+file that neither of them use.",
+        "E0024" => "No file was given to run.",
+        "E0025" => "A function was called on the VM from Rust while the VM was \
+running, or waiting to be resumed after a yield. Run or resume it until it's \
+done before calling into it.",
+        "E0026" => "Lists, dicts and blob instances can't be the keys of a dict, \
+since they can change after they're put in it. Use a tuple, or a value that \
+identifies them, as the key instead.",
+        "E0027" => "The VM was run or resumed when it had nothing left to run. \
+Either the program has already returned, or the VM was never given a program \
+with a block to start in.",
+        _ => return None,
+    })
 }
 
 impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ErrorKind::TypeError(op, types) => {
-                let types = types
-                    .iter()
-                    .fold(String::new(), |a, v| { format!("{}{:?}, ", a, v) });
-                write!(f, "Cannot apply {:?} to types {}", op, types)
+                write!(f, "Cannot apply {:?} to types {}", op, quoted(types.iter()))
             }
             ErrorKind::TypeMismatch(a, b) => {
-                write!(f, "Expected '{:?}' and got '{:?}'", a, b)
+                write!(f, "Expected '{}' and got '{}'", a, b)
             }
             ErrorKind::CannotInfer(a, b) => {
-                write!(f, "Failed to infer type '{:?}' from '{:?}'", a, b)
+                write!(f, "Failed to infer type '{}' from '{}'", a, b)
             }
             ErrorKind::ArgumentType(a, b) => {
-                let expected = a
-                    .iter()
-                    .fold(String::new(), |a, v| { format!("{}{:?}, ", a, v) });
-                let given = b
-                    .iter()
-                    .fold(String::new(), |a, v| { format!("{}{:?}, ", a, v) });
-                write!(f, "Argument types do not match, expected [{:?}] but got [{:?}]",
-                       expected, given)
+                write!(f, "Argument types do not match, expected ({}) but got ({})",
+                       comma_separated(a.iter()), comma_separated(b.iter()))
             }
             ErrorKind::IndexError(value, slot) => {
-                write!(f, "Cannot index value '{:?}' with type '{:?}'", value, slot)
+                write!(f, "Cannot index value '{}' with type '{}'", value, slot)
             }
             ErrorKind::ExternTypeMismatch(name, types) => {
-                write!(f, "Extern function '{}' doesn't accept argument(s) with type(s) {:?}",
-                       name, types)
+                write!(f, "Extern function '{}' doesn't accept argument(s) with type(s) {}",
+                       name, quoted(types.iter()))
             }
             ErrorKind::ValueError(op, values) => {
-                let values = values
-                    .iter()
-                    .fold(String::new(), |a, v| { format!("{}{:?}, ", a, v) });
-                write!(f, "Cannot apply {:?} to values {}", op, values)
+                write!(f, "Cannot apply {:?} to values {}", op, comma_separated(values.iter()))
             }
             ErrorKind::UnknownField(obj, field) => {
-                write!(f, "Cannot find field '{}' on {:?}", field, obj)
+                write!(f, "Cannot find field '{}' on '{}'", field, obj)
+            }
+            ErrorKind::UninitializedField(blob, field) => {
+                write!(f, "Field '{}' on '{}' is never given a value", field, blob)
+            }
+            ErrorKind::MaybeNil(ty) => {
+                write!(f, "The value may be nil, since its type is '{}'", ty)
+            }
+            ErrorKind::UnknownFunction(name) => {
+                write!(f, "Cannot find a global function named '{}'", name)
             }
             ErrorKind::ArgumentCount(expected, given) => {
                 write!(f, "Incorrect argument count, expected {} but got {}",
                        expected, given)
             }
+            ErrorKind::CannotResume => {
+                write!(f, "Cannot resume coroutine")
+            }
+            ErrorKind::NonExhaustiveMatch(types) => {
+                write!(f, "Match doesn't cover the type(s) {}", quoted(types.iter()))
+            }
+            ErrorKind::MutableKey(ty) => {
+                write!(f, "'{}' can't be the key of a dict, since it can change", ty)
+            }
             ErrorKind::IndexOutOfBounds(value, len, slot) => {
-                write!(f, "Failed to index for {:?} - length is {} but index is {}",
+                write!(f, "Failed to index for {} - length is {} but index is {}",
                        value, len, slot)
             }
+            ErrorKind::UnknownKey(dict, key) => {
+                write!(f, "Cannot find key {} in {}", key, dict)
+            }
             ErrorKind::AssertFailed => {
                 write!(f, "Assertion failed")
             }
             ErrorKind::InvalidProgram => {
-                write!(f, "{}", "[!!] Invalid program [!!]".bold())
+                write!(f, "[!!] Invalid program [!!]")
             }
             ErrorKind::Unreachable => {
                 write!(f, "Reached unreachable code")
@@ -115,41 +328,195 @@ impl fmt::Display for ErrorKind {
             ErrorKind::FileNotFound(path) => {
                 write!(f, "File '{}' not found", path.display())
             }
+            ErrorKind::CyclicImport(files) => {
+                let files = files
+                    .iter()
+                    .map(|file| file.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                write!(f, "Cyclic import {}", files)
+            }
             ErrorKind::NoFileGiven => {
                 write!(f, "No file to run")
             }
+            ErrorKind::VMBusy => {
+                write!(f, "The VM is already running")
+            }
+            ErrorKind::NothingToRun => {
+                write!(f, "The VM has nothing to run")
+            }
         }
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(false))
+    }
+}
+
+impl Error {
+    pub fn new_nowhere(kind: ErrorKind, message: Option<String>) -> Self {
+        Self {
+            kind,
+            message,
+            file: PathBuf::from("!compiler!"),
+            span: Span::default(),
+            notes: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// The error as it's shown to people, with the lines of source
+    /// around it. Displaying the error is the same as rendering it
+    /// without colours.
+    pub fn render(&self, colours: bool) -> String {
         let prompt = "     ";
-        let message = match &self.message {
-            Some(s) => format!("\n{} {}", prompt, s),
-            None => String::from(""),
-        };
+        let message = self.message.iter()
+            .chain(self.notes.iter())
+            .map(|s| format!("\n{} {}", prompt, s))
+            .collect::<String>();
 
-        let line = if let Ok(file) = File::open(&self.file) {
+        let line = self.span.line;
+        let lines = if let Ok(file) = File::open(&self.file) {
             io::BufReader::new(file).lines().enumerate()
-                    .filter(|(n, _)| self.line <= *n + 3 && *n + 3 <= self.line + 2)
-                    .fold(String::from("\n"), |a, (n, l)| format!("{} {:3} | {}\n", a, (n + 1).blue(), l.unwrap()))
+                    .filter(|(n, _)| line <= *n + 3 && *n + 3 <= line + 2)
+                    .fold(String::from("\n"), |a, (n, l)| {
+                        let l = l.unwrap();
+                        let caret = if n + 1 == line { self.caret(&l, colours) } else { String::new() };
+                        format!("{} {:>3} | {}\n{}", a, paint(colours, n + 1, |s| s.blue().to_string()), l, caret)
+                    })
         } else {
             String::new()
         };
 
-        write!(f, "{} {}:{}\n{} {}{}{}", "ERROR".red(),
-               self.file.display().blue(), self.line.blue(), prompt, self.kind, message, line)
+        let position = if self.span.col_start == 0 {
+            format!("{}", line)
+        } else {
+            format!("{}:{}", line, self.span.col_start)
+        };
+        let stack = if self.stack.is_empty() {
+            String::new()
+        } else {
+            self.stack.iter()
+                .enumerate()
+                .fold(format!("{} Stack, most recent call last:\n", prompt), |a, (i, frame)| {
+                    format!("{}{} {:>3}. {}\n", a, prompt, i, frame)
+                })
+        };
+        format!("{} {}:{}\n{} {}{}{}{}",
+                paint(colours, format!("ERROR[{}]", self.kind.code()), |s| s.red().to_string()),
+                paint(colours, self.file.display(), |s| s.blue().to_string()),
+                paint(colours, position, |s| s.blue().to_string()),
+                prompt, self.kind, message, lines, stack)
+    }
+
+    /// The error as a line of JSON, for tools that read errors.
+    /// The message is null when the error only has its kind.
+    pub fn to_json(&self) -> String {
+        let message = self.message.as_deref().map(json_string).unwrap_or_else(|| String::from("null"));
+        let notes = self.notes.iter()
+            .map(|note| json_string(note))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let stack = self.stack.iter()
+            .map(|frame| format!("{{\"function\": {}, \"file\": {}, \"line\": {}}}",
+                                 json_string(&frame.function),
+                                 json_string(&frame.file.display().to_string()),
+                                 frame.line))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(concat!("{{\"file\": {}, \"line\": {}, ",
+                        "\"span\": {{\"line\": {}, \"col_start\": {}, \"col_end\": {}}}, ",
+                        "\"kind\": {}, \"code\": {}, \"description\": {}, \"message\": {}, ",
+                        "\"notes\": [{}], \"stack\": [{}]}}"),
+                json_string(&self.file.display().to_string()),
+                self.span.line,
+                self.span.line,
+                self.span.col_start,
+                self.span.col_end,
+                json_string(self.kind.name()),
+                json_string(self.kind.code()),
+                json_string(&self.kind.to_string()),
+                message,
+                notes,
+                stack)
+    }
+
+    /// Underlines the span in the given source line. Tabs are kept,
+    /// so the carets line up with the source.
+    fn caret(&self, source: &str, colours: bool) -> String {
+        if self.span.col_start == 0 {
+            return String::new();
+        }
+        let indent: String = source.chars()
+            .take(self.span.col_start - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let available = source.chars().count().saturating_sub(self.span.col_start - 1);
+        let len = self.span.col_end.saturating_sub(self.span.col_start);
+        let len = std::cmp::max(std::cmp::min(len, available), 1);
+        format!("     | {}{}\n", indent, paint(colours, "^".repeat(len), |s| s.red().to_string()))
     }
 }
 
-impl Error {
-    pub fn new_nowhere(kind: ErrorKind, message: Option<String>) -> Self {
-        Self {
-            kind,
-            message,
-            file: PathBuf::from("!compiler!"),
-            line: 0,
+/// The candidate closest to `name`, if one is close enough to be a typo.
+pub(crate) fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let length = name.chars().count();
+    let allowed = std::cmp::max(length / 3, 1);
+    candidates.into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= allowed && *distance < length)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// A note suggesting the candidate closest to `name`.
+pub(crate) fn did_you_mean<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    closest(name, candidates).map(|candidate| format!("Did you mean '{}'?", candidate))
+}
+
+/// The number of characters that have to be inserted, removed, changed
+/// or swapped with their neighbour to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances[i][j] is the distance between a[..i] and b[..j].
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..=a.len() {
+        for j in 0..=b.len() {
+            distances[i][j] = if i == 0 || j == 0 {
+                i + j
+            } else {
+                let change = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+                let mut distance = (distances[i - 1][j] + 1)
+                    .min(distances[i][j - 1] + 1)
+                    .min(distances[i - 1][j - 1] + change);
+                if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                    distance = distance.min(distances[i - 2][j - 2] + 1);
+                }
+                distance
+            };
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// Quotes the text as a JSON string.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
         }
     }
+    quoted.push('"');
+    quoted
 }
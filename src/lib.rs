@@ -12,48 +12,99 @@ use owo_colors::OwoColorize;
 use error::Error;
 
 use crate::error::ErrorKind;
+use crate::tokenizer::Span;
 
+pub mod ast;
 pub mod error;
 pub mod vm;
 
 mod compiler;
 mod sectionizer;
 mod tokenizer;
+mod typechecker;
 
 /// Compiles, links and runs the given file. Supplied functions are callable
 /// external functions. If you want your program to be able to yield, use
 /// [compile_file].
 pub fn run_file(args: Args, functions: Vec<(String, RustFunction)>) -> Result<(), Vec<Error>> {
-    run(args, functions)
-}
-
-fn run(args: Args, functions: Vec<(String, RustFunction)>) -> Result<(), Vec<Error>> {
     let path = match args.file {
         Some(file) => file,
         None => {
             return Err(vec![Error {
                 kind: ErrorKind::NoFileGiven,
                 file: PathBuf::from(""),
-                line: 0,
+                span: Span::default(),
                 message: None,
+                notes: Vec::new(),
+                stack: Vec::new(),
             }]);
         }
     };
     let sections = sectionizer::sectionize(&path)?;
-    match compiler::Compiler::new(sections).compile("/preamble", &path, &functions) {
-        Ok(prog) => {
-            let mut vm = vm::VM::new();
-            vm.print_bytecode = args.print_bytecode;
-            vm.print_exec = args.print_exec;
-            vm.typecheck(&prog)?;
-            vm.init(&prog);
-            if let Err(e) = vm.run() {
-                Err(vec![e])
-            } else {
-                Ok(())
-            }
+    let prog = compile(sections, &path, args.print_bytecode, args.strict, &functions)?;
+    run(&prog, args.print_bytecode, args.print_exec, args.strict)
+}
+
+/// Compiles, links and runs the given source. Errors are reported as if
+/// the source came from a file called `/string`. If you want your program
+/// to be able to yield, use [compile_string].
+pub fn run_string(source: &str, print: bool, functions: Vec<(String, RustFunction)>) -> Result<(), Vec<Error>> {
+    let prog = compile_string(source, Path::new("/string"), print, false, functions)?;
+    run(&prog, print, false, false)
+}
+
+/// Compiles, links and typechecks the given file. The returned [Prog]
+/// can be run with a [vm::VM]. Variables are only checked by a strict VM
+/// if `strict` is set, since the checks cost time even when they're skipped.
+pub fn compile_file(path: &Path, print: bool, strict: bool, functions: Vec<(String, RustFunction)>) -> Result<Prog, Vec<Error>> {
+    let sections = sectionizer::sectionize(path)?;
+    compile(sections, path, print, strict, &functions)
+}
+
+/// Compiles, links and typechecks source held in memory. The path is
+/// never read, it's used for error reporting and for finding files
+/// pulled in with `use`. `strict` is the same as for [compile_file].
+pub fn compile_string(source: &str, path: &Path, print: bool, strict: bool, functions: Vec<(String, RustFunction)>) -> Result<Prog, Vec<Error>> {
+    let sections = sectionizer::sectionize_string(path, source)?;
+    compile(sections, path, print, strict, &functions)
+}
+
+fn compile(
+    sections: Vec<sectionizer::Section>,
+    path: &Path,
+    print_bytecode: bool,
+    strict: bool,
+    functions: &[(String, RustFunction)],
+) -> Result<Prog, Vec<Error>> {
+    let modules = ast::parse_sections(&sections)?;
+    // The compiler needs the inferred types for strict mode, but
+    // its errors are reported before the errors of the typechecker.
+    let (inferred, type_errors) = match typechecker::check(&modules, functions) {
+        Ok(inferred) => (inferred, Vec::new()),
+        Err(errors) => (typechecker::Inferred::new(), errors),
+    };
+    let prog = compiler::Compiler::new().compile("/preamble", path, &modules, functions, inferred, strict)?;
+    if print_bytecode {
+        for block in prog.blocks.iter() {
+            RefCell::borrow(block).debug_print();
         }
-        Err(errors) => Err(errors),
+    }
+    if !type_errors.is_empty() {
+        return Err(type_errors);
+    }
+    Ok(prog)
+}
+
+fn run(prog: &Prog, print_bytecode: bool, print_exec: bool, strict: bool) -> Result<(), Vec<Error>> {
+    let mut vm = vm::VM::new();
+    vm.print_bytecode = print_bytecode;
+    vm.print_exec = print_exec;
+    vm.strict = strict;
+    vm.init(prog);
+    if let Err(e) = vm.run() {
+        Err(vec![e])
+    } else {
+        Ok(())
     }
 }
 
@@ -61,6 +112,8 @@ pub struct Args {
     pub file: Option<PathBuf>,
     pub print_exec: bool,
     pub print_bytecode: bool,
+    /// Checks types while running, see [vm::VM::strict].
+    pub strict: bool,
 }
 
 impl Default for Args {
@@ -69,6 +122,7 @@ impl Default for Args {
             file: None,
             print_exec: false,
             print_bytecode: false,
+            strict: false,
         }
     }
 }
@@ -88,9 +142,16 @@ pub enum Type {
     Tuple(Vec<Type>),
     Union(HashSet<Type>),
     List(Box<Type>),
+    /// (Key, Value)
+    Dict(Box<Type>, Box<Type>),
+    Range,
     Function(Vec<Type>, Box<Type>),
     Blob(Rc<Blob>),
     Instance(Rc<Blob>),
+    Enum(Rc<Enum>),
+    Coroutine,
+    /// A type parameter of a function, like the `T` in `fn<T> a: T -> T`.
+    Generic(String),
 }
 
 impl Hash for Type {
@@ -112,6 +173,11 @@ impl Hash for Type {
                 t.as_ref().hash(h);
                 12
             }
+            Type::Dict(k, v) => {
+                k.as_ref().hash(h);
+                v.as_ref().hash(h);
+                14
+            }
             Type::Union(ts) => {
                 for t in ts {
                     t.hash(h);
@@ -126,17 +192,23 @@ impl Hash for Type {
                 8
             }
             Type::Blob(b) => {
-                for (_, t) in b.fields.values() {
-                    t.hash(h);
-                }
+                b.id.hash(h);
                 10
             }
             Type::Instance(b) => {
-                for (_, t) in b.fields.values() {
-                    t.hash(h);
-                }
+                b.id.hash(h);
                 11
             }
+            Type::Coroutine => 13,
+            Type::Range => 15,
+            Type::Enum(e) => {
+                e.id.hash(h);
+                16
+            }
+            Type::Generic(name) => {
+                name.hash(h);
+                17
+            }
         }.hash(h);
     }
 }
@@ -147,10 +219,14 @@ impl PartialEq for Type {
             (Type::Void, Type::Void) => true,
             (Type::Instance(a), Type::Instance(b)) => *a == *b,
             (Type::Blob(a), Type::Blob(b)) => *a == *b,
+            (Type::Enum(a), Type::Enum(b)) => *a == *b,
             (Type::Int, Type::Int) => true,
             (Type::Float, Type::Float) => true,
             (Type::Bool, Type::Bool) => true,
             (Type::String, Type::String) => true,
+            (Type::Coroutine, Type::Coroutine) => true,
+            (Type::Range, Type::Range) => true,
+            (Type::Generic(a), Type::Generic(b)) => a == b,
             (Type::Tuple(a), Type::Tuple(b)) => {
                 a.iter().zip(b.iter()).all(|(a, b)| a == b)
             }
@@ -158,6 +234,7 @@ impl PartialEq for Type {
                 a.iter().any(|x| x == b)
             }
             (Type::List(a), Type::List(b)) => a == b,
+            (Type::Dict(ak, av), Type::Dict(bk, bv)) => ak == bk && av == bv,
             (Type::Function(a_args, a_ret), Type::Function(b_args, b_ret)) =>
                 a_args == b_args && a_ret == b_ret,
             _ => false,
@@ -167,6 +244,51 @@ impl PartialEq for Type {
 
 impl Eq for Type {}
 
+/// Writes the items separated by commas.
+fn comma_separated<T: std::fmt::Display>(items: impl Iterator<Item = T>) -> String {
+    items.map(|item| item.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Types are written the way they're written in code.
+impl std::fmt::Display for Type {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Void => write!(fmt, "void"),
+            Type::Unknown => write!(fmt, "unknown"),
+            Type::Int => write!(fmt, "int"),
+            Type::Float => write!(fmt, "float"),
+            Type::Bool => write!(fmt, "bool"),
+            Type::String => write!(fmt, "str"),
+            Type::Tuple(tys) => write!(fmt, "({})", comma_separated(tys.iter())),
+            Type::Union(tys) => {
+                // Sorted, since the order of a set changes between runs.
+                let mut members: Vec<_> = tys.iter()
+                    .filter(|ty| **ty != Type::Void)
+                    .map(|ty| ty.to_string())
+                    .collect();
+                members.sort();
+                if members.is_empty() {
+                    write!(fmt, "void")
+                } else if tys.contains(&Type::Void) {
+                    write!(fmt, "{}?", members.join(" | "))
+                } else {
+                    write!(fmt, "{}", members.join(" | "))
+                }
+            }
+            Type::List(ty) => write!(fmt, "[{}]", ty),
+            Type::Dict(key, value) => write!(fmt, "[{}: {}]", key, value),
+            Type::Range => write!(fmt, "range"),
+            Type::Function(params, ret) if params.is_empty() => write!(fmt, "fn -> {}", ret),
+            Type::Function(params, ret) => write!(fmt, "fn {} -> {}", comma_separated(params.iter()), ret),
+            Type::Blob(blob) => write!(fmt, "blob {}", blob.name),
+            Type::Instance(blob) => write!(fmt, "{}", blob.name),
+            Type::Enum(e) => write!(fmt, "{}", e.name),
+            Type::Coroutine => write!(fmt, "coroutine"),
+            Type::Generic(name) => write!(fmt, "{}", name),
+        }
+    }
+}
+
 impl From<&Value> for Type {
     fn from(value: &Value) -> Type {
         match value {
@@ -179,12 +301,16 @@ impl From<&Value> for Type {
                 let v: &RefCell<_> = v.borrow();
                 let v: &Vec<_> = &v.borrow();
                 let set: HashSet<_> = v.iter().map(|x| Type::from(x)).collect();
-                let t = match set.len() {
-                    0 => Type::Unknown,
-                    1 => set.into_iter().next().unwrap(),
-                    _ => Type::Union(set),
-                };
-                Type::List(Box::new(t))
+                Type::List(Box::new(Type::union_of(set)))
+            }
+            Value::Dict(v) => {
+                let v: &RefCell<_> = v.borrow();
+                // The keys can't change, see ErrorKind::MutableKey.
+                #[allow(clippy::mutable_key_type)]
+                let v: &HashMap<_, _> = &v.borrow();
+                let keys: HashSet<_> = v.keys().map(|x| Type::from(x)).collect();
+                let values: HashSet<_> = v.values().map(|x| Type::from(x)).collect();
+                Type::Dict(Box::new(Type::union_of(keys)), Box::new(Type::union_of(values)))
             }
             Value::Union(v) => {
                 Type::Union(v.iter().map(|x| Type::from(x)).collect())
@@ -198,6 +324,15 @@ impl From<&Value> for Type {
                 let block = &block.borrow();
                 block.borrow().ty.clone()
             }
+            Value::BoundMethod(_, method) => {
+                match Type::from(method.as_ref()) {
+                    Type::Function(args, ret) => Type::Function(args[1..].to_vec(), ret),
+                    ty => ty,
+                }
+            }
+            Value::Coroutine(_) => Type::Coroutine,
+            Value::Range(_, _) => Type::Range,
+            Value::Variant(e, _, _) => Type::Enum(Rc::clone(e)),
             Value::Unknown => Type::Unknown,
             _ => Type::Void,
         }
@@ -228,7 +363,16 @@ impl From<&Type> for Value {
             Type::List(fields) => {
                 Value::List(Rc::new(RefCell::new(vec![Value::from(fields.as_ref())])))
             }
-            Type::Unknown => Value::Unknown,
+            Type::Dict(k, v) => {
+                // Dict types with keys that can change are rejected
+                // by the typechecker, see ErrorKind::MutableKey.
+                #[allow(clippy::mutable_key_type)]
+                let mut dict = HashMap::new();
+                dict.insert(Value::from(k.as_ref()), Value::from(v.as_ref()));
+                Value::Dict(Rc::new(RefCell::new(dict)))
+            }
+            // Nothing is known about a type parameter inside the function.
+            Type::Unknown | Type::Generic(_) => Value::Unknown,
             Type::Int => Value::Int(1),
             Type::Float => Value::Float(1.0),
             Type::Bool => Value::Bool(true),
@@ -236,6 +380,9 @@ impl From<&Type> for Value {
             Type::Function(_, _) => Value::Function(
                 Rc::new(Vec::new()),
                 Rc::new(RefCell::new(Block::stubbed_block(ty)))),
+            Type::Coroutine => Value::Coroutine(Rc::new(RefCell::new(vm::Coroutine::default()))),
+            Type::Range => Value::Range(0, 1),
+            Type::Enum(e) => Value::Variant(Rc::clone(e), 0, Box::new(Value::Nil)),
         }
     }
 }
@@ -251,12 +398,23 @@ impl Type {
     /// comparison for types useful when checking assignment.
     pub fn fits(&self, other: &Self) -> bool {
         match (self, other) {
-            (_, Type::Unknown) => {
+            (_, Type::Unknown) | (Type::Unknown, _) => {
                 true
             }
             (Type::List(a), Type::List(b)) => {
                 a.fits(b)
             },
+            (Type::Dict(ak, av), Type::Dict(bk, bv)) => {
+                ak.fits(bk) && av.fits(bv)
+            },
+            (Type::Tuple(a), Type::Tuple(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.fits(b))
+            },
+            (Type::Function(a_args, a_ret), Type::Function(b_args, b_ret)) => {
+                a_args.len() == b_args.len()
+                    && a_args.iter().zip(b_args.iter()).all(|(a, b)| a.fits(b))
+                    && a_ret.fits(b_ret)
+            },
             (Type::Union(a), Type::Union(b)) => {
                 a.iter().all(|x| b.contains(x))
             },
@@ -268,6 +426,99 @@ impl Type {
             },
         }
     }
+
+    /// Binds the type parameters in the self type so the other type
+    /// matches it, like when passing an argument to a function. The
+    /// first type a parameter is bound to sticks, unless it's unknown.
+    pub fn unify(&self, other: &Self, bindings: &mut HashMap<String, Type>) -> bool {
+        match (self, other) {
+            (Type::Generic(name), other) => {
+                match bindings.get(name) {
+                    None | Some(Type::Unknown) => {
+                        bindings.insert(name.clone(), other.clone());
+                        true
+                    }
+                    Some(bound) => {
+                        matches!(other, Type::Unknown) || bound == other
+                    }
+                }
+            }
+            (_, Type::Unknown) => {
+                true
+            }
+            (Type::List(a), Type::List(b)) => {
+                a.unify(b, bindings)
+            }
+            (Type::Dict(ak, av), Type::Dict(bk, bv)) => {
+                ak.unify(bk, bindings) && av.unify(bv, bindings)
+            }
+            (Type::Tuple(a), Type::Tuple(b)) => {
+                a.len() == b.len()
+                    && a.iter().zip(b.iter()).all(|(a, b)| a.unify(b, bindings))
+            }
+            (Type::Function(a_args, a_ret), Type::Function(b_args, b_ret)) => {
+                a_args.len() == b_args.len()
+                    && a_args.iter().zip(b_args.iter()).all(|(a, b)| a.unify(b, bindings))
+                    && a_ret.unify(b_ret, bindings)
+            }
+            (a, b) => {
+                a == b
+            }
+        }
+    }
+
+    /// Replaces the type parameters with what they're bound to,
+    /// parameters that aren't bound become [Type::Unknown].
+    pub fn substitute(&self, bindings: &HashMap<String, Type>) -> Type {
+        match self {
+            Type::Generic(name) => {
+                bindings.get(name).cloned().unwrap_or(Type::Unknown)
+            }
+            Type::List(ty) => Type::List(Box::new(ty.substitute(bindings))),
+            Type::Dict(k, v) => {
+                Type::Dict(Box::new(k.substitute(bindings)), Box::new(v.substitute(bindings)))
+            }
+            Type::Tuple(tys) => {
+                Type::Tuple(tys.iter().map(|ty| ty.substitute(bindings)).collect())
+            }
+            Type::Union(tys) => {
+                Type::Union(tys.iter().map(|ty| ty.substitute(bindings)).collect())
+            }
+            Type::Function(args, ret) => {
+                Type::Function(args.iter().map(|ty| ty.substitute(bindings)).collect(),
+                               Box::new(ret.substitute(bindings)))
+            }
+            ty => ty.clone(),
+        }
+    }
+
+    /// Collapses a set of types into a single type, where
+    /// an empty set is [Type::Unknown].
+    pub fn union_of(set: HashSet<Type>) -> Type {
+        match set.len() {
+            0 => Type::Unknown,
+            1 => set.into_iter().next().unwrap(),
+            _ => Type::Union(set),
+        }
+    }
+
+    /// The types a value of this type can have, the members
+    /// of a union or otherwise just the type itself.
+    pub fn members(&self) -> Vec<Type> {
+        match self {
+            Type::Union(tys) => tys.iter().cloned().collect(),
+            ty => vec![ty.clone()],
+        }
+    }
+
+    /// Checks if nil is a valid value for the type.
+    pub fn is_nullable(&self) -> bool {
+        match self {
+            Type::Void => true,
+            Type::Union(tys) => tys.contains(&Type::Void),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -277,6 +528,7 @@ pub enum Value {
     Instance(Rc<Blob>, Rc<RefCell<Vec<Value>>>),
     Tuple(Rc<Vec<Value>>),
     List(Rc<RefCell<Vec<Value>>>),
+    Dict(Rc<RefCell<HashMap<Value, Value>>>),
     Union(HashSet<Value>),
     Float(f64),
     Int(i64),
@@ -284,6 +536,16 @@ pub enum Value {
     String(Rc<String>),
     Function(Rc<Vec<Rc<RefCell<UpValue>>>>, Rc<RefCell<Block>>),
     ExternFunction(usize),
+    /// A method together with the instance it was looked up on,
+    /// which is passed as the first argument when it's called.
+    BoundMethod(Box<Value>, Box<Value>),
+    Coroutine(Rc<RefCell<vm::Coroutine>>),
+    /// The integers from the first number up to,
+    /// but not including, the second.
+    Range(i64, i64),
+    /// A variant of an enum, by its index, and its payload.
+    /// The payload is nil for variants without one.
+    Variant(Rc<Enum>, usize, Box<Value>),
     /// This value should not be present when running, only when type checking.
     /// Most operations are valid but produce funky results.
     Unknown,
@@ -302,6 +564,7 @@ impl Debug for Value {
             Value::Bool(b) => write!(fmt, "(bool {})", b),
             Value::String(s) => write!(fmt, "(string \"{}\")", s),
             Value::List(v) => write!(fmt, "(array {:?})", v),
+            Value::Dict(v) => write!(fmt, "(dict {:?})", v),
             Value::Function(_, block) => {
                 let block: &RefCell<_> = block.borrow();
                 let block = &block.borrow();
@@ -311,6 +574,19 @@ impl Debug for Value {
                 )
             }
             Value::ExternFunction(slot) => write!(fmt, "(extern fn {})", slot),
+            Value::BoundMethod(_, method) => write!(fmt, "(bound {:?})", method),
+            Value::Coroutine(co) => {
+                let co: &RefCell<_> = co.borrow();
+                if co.borrow().is_done() {
+                    write!(fmt, "(coroutine done)")
+                } else {
+                    write!(fmt, "(coroutine)")
+                }
+            }
+            Value::Range(lo, hi) => write!(fmt, "(range {}..{})", lo, hi),
+            Value::Variant(e, variant, payload) => {
+                write!(fmt, "({}.{} {:?})", e.name, e.variants[*variant].0, payload)
+            }
             Value::Unknown => write!(fmt, "(unknown)"),
             Value::Nil => write!(fmt, "(nil)"),
             Value::Tuple(v) => write!(fmt, "({:?})", v),
@@ -319,14 +595,78 @@ impl Debug for Value {
     }
 }
 
+/// Values are written the way they're written in code, where it's possible.
+impl std::fmt::Display for Value {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Ty(ty) => write!(fmt, "{}", ty),
+            Value::Blob(b) => write!(fmt, "{}", b.name),
+            // Instances made from types have no values.
+            Value::Instance(b, values) if RefCell::borrow(values).is_empty() => write!(fmt, "{}", b.name),
+            Value::Instance(b, values) => {
+                let fields = b.fields.borrow();
+                let mut fields: Vec<_> = fields.iter().collect();
+                fields.sort_by_key(|(_, (slot, _))| *slot);
+                let values = RefCell::borrow(values);
+                let fields = fields.iter()
+                    .map(|(name, (slot, _))| format!("{}: {}", name, values.get(*slot).unwrap_or(&Value::Nil)));
+                write!(fmt, "{} {{ {} }}", b.name, comma_separated(fields))
+            }
+            Value::Float(f) => write!(fmt, "{:?}", f),
+            Value::Int(i) => write!(fmt, "{}", i),
+            Value::Bool(b) => write!(fmt, "{}", b),
+            Value::String(s) => write!(fmt, "\"{}\"", s),
+            Value::List(v) => write!(fmt, "[{}]", comma_separated(RefCell::borrow(v).iter())),
+            Value::Dict(v) => {
+                // Sorted, since the order of a map changes between runs.
+                let mut entries: Vec<_> = RefCell::borrow(v).iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect();
+                entries.sort();
+                write!(fmt, "{{{}}}", entries.join(", "))
+            }
+            Value::Function(_, block) => {
+                let block: &RefCell<_> = block.borrow();
+                write!(fmt, "fn {}", block.borrow().name)
+            }
+            Value::ExternFunction(_) => write!(fmt, "extern fn"),
+            Value::BoundMethod(_, method) => write!(fmt, "{}", method),
+            Value::Coroutine(_) => write!(fmt, "coroutine"),
+            Value::Range(lo, hi) => write!(fmt, "range({}, {})", lo, hi),
+            Value::Variant(e, variant, payload) => {
+                if e.variants[*variant].1.is_some() {
+                    write!(fmt, "{}.{}({})", e.name, e.variants[*variant].0, payload)
+                } else {
+                    write!(fmt, "{}.{}", e.name, e.variants[*variant].0)
+                }
+            }
+            Value::Unknown => write!(fmt, "unknown"),
+            Value::Nil => write!(fmt, "nil"),
+            Value::Tuple(v) => write!(fmt, "({})", comma_separated(v.iter())),
+            Value::Union(v) => {
+                let mut members: Vec<_> = v.iter().map(|v| v.to_string()).collect();
+                members.sort();
+                write!(fmt, "{}", members.join(" | "))
+            }
+        }
+    }
+}
+
 impl PartialEq<Value> for Value {
     fn eq(&self, other: &Value) -> bool {
         match (self, other) {
-            (Value::Float(a), Value::Float(b)) => a == b,
+            // NaN equals itself here, so it can be the key of a dict.
+            // Comparing in the language still follows IEEE, see op::eq.
+            (Value::Float(a), Value::Float(b)) => a == b || (a.is_nan() && b.is_nan()),
             (Value::Int(a), Value::Int(b)) => a == b,
             (Value::Bool(a), Value::Bool(b)) => a == b,
             (Value::String(a), Value::String(b)) => a == b,
             (Value::List(a), Value::List(b)) => a == b,
+            (Value::Dict(a), Value::Dict(b)) => a == b,
+            (Value::Range(a_lo, a_hi), Value::Range(b_lo, b_hi)) => a_lo == b_lo && a_hi == b_hi,
+            (Value::Variant(a, a_variant, a_payload), Value::Variant(b, b_variant, b_payload)) => {
+                a == b && a_variant == b_variant && a_payload == b_payload
+            }
             (Value::Tuple(a), Value::Tuple(b)) => {
                 a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a == b)
             }
@@ -345,15 +685,29 @@ impl Hash for Value {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
             Value::Float(a) => {
-                // We have to limit the values, because
-                // floats are wierd.
-                assert!(a.is_finite());
+                // Equal floats have to hash the same, like 0.0 and -0.0,
+                // and NaNs with different bits.
+                let a = if *a == 0.0 {
+                    0.0
+                } else if a.is_nan() {
+                    f64::NAN
+                } else {
+                    *a
+                };
                 a.to_bits().hash(state);
             },
             Value::Int(a) => a.hash(state),
             Value::Bool(a) => a.hash(state),
             Value::String(a) => a.hash(state),
             Value::Tuple(a) => a.hash(state),
+            Value::Range(lo, hi) => {
+                lo.hash(state);
+                hi.hash(state);
+            },
+            Value::Variant(_, variant, payload) => {
+                variant.hash(state);
+                payload.hash(state);
+            },
             Value::Nil => state.write_i8(0),
             _ => {},
         };
@@ -361,19 +715,6 @@ impl Hash for Value {
 }
 
 impl Value {
-    fn identity(self) -> Self {
-        match self {
-            Value::Float(_) => Value::Float(1.0),
-            Value::Int(_) => Value::Int(1),
-            Value::Bool(_) => Value::Bool(true),
-            x if matches!(x, Value::List(_)) => {
-                let x = Type::from(x);
-                Value::from(&x)
-            }
-            a => a,
-        }
-    }
-
     fn is_nil(&self) -> bool {
         match self {
             Value::Nil => true,
@@ -397,20 +738,23 @@ impl UpValue {
         }
     }
 
-    fn get(&self, stack: &[Value]) -> Value {
+    /// `None` if the value is open but outside the stack.
+    fn get(&self, stack: &[Value]) -> Option<Value> {
         if self.is_closed() {
-            self.value.clone()
+            Some(self.value.clone())
         } else {
-            stack[self.slot].clone()
+            stack.get(self.slot).cloned()
         }
     }
 
-    fn set(&mut self, stack: &mut [Value], value: Value) {
+    /// `None` if the value is open but outside the stack.
+    fn set(&mut self, stack: &mut [Value], value: Value) -> Option<()> {
         if self.is_closed() {
             self.value = value;
         } else {
-            stack[self.slot] = value;
+            *stack.get_mut(self.slot)? = value;
         }
+        Some(())
     }
 
     fn is_closed(&self) -> bool {
@@ -421,14 +765,34 @@ impl UpValue {
         self.slot = 0;
         self.value = value;
     }
+
+    /// Opens a closed upvalue at the given slot, returning
+    /// the value that should be placed there.
+    fn reopen(&mut self, slot: usize) -> Value {
+        self.slot = slot;
+        std::mem::replace(&mut self.value, Value::Nil)
+    }
 }
 
-#[derive(Debug, Clone)]
+/// A blob is declared before its body is compiled, so its fields
+/// can refer to the blob itself or to blobs declared later.
+#[derive(Clone)]
 pub struct Blob {
     pub id: usize,
     pub name: String,
     /// Maps field names to their slot and type.
-    pub fields: HashMap<String, (usize, Type)>,
+    pub fields: RefCell<HashMap<String, (usize, Type)>>,
+    /// Maps method names to the slot of their function in the constants.
+    pub methods: RefCell<HashMap<String, usize>>,
+    /// Values given to fields when nothing else is.
+    pub defaults: RefCell<HashMap<String, Value>>,
+}
+
+impl Debug for Blob {
+    // The fields can contain the blob itself.
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "Blob({})", self.name)
+    }
 }
 
 impl PartialEq for Blob {
@@ -442,13 +806,44 @@ impl Blob {
         Self {
             id: id,
             name: String::from(name),
-            fields: HashMap::new(),
+            fields: RefCell::new(HashMap::new()),
+            methods: RefCell::new(HashMap::new()),
+            defaults: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The values of a new instance, before any fields are given.
+    /// Fields without a default value are nil.
+    fn initial_values(&self) -> Vec<Value> {
+        let fields = self.fields.borrow();
+        let mut values = vec![Value::Nil; fields.len()];
+        for (name, value) in self.defaults.borrow().iter() {
+            if let Some(slot) = fields.get(name).and_then(|(slot, _)| values.get_mut(*slot)) {
+                *slot = value.clone();
+            }
         }
+        values
     }
 
-    fn add_field(&mut self, name: &str, ty: Type) -> Result<(), ()> {
-        let size = self.fields.len();
-        let entry = self.fields.entry(String::from(name));
+    /// Finds a field that has to be given a value when creating
+    /// an instance, but isn't one of the given fields.
+    fn missing_field(&self, given: &HashSet<String>) -> Option<String> {
+        let defaults = self.defaults.borrow();
+        self.fields.borrow().iter()
+            .filter(|(name, (_, ty))| {
+                !(ty.is_nullable() || defaults.contains_key(*name) || given.contains(*name))
+            })
+            .min_by_key(|(_, (slot, _))| *slot)
+            .map(|(name, _)| name.clone())
+    }
+
+    fn add_field(&self, name: &str, ty: Type) -> Result<(), ()> {
+        if self.methods.borrow().contains_key(name) {
+            return Err(());
+        }
+        let mut fields = self.fields.borrow_mut();
+        let size = fields.len();
+        let entry = fields.entry(String::from(name));
         match entry {
             Entry::Occupied(_) => Err(()),
             Entry::Vacant(v) => {
@@ -457,6 +852,56 @@ impl Blob {
             }
         }
     }
+
+    fn add_method(&self, name: &str, slot: usize) -> Result<(), ()> {
+        if self.fields.borrow().contains_key(name) {
+            return Err(());
+        }
+        match self.methods.borrow_mut().entry(String::from(name)) {
+            Entry::Occupied(_) => Err(()),
+            Entry::Vacant(v) => {
+                v.insert(slot);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Enum {
+    pub id: usize,
+    pub name: String,
+    /// The names of the variants and the types of their
+    /// payloads, in the order they're declared.
+    pub variants: Vec<(String, Option<Type>)>,
+}
+
+impl PartialEq for Enum {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Enum {
+    fn new(id: usize, name: &str) -> Self {
+        Self {
+            id: id,
+            name: String::from(name),
+            variants: Vec::new(),
+        }
+    }
+
+    fn variant(&self, name: &str) -> Option<usize> {
+        self.variants.iter().position(|(variant, _)| variant == name)
+    }
+
+    fn add_variant(&mut self, name: &str, payload: Option<Type>) -> Result<(), ()> {
+        if self.variant(name).is_some() {
+            return Err(());
+        }
+        self.variants.push((String::from(name), payload));
+        Ok(())
+    }
 }
 
 ///
@@ -480,11 +925,12 @@ pub enum Op {
     ///
     /// {A, B} - Pop - {A}
     PopUpvalue,
-    /// Copies the value on the top of the stack
-    /// and puts it on top of the stack.
+    /// Copies the given number of values on the top
+    /// of the stack and puts them on top of the stack.
     ///
-    /// {A, B} - Copy - {A, B, B}
-    Copy,
+    /// {A, B} - Copy(1) - {A, B, B}
+    /// {A, B} - Copy(2) - {A, B, A, B}
+    Copy(usize),
     /// Adds the value indexed in the `constants-vector` to the top of the stack.
     /// Also links upvalues if the value is a function.
     ///
@@ -501,14 +947,35 @@ pub enum Op {
     /// {A, B, C} - List(3) - {D(A, B, C)}
     List(usize),
 
-    /// Indexes something indexable, currently only Tuples,
+    /// Creates a new [Value::Dict] from the given number of
+    /// key-value pairs and places it on the top of the stack.
+    ///
+    /// {K1, V1, K2, V2} - Dict(2) - {D(K1: V1, K2: V2)}
+    Dict(usize),
+
+    /// Indexes something indexable, Tuples, Lists and Dicts,
     /// and adds that element to the stack.
     ///
     /// {T, I} - Index - {T[I]}
     Index,
+    /// Replaces the element at the index with a new value.
+    /// Expects a [Value::List] or a [Value::Dict].
+    ///
+    /// {T, I, V} - AssignIndex - {}
+    AssignIndex,
+    /// Steps the iteration of a for-loop, where the collection
+    /// and the position in it are kept in two hidden locals.
+    /// The next element is pushed and the position moved forward.
+    /// When there are no elements left, the program jumps.
+    ///
+    /// {C, I} - IterNext(L) - {C, I + 1, C[I]}
+    /// {C, I} - IterNext(L) - {C, I} - and jumps to L
+    IterNext(usize),
     /// Looks up a field by the given name
     /// and replaces the parent with it.
-    /// Currently only expects [Value::Blob].
+    /// Currently only expects [Value::Instance].
+    /// If there's no such field, the blob's methods
+    /// are searched and a [Value::BoundMethod] is made.
     /// (name is looked up in the internal string-list)
     ///
     /// {O} - Get(F) - {O.F}
@@ -641,15 +1108,57 @@ pub enum Op {
     /// {A} - AssignUpvalue(0) - {}
     AssignUpvalue(usize),
 
-    /// A helper instruction for the typechecker,
-    /// makes sure the top value on the stack
-    /// is of the given type, and is ment to signal
-    /// that the "variable" is added.
+    /// Gives the variant below the top of the stack
+    /// the payload on the top of the stack.
+    ///
+    /// {V, P} - Variant - {V(P)}
+    Variant,
+    /// Checks if the top value of the stack is the same
+    /// variant as the given one, regardless of payload,
+    /// and replaces it with the answer.
+    /// (The variant is looked up in the constants vector)
+    ///
+    /// {A} - IsVariant(V) - {A is V}
+    IsVariant(usize),
+    /// Replaces the variant on the top of the stack
+    /// with its payload. Has to come after an
+    /// [Op::IsVariant] check.
+    /// (The variant is looked up in the constants vector)
+    ///
+    /// {V(P)} - Payload(V) - {P}
+    Payload(usize),
+
+    /// Converts the top value of the stack between
+    /// int, float and str, failing if it can't be.
     /// (The type is looked up in the constants vector)
     ///
-    /// Does not affect the stack.
+    /// {A} - Cast(T) - {A as T}
+    Cast(usize),
+
+    /// Checks that the top value of the stack fits the
+    /// type of the variable it's given to. Only compiled
+    /// in for strict mode, and does nothing otherwise.
+    /// It costs a dispatch for every assignment to a
+    /// variable with a type, even when it does nothing.
+    /// (The type is looked up in the constants vector)
+    ///
+    /// {A} - Define(T) - {A}
     Define(usize),
 
+    /// Checks if the top value of the stack is of
+    /// the given type and replaces it with the answer.
+    /// (The type is looked up in the constants vector)
+    ///
+    /// {A} - Is(T) - {A is T}
+    Is(usize),
+    /// Reached when no arm of a match statement
+    /// matched the value, which is an error. The given
+    /// type is what the arms of the statement cover.
+    /// (The type is looked up in the constants vector)
+    ///
+    /// {A} - NoMatch(T) - {}
+    NoMatch(usize),
+
     /// Links the upvalues for the given constant
     /// function. This updates the constant stack.
     ///
@@ -661,10 +1170,25 @@ pub enum Op {
     /// then replaced with the result.
     ///
     /// Callable things are: [Value::Blob], [Value::Function],
-    /// and [Value::ExternFunction].
+    /// [Value::ExternFunction], [Value::BoundMethod]
+    /// and [Value::Coroutine]. Calling
+    /// a coroutine resumes it, and it's replaced with the value
+    /// it yields or returns.
     ///
     /// {F, A, B} - Call(2) - {F(A, B)}
     Call(usize),
+    /// Takes the function and the given number of
+    /// arguments and wraps them in a new [Value::Coroutine],
+    /// which runs the function when called.
+    ///
+    /// {F, A, B} - Spawn(2) - {C}
+    Spawn(usize),
+    /// Creates an instance of a blob from the given
+    /// number of field names and values. Fields that
+    /// aren't given get their default value.
+    ///
+    /// {B, "x", X, "y", Y} - Construct(2) - {B { x: X, y: Y }}
+    Construct(usize),
 
     /// Prints and pops the top value on the stack.
     ///
@@ -679,9 +1203,12 @@ pub enum Op {
     Return,
 
     /// Temporarily stops execution and returns
-    /// to the call site.
+    /// to the call site. Inside a coroutine the
+    /// top value is handed to whoever resumed it,
+    /// otherwise execution returns to the host
+    /// and the value is dropped.
     ///
-    /// Does not affect the stack.
+    /// {A} - Yield - {}
     Yield,
 }
 
@@ -693,7 +1220,6 @@ pub enum Op {
 mod op {
     use super::{Type, Value};
     use std::rc::Rc;
-    use std::collections::HashSet;
 
     fn tuple_bin_op(a: &Rc<Vec<Value>>, b: &Rc<Vec<Value>>, f: fn (&Value, &Value) -> Value) -> Value {
         Value::Tuple(Rc::new(a.iter().zip(b.iter()).map(|(a, b)| f(a, b)).collect()))
@@ -703,35 +1229,30 @@ mod op {
         Value::Tuple(Rc::new(a.iter().map(f).collect()))
     }
 
-    fn union_un_op(a: &HashSet<Value>, f: fn (&Value) -> Value) -> Value {
-        a.iter().find_map(|x| {
-            let x = f(x);
-            if x.is_nil() {
-                None
-            } else {
-                Some(x)
-            }
-        }).unwrap_or(Value::Nil)
-    }
-
-    fn union_bin_op(a: &HashSet<Value>, b: &Value, f: fn (&Value, &Value) -> Value) -> Value {
-        a.iter().find_map(|x| {
-            let x = f(x, b);
-            if x.is_nil() {
-                None
-            } else {
-                Some(x)
-            }
-        }).unwrap_or(Value::Nil)
-    }
-
     pub fn neg(value: &Value) -> Value {
         match value {
             Value::Float(a) => Value::Float(-*a),
-            Value::Int(a) => Value::Int(-*a),
+            Value::Int(a) => Value::Int(a.wrapping_neg()),
             Value::Tuple(a) => tuple_un_op(a, neg),
-            Value::Union(v) => union_un_op(&v, neg),
-            Value::Unknown => Value::Unknown,
+            _ => Value::Nil,
+        }
+    }
+
+    /// Converts between ints, floats and strings. Floats are rounded
+    /// towards zero, and have to be in the range of an int.
+    pub fn cast(value: &Value, ty: &Type) -> Value {
+        match (value, ty) {
+            (Value::Int(a), Type::Int) => Value::Int(*a),
+            (Value::Int(a), Type::Float) => Value::Float(*a as f64),
+            (Value::Int(a), Type::String) => Value::String(Rc::new(a.to_string())),
+            (Value::Float(a), Type::Int) if *a >= i64::MIN as f64 && *a < i64::MAX as f64 => {
+                Value::Int(*a as i64)
+            }
+            (Value::Float(a), Type::Float) => Value::Float(*a),
+            (Value::Float(a), Type::String) => Value::String(Rc::new(a.to_string())),
+            (Value::String(a), Type::Int) => a.parse().map(Value::Int).unwrap_or(Value::Nil),
+            (Value::String(a), Type::Float) => a.parse().map(Value::Float).unwrap_or(Value::Nil),
+            (Value::String(a), Type::String) => Value::String(Rc::clone(a)),
             _ => Value::Nil,
         }
     }
@@ -740,8 +1261,6 @@ mod op {
         match value {
             Value::Bool(a) => Value::Bool(!*a),
             Value::Tuple(a) => tuple_un_op(a, not),
-            Value::Union(v) => union_un_op(&v, not),
-            Value::Unknown => Value::from(Type::Bool),
             _ => Value::Nil,
         }
     }
@@ -750,12 +1269,9 @@ mod op {
     pub fn add(a: &Value, b: &Value) -> Value {
         match (a, b) {
             (Value::Float(a), Value::Float(b)) => Value::Float(a + b),
-            (Value::Int(a), Value::Int(b)) => Value::Int(a + b),
+            (Value::Int(a), Value::Int(b)) => Value::Int(a.wrapping_add(*b)),
             (Value::String(a), Value::String(b)) => Value::String(Rc::from(format!("{}{}", a, b))),
             (Value::Tuple(a), Value::Tuple(b)) if a.len() == b.len() => tuple_bin_op(a, b, add),
-            (Value::Unknown, a) | (a, Value::Unknown) if !matches!(a, Value::Unknown) => add(a, a),
-            (Value::Unknown, Value::Unknown) => Value::Unknown,
-            (Value::Union(a), b) | (b, Value::Union(a)) => union_bin_op(&a, b, add),
             _ => Value::Nil,
         }
     }
@@ -767,11 +1283,8 @@ mod op {
     pub fn mul(a: &Value, b: &Value) -> Value {
         match (a, b) {
             (Value::Float(a), Value::Float(b)) => Value::Float(a * b),
-            (Value::Int(a), Value::Int(b)) => Value::Int(a * b),
+            (Value::Int(a), Value::Int(b)) => Value::Int(a.wrapping_mul(*b)),
             (Value::Tuple(a), Value::Tuple(b)) if a.len() == b.len() => tuple_bin_op(a, b, mul),
-            (Value::Unknown, a) | (a, Value::Unknown) if !matches!(a, Value::Unknown) => mul(a, a),
-            (Value::Unknown, Value::Unknown) => Value::Unknown,
-            (Value::Union(a), b) | (b, Value::Union(a)) => union_bin_op(&a, b, mul),
             _ => Value::Nil,
         }
     }
@@ -779,11 +1292,11 @@ mod op {
     pub fn div(a: &Value, b: &Value) -> Value {
         match (a, b) {
             (Value::Float(a), Value::Float(b)) => Value::Float(a / b),
-            (Value::Int(a), Value::Int(b)) => Value::Int(a / b),
+            (Value::Int(a), Value::Int(b)) => match a.checked_div(*b) {
+                Some(c) => Value::Int(c),
+                None => Value::Nil,
+            },
             (Value::Tuple(a), Value::Tuple(b)) if a.len() == b.len() => tuple_bin_op(a, b, div),
-            (Value::Unknown, a) | (a, Value::Unknown) if !matches!(a, Value::Unknown) => div(a, a),
-            (Value::Unknown, Value::Unknown) => Value::Unknown,
-            (Value::Union(a), b) | (b, Value::Union(a)) => union_bin_op(&a, b, div),
             _ => Value::Nil,
         }
     }
@@ -805,10 +1318,8 @@ mod op {
                 }
                 Value::Bool(true)
             }
-            (Value::Unknown, a) | (a, Value::Unknown) if !matches!(a, Value::Unknown) => eq(a, a),
-            (Value::Unknown, Value::Unknown) => Value::Unknown,
-            (Value::Union(a), b) | (b, Value::Union(a)) => union_bin_op(&a, b, eq),
             (Value::Nil, Value::Nil) => Value::Bool(true),
+            (Value::Nil, _) | (_, Value::Nil) => Value::Bool(false),
             (Value::List(a), Value::List(b))  => {
                 let a = a.borrow();
                 let b = b.borrow();
@@ -825,6 +1336,34 @@ mod op {
                 }
                 Value::Bool(true)
             }
+            (Value::Variant(a, a_variant, a_payload), Value::Variant(b, b_variant, b_payload))
+                if a == b => {
+                if a_variant != b_variant {
+                    Value::Bool(false)
+                } else {
+                    eq(a_payload, b_payload)
+                }
+            }
+            (Value::Dict(a), Value::Dict(b))  => {
+                let a = a.borrow();
+                let b = b.borrow();
+                if a.len() != b.len() {
+                    return Value::Bool(false);
+                }
+                for (k, a) in a.iter() {
+                    let b = match b.get(k) {
+                        Some(b) => b,
+                        None => { return Value::Bool(false); },
+                    };
+                    match eq(a, b) {
+                        Value::Bool(true) => {},
+                        Value::Bool(false) => { return Value::Bool(false); },
+                        Value::Nil => { return Value::Nil; },
+                        _ => unreachable!("Equality should only return bool or nil.")
+                    }
+                }
+                Value::Bool(true)
+            }
             _ => Value::Nil,
         }
     }
@@ -842,9 +1381,6 @@ mod op {
                         a => Some(a),
                     }).unwrap_or(Value::Bool(true))
             }
-            (Value::Unknown, a) | (a, Value::Unknown) if !matches!(a, Value::Unknown) => less(a, a),
-            (Value::Unknown, Value::Unknown) => Value::Unknown,
-            (Value::Union(a), b) | (b, Value::Union(a)) => union_bin_op(&a, b, less),
             _ => Value::Nil,
         }
     }
@@ -857,9 +1393,6 @@ mod op {
         match (a, b) {
             (Value::Bool(a), Value::Bool(b)) => Value::Bool(*a && *b),
             (Value::Tuple(a), Value::Tuple(b)) if a.len() == b.len() => tuple_bin_op(a, b, and),
-            (Value::Unknown, a) | (a, Value::Unknown) if !matches!(a, Value::Unknown) => and(a, a),
-            (Value::Unknown, Value::Unknown) => Value::Unknown,
-            (Value::Union(a), b) | (b, Value::Union(a)) => union_bin_op(&a, b, and),
             _ => Value::Nil,
         }
     }
@@ -868,9 +1401,6 @@ mod op {
         match (a, b) {
             (Value::Bool(a), Value::Bool(b)) => Value::Bool(*a || *b),
             (Value::Tuple(a), Value::Tuple(b)) if a.len() == b.len() => tuple_bin_op(a, b, or),
-            (Value::Unknown, a) | (a, Value::Unknown) if !matches!(a, Value::Unknown) => or(a, a),
-            (Value::Unknown, Value::Unknown) => Value::Unknown,
-            (Value::Union(a), b) | (b, Value::Union(a)) => union_bin_op(&a, b, or),
             _ => Value::Nil,
         }
     }
@@ -892,8 +1422,8 @@ pub struct Block {
     pub name: String,
     pub file: PathBuf,
     ops: Vec<Op>,
-    last_line_offset: usize,
-    line_offsets: HashMap<usize, usize>,
+    last_line_offset: Span,
+    line_offsets: HashMap<usize, Span>,
 }
 
 impl Block {
@@ -906,7 +1436,7 @@ impl Block {
             name: String::from(name),
             file: file.to_owned(),
             ops: Vec::new(),
-            last_line_offset: 0,
+            last_line_offset: Span::default(),
             line_offsets: HashMap::new(),
         }
     }
@@ -949,20 +1479,25 @@ impl Block {
         }
     }
 
-    fn add_line(&mut self, token_position: usize) {
-        if token_position != self.last_line_offset {
-            self.line_offsets.insert(self.curr(), token_position);
-            self.last_line_offset = token_position;
+    fn add_line(&mut self, span: Span) {
+        if span != self.last_line_offset {
+            self.line_offsets.insert(self.curr(), span);
+            self.last_line_offset = span;
         }
     }
 
-    fn line(&self, ip: usize) -> usize {
+    /// Where the op at `ip` was written.
+    fn span(&self, ip: usize) -> Span {
         for i in (0..=ip).rev() {
-            if let Some(line) = self.line_offsets.get(&i) {
-                return *line;
+            if let Some(span) = self.line_offsets.get(&i) {
+                return *span;
             }
         }
-        return 0;
+        return Span::default();
+    }
+
+    fn line(&self, ip: usize) -> usize {
+        self.span(ip).line
     }
 
     pub fn debug_print(&self) {
@@ -970,7 +1505,7 @@ impl Block {
         for (i, s) in self.ops.iter().enumerate() {
             println!("{}{}",
                      if self.line_offsets.contains_key(&i) {
-                         format!("{:5} ", self.line_offsets[&i].blue())
+                         format!("{:5} ", self.line_offsets[&i].line.blue())
                      } else {
                          format!("    {} ", "|".blue())
                      },
@@ -980,16 +1515,16 @@ impl Block {
         println!();
     }
 
-    fn add(&mut self, op: Op, token_position: usize) -> usize {
+    fn add(&mut self, op: Op, span: Span) -> usize {
         let len = self.curr();
-        self.add_line(token_position);
+        self.add_line(span);
         self.ops.push(op);
         len
     }
 
-    fn add_from(&mut self, ops: &[Op], token_position: usize) -> usize {
+    fn add_from(&mut self, ops: &[Op], span: Span) -> usize {
         let len = self.curr();
-        self.add_line(token_position);
+        self.add_line(span);
         self.ops.extend_from_slice(ops);
         len
     }
@@ -1009,6 +1544,9 @@ pub struct Prog {
     pub functions: Vec<RustFunction>,
     pub constants: Vec<Value>,
     pub strings: Vec<String>,
+    /// Maps the names of the global constants in the main file
+    /// to their slot in `constants`.
+    pub globals: HashMap<String, usize>,
 }
 
 #[cfg(test)]
@@ -1027,8 +1565,10 @@ mod tests {
                 &[$($crate::error::Error {
                     kind: $kind,
                     file: _,
-                    line: _,
+                    span: _,
                     message: _,
+                    notes: _,
+                    stack: _,
                 },
                 )*]
             ) {
@@ -1087,14 +1627,21 @@ mod tests {
         ($fn:ident, $path:literal, $print:expr) => {
             #[test]
             fn $fn() {
-                let mut args = $crate::Args::default();
-                args.file = Some(std::path::PathBuf::from($path));
-                args.print_bytecode = $print;
-                $crate::run_file(args, sylt_macro::link!(
-                    crate::dbg as dbg,
-                    crate::push as push,
-                    crate::len as len,
-                )).unwrap();
+                // Programs that run have to run in strict mode too.
+                for strict in [false, true] {
+                    let mut args = $crate::Args::default();
+                    args.file = Some(std::path::PathBuf::from($path));
+                    args.print_bytecode = $print;
+                    args.strict = strict;
+                    $crate::run_file(args, sylt_macro::link!(
+                        crate::dbg as dbg,
+                        crate::push as push,
+                        crate::len as len,
+                        crate::done as done,
+                        crate::has as has,
+                        crate::range as range,
+                    )).unwrap();
+                }
             }
         };
         ($fn:ident, $path:literal, $print:expr, $errs:tt) => {
@@ -1111,6 +1658,9 @@ mod tests {
                     crate::dbg as dbg,
                     crate::push as push,
                     crate::len as len,
+                    crate::done as done,
+                    crate::has as has,
+                    crate::range as range,
                 ));
                 $crate::assert_errs!(res, $errs);
             }
@@ -1118,6 +1668,473 @@ mod tests {
     }
 
     sylt_macro::find_tests!();
+
+    #[test]
+    fn run_string() {
+        crate::run_string("start :: fn {\n    1 + 1 <=> 2\n}\n", false, Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn parse_string() {
+        use crate::ast::StatementKind;
+
+        let path = std::path::Path::new("parsed.sy");
+        let module = crate::ast::parse("a := 1\nstart :: fn {\n    a <=> 1\n}\n", path).unwrap();
+        assert_eq!(module.path, path);
+        assert_eq!(module.statements.len(), 2);
+        assert!(matches!(module.statements[1].kind, StatementKind::Definition { .. }));
+        assert_eq!(module.statements[1].span.line, 2);
+    }
+
+    #[test]
+    fn compile_string_uses_given_path() {
+        use crate::error::ErrorKind;
+
+        let source = "start :: fn {\n    a := 1\n    a = 0.1\n    a\n}\n";
+        let path = std::path::Path::new("assets/level.sy");
+        let res = crate::compile_string(source, path, false, false, Vec::new());
+        assert_errs!(&res, [ErrorKind::TypeMismatch(_, _)]);
+        assert_eq!(res.err().unwrap()[0].file, path);
+    }
+
+    #[test]
+    fn errors_point_at_columns() {
+        use crate::tokenizer::Span;
+
+        let source = "start :: fn {\n    a := 1 + 1.0\n    a\n}\n";
+        let errs = crate::compile_string(source, std::path::Path::new("/string"), false, false, Vec::new()).err().unwrap();
+        assert_eq!(errs[0].span, Span { line: 2, col_start: 10, col_end: 17 });
+    }
+
+    #[test]
+    fn error_to_json() {
+        use crate::error::{Error, ErrorKind};
+        use crate::tokenizer::Span;
+
+        let error = Error {
+            kind: ErrorKind::AssertFailed,
+            file: std::path::PathBuf::from("a.sy"),
+            span: Span { line: 2, col_start: 5, col_end: 11 },
+            message: Some(String::from("\"a\"\n\tb")),
+            notes: Vec::new(),
+            stack: Vec::new(),
+        };
+        assert_eq!(
+            error.to_json(),
+            concat!(r#"{"file": "a.sy", "line": 2, "span": {"line": 2, "col_start": 5, "col_end": 11}, "#,
+                    r#""kind": "AssertFailed", "code": "E0017", "description": "Assertion failed", "#,
+                    r#""message": "\"a\"\n\tb", "notes": [], "stack": []}"#)
+        );
+    }
+
+    #[test]
+    fn types_are_written_like_code() {
+        use crate::error::ErrorKind;
+        use crate::Type;
+
+        let maybe_int = Type::Union(vec![Type::Int, Type::Void].into_iter().collect());
+        assert_eq!(maybe_int.to_string(), "int?");
+        assert_eq!(Type::List(Box::new(Type::Float)).to_string(), "[float]");
+        assert_eq!(Type::Function(vec![Type::Int], Box::new(Type::Bool)).to_string(), "fn int -> bool");
+        assert_eq!(
+            ErrorKind::TypeMismatch(maybe_int, Type::String).to_string(),
+            "Expected 'int?' and got 'str'"
+        );
+    }
+
+    #[test]
+    fn every_code_is_explained() {
+        use std::collections::HashSet;
+        use std::path::PathBuf;
+        use crate::error::{explain, ErrorKind};
+        use crate::tokenizer::Token;
+        use crate::{Op, Type, Value};
+
+        let kinds = [
+            ErrorKind::TypeError(Op::Pop, Vec::new()),
+            ErrorKind::TypeMismatch(Type::Int, Type::Int),
+            ErrorKind::CannotInfer(Type::Int, Type::Int),
+            ErrorKind::ArgumentType(Vec::new(), Vec::new()),
+            ErrorKind::IndexError(Value::Nil, Type::Int),
+            ErrorKind::ExternTypeMismatch(String::new(), Vec::new()),
+            ErrorKind::ValueError(Op::Pop, Vec::new()),
+            ErrorKind::UnknownField(Value::Nil, String::new()),
+            ErrorKind::UninitializedField(Value::Nil, String::new()),
+            ErrorKind::UnknownFunction(String::new()),
+            ErrorKind::MaybeNil(Type::Int),
+            ErrorKind::ArgumentCount(0, 0),
+            ErrorKind::CannotResume,
+            ErrorKind::NonExhaustiveMatch(Vec::new()),
+            ErrorKind::MutableKey(Type::Int),
+            ErrorKind::IndexOutOfBounds(Value::Nil, 0, 0),
+            ErrorKind::UnknownKey(Value::Nil, Value::Nil),
+            ErrorKind::AssertFailed,
+            ErrorKind::InvalidProgram,
+            ErrorKind::Unreachable,
+            ErrorKind::SyntaxError(0, Token::EOF),
+            ErrorKind::GitConflictError(0, 0),
+            ErrorKind::FileNotFound(PathBuf::new()),
+            ErrorKind::CyclicImport(Vec::new()),
+            ErrorKind::NoFileGiven,
+            ErrorKind::VMBusy,
+            ErrorKind::NothingToRun,
+        ];
+        let mut codes = HashSet::new();
+        for kind in kinds.iter() {
+            // Stops compiling when a kind is added, so it's added above too.
+            match kind {
+                ErrorKind::TypeError(..) | ErrorKind::TypeMismatch(..) | ErrorKind::CannotInfer(..)
+                | ErrorKind::ArgumentType(..) | ErrorKind::IndexError(..)
+                | ErrorKind::ExternTypeMismatch(..) | ErrorKind::ValueError(..)
+                | ErrorKind::UnknownField(..) | ErrorKind::UninitializedField(..)
+                | ErrorKind::UnknownFunction(..) | ErrorKind::MaybeNil(..)
+                | ErrorKind::ArgumentCount(..) | ErrorKind::CannotResume
+                | ErrorKind::NonExhaustiveMatch(..) | ErrorKind::MutableKey(..)
+                | ErrorKind::IndexOutOfBounds(..) | ErrorKind::UnknownKey(..)
+                | ErrorKind::AssertFailed | ErrorKind::InvalidProgram | ErrorKind::Unreachable
+                | ErrorKind::SyntaxError(..) | ErrorKind::GitConflictError(..)
+                | ErrorKind::FileNotFound(..) | ErrorKind::CyclicImport(..)
+                | ErrorKind::NoFileGiven | ErrorKind::VMBusy | ErrorKind::NothingToRun => {}
+            }
+            assert!(explain(kind.code()).is_some(), "{} isn't explained", kind.code());
+            assert!(codes.insert(kind.code()), "{} is used twice", kind.code());
+        }
+    }
+
+    #[test]
+    fn suggests_close_names() {
+        use crate::error::closest;
+
+        assert_eq!(closest("lenght", vec!["length", "left"]), Some("length"));
+        assert_eq!(closest("x", vec!["y"]), None);
+
+        let notes = |source: &str| {
+            let errs = crate::compile_string(source, std::path::Path::new("/string"), false, false, Vec::new()).err().unwrap();
+            errs.iter().flat_map(|err| err.notes.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(notes("start :: fn {\n    counter := 1\n    counter\n    countr\n}\n"),
+                   vec!["Did you mean 'counter'?"]);
+        assert_eq!(notes("A :: blob { length: int }\nstart :: fn {\n    a := A { length: 1 }\n    a.lenght\n}\n"),
+                   vec!["Did you mean 'length'?"]);
+    }
+
+    #[test]
+    fn runtime_errors_have_stack() {
+        let source = "f :: fn x: int -> int {\n    l := [1]\n    ret l[x]\n}\n\nstart :: fn {\n    f(2)\n}\n";
+        let prog = crate::compile_string(source, std::path::Path::new("/string"), false, false, Vec::new()).unwrap();
+        let mut vm = crate::vm::VM::new();
+        vm.init(&prog);
+        let err = vm.run().err().unwrap();
+        let stack: Vec<_> = err.stack.iter().map(|frame| (frame.function.as_str(), frame.line)).collect();
+        assert_eq!(stack, vec![("/preamble", 6), ("start", 7), ("f", 3)]);
+    }
+
+    fn init_vm(source: &str) -> crate::vm::VM {
+        let prog = crate::compile_string(source, std::path::Path::new("/string"), false, false, Vec::new()).unwrap();
+        let mut vm = crate::vm::VM::new();
+        vm.init(&prog);
+        vm.run().unwrap();
+        vm
+    }
+
+    #[test]
+    fn call_into_script() {
+        use crate::Value;
+
+        let mut vm = init_vm("
+total := 0.0
+
+update :: fn dt: float -> float {
+    total += dt
+    ret total
+}
+
+start :: fn {}
+");
+        assert_eq!(vm.call("update", &[Value::Float(0.5)]).unwrap(), Some(Value::Float(0.5)));
+        assert_eq!(vm.call("update", &[Value::Float(1.0)]).unwrap(), Some(Value::Float(1.5)));
+    }
+
+    #[test]
+    fn call_into_script_errors() {
+        use crate::Value;
+        use crate::error::ErrorKind;
+
+        let mut vm = init_vm("
+f :: fn a: int -> int {
+    ret 1 / a
+}
+
+start :: fn {}
+");
+        assert_errs!(vm.call("g", &[]).map_err(|e| vec![e]), [ErrorKind::UnknownFunction(_)]);
+        assert_errs!(vm.call("f", &[]).map_err(|e| vec![e]), [ErrorKind::ArgumentCount(1, 0)]);
+        assert_errs!(vm.call("f", &[Value::Bool(true)]).map_err(|e| vec![e]), [ErrorKind::ArgumentType(_, _)]);
+        assert_eq!(vm.call("f", &[Value::Int(1)]).unwrap(), Some(Value::Int(1)));
+        assert_errs!(vm.call("f", &[Value::Int(0)]).map_err(|e| vec![e]), [ErrorKind::ValueError(_, _)]);
+    }
+
+    #[test]
+    fn call_and_resume() {
+        use crate::Value;
+        use crate::error::ErrorKind;
+
+        let mut vm = init_vm("
+f :: fn -> int {
+    yield
+    ret 2
+}
+
+start :: fn {}
+");
+        assert_eq!(vm.call("f", &[]).unwrap(), None);
+        assert_errs!(vm.call("f", &[]).map_err(|e| vec![e]), [ErrorKind::VMBusy]);
+        assert_eq!(vm.resume().unwrap(), Some(Value::Int(2)));
+        assert_errs!(vm.resume().map_err(|e| vec![e]), [ErrorKind::NothingToRun]);
+    }
+
+    #[test]
+    fn strict_mode_checks_types() {
+        use std::rc::Rc;
+        use crate::error::ErrorKind;
+        use crate::vm::VM;
+        use crate::{RustFunction, Value};
+
+        // Could be anything to the typechecker, but is a str.
+        fn anything(_: &[Value], typecheck: bool) -> Result<Value, ErrorKind> {
+            Ok(if typecheck { Value::Unknown } else { Value::String(Rc::new(String::from("a"))) })
+        }
+        // Says it returns an int, but returns a str.
+        fn liar(_: &[Value], typecheck: bool) -> Result<Value, ErrorKind> {
+            Ok(if typecheck { Value::Int(1) } else { Value::String(Rc::new(String::from("a"))) })
+        }
+
+        let run = |source: &str, strict: bool| {
+            let functions = vec![
+                (String::from("anything"), anything as RustFunction),
+                (String::from("liar"), liar as RustFunction),
+            ];
+            let prog = crate::compile_string(source, std::path::Path::new("/string"), false, strict, functions).unwrap();
+            let mut vm = VM::new();
+            vm.strict = strict;
+            vm.init(&prog);
+            vm.run().map_err(|e| vec![e])
+        };
+
+        let sources = [
+            "start :: fn {\n    a: int = anything()\n    a\n}\n",
+            "start :: fn {\n    a: int = 1\n    f := fn {\n        a = anything()\n    }\n    f()\n    a\n}\n",
+            "B :: blob { x: int }\nstart :: fn {\n    b := B { x: anything() }\n    b\n}\n",
+            "B :: blob { x: int }\nstart :: fn {\n    b := B { x: 1 }\n    b.x = anything()\n    b\n}\n",
+            "start :: fn {\n    a := liar()\n    a\n}\n",
+            "start :: fn {\n    a := 1\n    a = anything()\n    a\n}\n",
+            "start :: fn {\n    a := 1\n    f := fn {\n        a = anything()\n    }\n    f()\n    a\n}\n",
+        ];
+        for source in sources.iter() {
+            assert!(run(source, false).is_ok());
+            assert_errs!(run(source, true), [ErrorKind::TypeMismatch(_, _)]);
+        }
+
+        // Generic functions fit any function type while running.
+        let valid = [
+            "start :: fn {\n    f := fn<T> a: T -> T {\n        ret a\n    }\n    f(1) <=> 1\n}\n",
+            "id :: fn<T> a: T -> T {\n    ret a\n}\nstart :: fn {\n    fs := [id]\n    fs[0](1) <=> 1\n}\n",
+        ];
+        for source in valid.iter() {
+            assert!(run(source, true).is_ok());
+        }
+    }
+
+    /// A xorshift, so every run tests the same programs.
+    fn random(seed: &mut u64, n: usize) -> usize {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        (*seed % n as u64) as usize
+    }
+
+    /// Random ops with small arguments. Jumps only go forwards and functions
+    /// are only called right after they're loaded, and only `leaf` blocks
+    /// can be loaded, so every program ends.
+    fn random_ops(seed: &mut u64, num_constants: usize, leaf: bool) -> Vec<crate::Op> {
+        use crate::Op;
+
+        let len = 1 + random(seed, 24);
+        let mut ops = Vec::new();
+        while ops.len() < len {
+            let n = random(seed, 4);
+            let slot = random(seed, num_constants + 2);
+            let op = match random(seed, 50) {
+                0 => Op::Illegal,
+                1 => Op::Pop,
+                2 => Op::PopUpvalue,
+                3 => Op::Copy(n),
+                4..=8 => Op::Constant(slot),
+                9 => Op::Tuple(n),
+                10 => Op::List(n),
+                11 => Op::Dict(n),
+                12 => Op::Index,
+                13 => Op::AssignIndex,
+                14 => Op::IterNext(0),
+                15 => Op::Get(n),
+                16 => Op::Set(n),
+                17 => Op::Add,
+                18 => Op::Sub,
+                19 => Op::Mul,
+                20 => Op::Div,
+                21 => Op::Neg,
+                22 => Op::And,
+                23 => Op::Or,
+                24 => Op::Not,
+                25 => Op::Jmp(0),
+                26 => Op::JmpFalse(0),
+                27 => Op::JmpNPop(0, n),
+                28 => Op::Equal,
+                29 => Op::Less,
+                30 => Op::Greater,
+                31 => Op::Assert,
+                32 => Op::Unreachable,
+                33 => Op::ReadLocal(n),
+                34 => Op::AssignLocal(n),
+                35 => Op::ReadUpvalue(n),
+                36 => Op::AssignUpvalue(n),
+                37 => Op::Variant,
+                38 => Op::IsVariant(slot),
+                39 => Op::Payload(n),
+                40 => Op::Is(slot),
+                41 => Op::NoMatch(n),
+                42 => Op::Link(slot),
+                43 => Op::Spawn(n),
+                44 => Op::Construct(n),
+                45 => Op::Yield,
+                46 => Op::Return,
+                47 => Op::Cast(slot),
+                48 => Op::Define(slot),
+                _ if leaf => Op::Return,
+                _ => {
+                    ops.push(Op::Constant(slot));
+                    Op::Call(random(seed, 2))
+                }
+            };
+            ops.push(op);
+        }
+
+        for ip in 0..ops.len() {
+            let target = loop {
+                let target = ip + 1 + random(seed, ops.len() - ip);
+                if !matches!(ops.get(target), Some(Op::Call(_))) {
+                    break target;
+                }
+            };
+            ops[ip] = match ops[ip] {
+                Op::IterNext(_) => Op::IterNext(target),
+                Op::Jmp(_) => Op::Jmp(target),
+                Op::JmpFalse(_) => Op::JmpFalse(target),
+                Op::JmpNPop(_, n) => Op::JmpNPop(target, n),
+                op => op,
+            };
+        }
+        ops
+    }
+
+    fn random_prog(seed: &mut u64) -> crate::Prog {
+        use std::cell::RefCell;
+        use std::collections::HashMap;
+        use std::path::Path;
+        use std::rc::Rc;
+        use crate::{Blob, Block, Prog, Type, Value};
+
+        let block = |name: &str, ty: Type| {
+            let mut block = Block::new(name, Path::new("/fuzz.sy"));
+            block.ty = ty;
+            block
+        };
+        let function = Type::Function(Vec::new(), Box::new(Type::Void));
+
+        let mut leaves = vec![
+            block("leaf", function.clone()),
+            block("leaf", function.clone()),
+            block("takes_an_int", Type::Function(vec![Type::Int], Box::new(Type::Void))),
+            block("not_a_function", Type::Int),
+            block("captures_nothing", function.clone()),
+            block("captures_too_much", function.clone()),
+        ];
+        leaves[4].upvalues.push((9, false, Type::Int));
+        leaves[5].upvalues.push((9, true, Type::Int));
+
+        let blob = Blob::new(0, "B");
+        blob.fields.borrow_mut().insert(String::from("a"), (0, Type::Int));
+        blob.fields.borrow_mut().insert(String::from("b"), (7, Type::Int));
+        blob.methods.borrow_mut().insert(String::from("b"), 99);
+
+        let mut constants = vec![
+            Value::Int(random(seed, 5) as i64 - 2),
+            Value::Int(i64::MIN),
+            Value::Float(0.5),
+            Value::Bool(random(seed, 2) == 0),
+            Value::Nil,
+            Value::String(Rc::new(String::from("å"))),
+            Value::Ty(Type::Int),
+            Value::Range(0, 3),
+            Value::Tuple(Rc::new(vec![Value::Int(1), Value::Int(0)])),
+            Value::List(Rc::new(RefCell::new(vec![Value::Int(1)]))),
+            Value::ExternFunction(3),
+            Value::Blob(Rc::new(blob)),
+        ];
+        let leaves: Vec<_> = leaves.into_iter().map(|mut leaf| {
+            leaf.ops = random_ops(seed, constants.len() + 6, true);
+            Rc::new(RefCell::new(leaf))
+        }).collect();
+        constants.extend(leaves.iter().map(|leaf| Value::Function(Rc::new(Vec::new()), Rc::clone(leaf))));
+
+        let mut main = block("main", function);
+        main.ops = random_ops(seed, constants.len(), false);
+        let mut blocks = vec![Rc::new(RefCell::new(main))];
+        blocks.extend(leaves);
+
+        Prog {
+            blocks,
+            functions: Vec::new(),
+            constants,
+            strings: vec![String::from("a"), String::from("b")],
+            globals: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn invalid_programs_are_errors() {
+        use crate::error::ErrorKind;
+        use crate::vm::VM;
+        use crate::{Block, Op, Prog};
+
+        let mut vm = VM::new();
+        vm.init(&Prog {
+            blocks: Vec::new(),
+            functions: Vec::new(),
+            constants: Vec::new(),
+            strings: Vec::new(),
+            globals: std::collections::HashMap::new(),
+        });
+        assert_errs!(vm.run().map_err(|e| vec![e]), [ErrorKind::NothingToRun]);
+
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..3000 {
+            let prog = random_prog(&mut seed);
+            vm.strict = random(&mut seed, 2) == 0;
+            vm.init(&prog);
+            // Anything but a panic is fine, and running again after
+            // an error or a return shouldn't panic either.
+            let _ = vm.run();
+            let _ = vm.resume();
+        }
+
+        let mut prog = random_prog(&mut seed);
+        let mut block = Block::new("main", std::path::Path::new("/fuzz.sy"));
+        block.ops = vec![Op::Pop, Op::Pop];
+        prog.blocks[0] = std::rc::Rc::new(std::cell::RefCell::new(block));
+        vm.init(&prog);
+        assert_errs!(vm.run().map_err(|e| vec![e]), [ErrorKind::InvalidProgram]);
+    }
 }
 
 // The "standard library"
@@ -1136,9 +2153,14 @@ pub fn push(values: &[Value], typecheck: bool) -> Result<Value, ErrorKind> {
             let ls: &RefCell<_> = ls.borrow();
             let ls = &ls.borrow();
             assert!(ls.len() == 1);
+            // Anything can be pushed to an empty list, the
+            // typechecker decides its type from the first push.
+            if matches!(ls[0], Value::Unknown) {
+                return Ok(Value::Nil);
+            }
             let ls = Type::from(&ls[0]);
             let v: Type = Type::from(&*v);
-            if ls == v {
+            if ls == v || ls.fits(&v) {
                 Ok(Value::Nil)
             } else {
                 Err(ErrorKind::TypeMismatch(ls, v))
@@ -1169,4 +2191,36 @@ sylt_macro::extern_function!(
     [Value::Tuple(ls)] -> Type::Int => {
         Ok(Value::Int(ls.len() as i64))
     },
+    [Value::Dict(dict)] -> Type::Int => {
+        let dict: &RefCell<HashMap<Value, Value>> = dict.borrow();
+        let dict = dict.borrow();
+        Ok(Value::Int(dict.len() as i64))
+    },
+);
+
+sylt_macro::extern_function!(
+    has
+    [Value::Dict(dict), key] -> Type::Bool => {
+        let dict: &RefCell<HashMap<Value, Value>> = dict.borrow();
+        let dict = dict.borrow();
+        Ok(Value::Bool(dict.contains_key(key)))
+    },
+);
+
+sylt_macro::extern_function!(
+    range
+    [Value::Int(hi)] -> Type::Range => {
+        Ok(Value::Range(0, *hi))
+    },
+    [Value::Int(lo), Value::Int(hi)] -> Type::Range => {
+        Ok(Value::Range(*lo, *hi))
+    },
+);
+
+sylt_macro::extern_function!(
+    done
+    [Value::Coroutine(co)] -> Type::Bool => {
+        let co: &RefCell<vm::Coroutine> = co.borrow();
+        Ok(Value::Bool(co.borrow().is_done()))
+    },
 );
@@ -1,28 +1,91 @@
+use std::io::IsTerminal;
 use std::path::Path;
 
 use sylt::{run_file, Args};
 
+/// How errors are written to stdout.
+#[derive(PartialEq)]
+enum ErrorFormat {
+    Human,
+    /// One line of JSON per error, followed by a line like
+    /// `{"omitted": 3}` if some errors weren't written.
+    Json,
+}
+
+/// How errors are reported.
+struct Report {
+    format: ErrorFormat,
+    /// At most this many errors are written, since the first
+    /// few are the ones worth fixing first.
+    max_errors: usize,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            format: ErrorFormat::Human,
+            max_errors: 20,
+        }
+    }
+}
+
 fn main() -> Result<(), String> {
-    let args = parse_args();
+    if let Some(code) = explain_arg() {
+        return match sylt::error::explain(&code) {
+            Some(explanation) => {
+                println!("{}", explanation);
+                Ok(())
+            }
+            None => Err(format!("No error has the code '{}'", code)),
+        };
+    }
+
+    let (args, report) = parse_args();
     if args.file.is_none() {
         return Err("No file to run".to_string());
     }
+    let colours = std::io::stdout().is_terminal();
     let errs = match run_file(args, sylt_macro::link!(
         sylt::dbg as dbg,
         sylt::push as push,
         sylt::len as len,
+        sylt::done as done,
+        sylt::has as has,
+        sylt::range as range,
     )) {
         Err(it) => it,
         _ => return Ok(()),
     };
-    for err in errs.iter() {
-        println!("{}", err);
+    for err in errs.iter().take(report.max_errors) {
+        match report.format {
+            ErrorFormat::Human => println!("{}", err.render(colours)),
+            ErrorFormat::Json => println!("{}", err.to_json()),
+        }
+    }
+    if errs.len() > report.max_errors {
+        let omitted = errs.len() - report.max_errors;
+        match report.format {
+            ErrorFormat::Human => println!("... and {} more errors", omitted),
+            ErrorFormat::Json => println!("{{\"omitted\": {}}}", omitted),
+        }
     }
     Err(format!("{} errors occured.", errs.len()))
 }
 
-fn parse_args() -> Args {
+/// The error code given with `--explain`, if any.
+fn explain_arg() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--explain" {
+            return args.next();
+        }
+    }
+    None
+}
+
+fn parse_args() -> (Args, Report) {
     let mut args = Args::default();
+    let mut report = Report::default();
 
     for s in std::env::args().skip(1) {
         let path = Path::new(&s).to_owned();
@@ -33,9 +96,17 @@ fn parse_args() -> Args {
         } else if s == "-vv" {
             args.print_bytecode = true;
             args.print_exec = true;
+        } else if s == "--strict" {
+            args.strict = true;
+        } else if s == "--error-format=human" {
+            report.format = ErrorFormat::Human;
+        } else if s == "--error-format=json" {
+            report.format = ErrorFormat::Json;
+        } else if let Some(Ok(max_errors)) = s.strip_prefix("--max-errors=").map(str::parse) {
+            report.max_errors = max_errors;
         } else {
             eprintln!("Invalid argument {}.", s);
         }
     };
-    args
+    (args, report)
 }
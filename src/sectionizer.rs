@@ -1,5 +1,5 @@
 use crate::error::{Error, ErrorKind};
-use crate::tokenizer::{PlacedToken, Token, file_to_tokens};
+use crate::tokenizer::{PlacedToken, Span, Token, TokenStream, file_to_tokens, string_to_tokens};
 
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
@@ -7,7 +7,6 @@ use std::path::{Path, PathBuf};
 pub struct Section {
     pub tokens: Vec<PlacedToken>,
     pub path: PathBuf,
-    pub faulty: bool,
 }
 
 impl Section {
@@ -15,25 +14,59 @@ impl Section {
         Self {
             tokens: Vec::from(tokens),
             path,
-            faulty: false,
         }
     }
 }
 
+/// Splits the file at `path`, and all files it uses, into sections.
 pub fn sectionize(path: &Path) -> Result<Vec<Section>, Vec<Error>> {
-    let mut read_files = HashSet::new();
-    read_files.insert(path.to_path_buf());
     let tokens = file_to_tokens(path).map_err(|_| vec![
                  Error::new_nowhere(ErrorKind::FileNotFound(path.to_path_buf()), None)
     ])?;
-    let mut all_tokens = vec![(path.to_path_buf(), tokens)];
-    let mut sections = Vec::new();
-    let mut errors = Vec::new();
-
-    let mut i = 0;
-    while i < all_tokens.len() {
-        let (path, tokens) = all_tokens[i].clone();
-        i += 1;
+    sectionize_tokens(path, tokens)
+}
+
+/// Like [sectionize], but the source of the first file is given directly.
+/// The path is only used for error reporting and namespacing.
+pub fn sectionize_string(path: &Path, source: &str) -> Result<Vec<Section>, Vec<Error>> {
+    sectionize_tokens(path, string_to_tokens(source))
+}
+
+/// The path of the file pulled in with `use name` from the file at `importer`.
+/// Files are looked up relative to the file that uses them.
+pub fn use_path(importer: &Path, name: &str) -> PathBuf {
+    importer.with_file_name(format!("{}.sy", name))
+}
+
+fn sectionize_tokens(path: &Path, tokens: TokenStream) -> Result<Vec<Section>, Vec<Error>> {
+    let mut sectionizer = Sectionizer {
+        visiting: vec![path.to_path_buf()],
+        done: HashSet::new(),
+        sections: Vec::new(),
+        errors: Vec::new(),
+    };
+    sectionizer.file(path, tokens);
+
+    if sectionizer.errors.is_empty() {
+        Ok(sectionizer.sections)
+    } else {
+        Err(sectionizer.errors)
+    }
+}
+
+struct Sectionizer {
+    /// The chain of files currently being sectionized, used to find cycles.
+    visiting: Vec<PathBuf>,
+    done: HashSet<PathBuf>,
+    sections: Vec<Section>,
+    errors: Vec<Error>,
+}
+
+impl Sectionizer {
+    /// Sectionizes a file and everything it uses. The sections of a file
+    /// are placed after the sections of the files it uses.
+    fn file(&mut self, path: &Path, tokens: TokenStream) {
+        let mut sections = Vec::new();
         let mut last = 0;
         let mut curr = 0;
         while curr < tokens.len() {
@@ -47,25 +80,9 @@ pub fn sectionize(path: &Path) -> Result<Vec<Section>, Vec<Error>> {
                     },
 
                 (Some((Token::Use, _)),
-                 Some((Token::Identifier(use_file), _)),
-                 Some((Token::Newline, line))) => {
-                    let use_file: PathBuf = format!("{}.sy", use_file).into();
-                    if !read_files.contains(&use_file) {
-                        read_files.insert(use_file.clone());
-                        match file_to_tokens(&use_file) {
-                            Ok(tokens) => {
-                                all_tokens.push((use_file, tokens))
-                            }
-                            Err(_) => {
-                                errors.push(Error {
-                                    kind: ErrorKind::FileNotFound(use_file),
-                                    file: path.to_path_buf(),
-                                    line: *line,
-                                    message: None,
-                                });
-                            }
-                        }
-                    }
+                 Some((Token::Identifier(use_file), span)),
+                 Some((Token::Newline, _))) => {
+                    self.use_file(path, &use_path(path, use_file), *span);
                     true
                 },
 
@@ -80,7 +97,6 @@ pub fn sectionize(path: &Path) -> Result<Vec<Section>, Vec<Error>> {
                                 }
 
                                 Some((Token::RightBrace, _)) => {
-                                    curr += 1;
                                     blocks -= 1;
                                     if blocks <= 0 {
                                         break;
@@ -109,16 +125,54 @@ pub fn sectionize(path: &Path) -> Result<Vec<Section>, Vec<Error>> {
 
                 _ => false,
             } {
-                sections.push(Section::new(path.clone(), &tokens[last..curr]));
+                sections.push(Section::new(path.to_path_buf(), &tokens[last..curr]));
                 last = curr;
             }
             curr += 1;
         }
-        sections.push(Section::new(path.clone(), &tokens[last..curr]));
+        // An unclosed block runs to the end of the file.
+        sections.push(Section::new(path.to_path_buf(), &tokens[last..]));
+
+        self.sections.append(&mut sections);
+        self.done.insert(path.to_path_buf());
     }
-    if errors.is_empty() {
-        Ok(sections)
-    } else {
-        Err(errors)
+
+    fn use_file(&mut self, path: &Path, use_file: &Path, span: Span) {
+        if let Some(start) = self.visiting.iter().position(|p| p == use_file) {
+            let mut cycle = self.visiting[start..].to_vec();
+            cycle.push(use_file.to_path_buf());
+            self.errors.push(Error {
+                kind: ErrorKind::CyclicImport(cycle),
+                file: path.to_path_buf(),
+                span,
+                message: None,
+                notes: Vec::new(),
+                stack: Vec::new(),
+            });
+            return;
+        }
+        if self.done.contains(use_file) {
+            return;
+        }
+
+        match file_to_tokens(use_file) {
+            Ok(tokens) => {
+                self.visiting.push(use_file.to_path_buf());
+                self.file(use_file, tokens);
+                self.visiting.pop();
+            }
+            Err(_) => {
+                self.errors.push(Error {
+                    kind: ErrorKind::FileNotFound(use_file.to_path_buf()),
+                    file: path.to_path_buf(),
+                    span,
+                    message: None,
+                    notes: Vec::new(),
+                    stack: Vec::new(),
+                });
+                // Only report a missing file once.
+                self.done.insert(use_file.to_path_buf());
+            }
+        }
     }
 }
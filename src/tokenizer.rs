@@ -27,16 +27,22 @@ pub enum Token {
     Else,
     #[token("for")]
     For,
+    #[token("match")]
+    Match,
     #[token("break")]
     Break,
     #[token("continue")]
     Continue,
-    // #[token("in")]
-    // In,
+    #[token("in")]
+    In,
+    #[token("as")]
+    As,
     // #[token("loop")]
     // Loop,
     #[token("blob")]
     Blob,
+    #[token("enum")]
+    Enum,
 
     // TODO(ed): Remove
     #[token("print")]
@@ -44,6 +50,8 @@ pub enum Token {
 
     #[token("yield")]
     Yield,
+    #[token("spawn")]
+    Spawn,
 
     #[token("ret")]
     Ret,
@@ -154,7 +162,16 @@ pub enum Token {
     Error,
 }
 
-pub type PlacedToken = (Token, usize);
+/// Where in a file something was written. Columns count characters
+/// from 1 and the end is exclusive, 0 means the column isn't known.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub line: usize,
+    pub col_start: usize,
+    pub col_end: usize,
+}
+
+pub type PlacedToken = (Token, Span);
 pub type TokenStream = Vec<PlacedToken>;
 
 pub fn string_to_tokens(content: &str) -> TokenStream {
@@ -164,12 +181,15 @@ pub fn string_to_tokens(content: &str) -> TokenStream {
 
     let mut lined_tokens = Vec::new();
     let mut line: usize = 1;
-    for (c_idx, c) in content.chars().enumerate() {
+    let mut col: usize = 1;
+    for (c_idx, c) in content.char_indices() {
         if let Some((kind, t_range)) = placed_tokens.peek() {
             if t_range.start == c_idx {
-                let kind = kind.clone();
+                // Tokens spanning several lines are only marked on their first line.
+                let len = content[t_range.clone()].chars().take_while(|c| *c != '\n').count();
+                let span = Span { line, col_start: col, col_end: col + std::cmp::max(len, 1) };
+                lined_tokens.push((kind.clone(), span));
                 placed_tokens.next();
-                lined_tokens.push((kind, line));
             }
         } else {
             break;
@@ -177,6 +197,9 @@ pub fn string_to_tokens(content: &str) -> TokenStream {
 
         if c == '\n' {
             line += 1;
+            col = 1;
+        } else {
+            col += 1;
         }
     }
 
@@ -246,6 +269,15 @@ mod tests {
         assert_eq!(lex("\t1   \t  \t\t     2\t").len(), 2);
     }
 
+    #[test]
+    fn spans() {
+        let spans: Vec<_> = super::string_to_tokens("a := 1\n\tbc \"x\"")
+            .iter()
+            .map(|(_, span)| (span.line, span.col_start, span.col_end))
+            .collect();
+        assert_eq!(spans, vec![(1, 1, 2), (1, 3, 5), (1, 6, 7), (1, 7, 8), (2, 2, 4), (2, 5, 8)]);
+    }
+
     #[test]
     fn comment() {
         assert_eq!(lex("// a\n1").len(), 2);
@@ -0,0 +1,1552 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::ast::{self, BinOp, Expression, ExpressionKind, Function, Module, Pattern, Span, Statement, StatementKind, TypeKind, UnOp, VarKind};
+use crate::error::{Error, ErrorKind};
+use crate::sectionizer::use_path;
+use crate::{Blob, Enum, Op, RustFunction, Type, Value};
+
+/// The types of variables that were defined without writing out a type,
+/// by the file and the span of their name. Strict mode checks them too.
+pub(crate) type Inferred = HashMap<(PathBuf, Span), Type>;
+
+macro_rules! error {
+    ($checker:expr, $span:expr, $kind:expr) => {
+        $checker.error($span, $kind, None)
+    };
+    ($checker:expr, $span:expr, $kind:expr, $( $msg:expr ),+ ) => {
+        $checker.error($span, $kind, Some(format!($( $msg ),*)))
+    };
+}
+
+/// What a name written at the top of a file refers to.
+#[derive(Debug, Clone)]
+enum Name {
+    Global(usize),
+    Blob(Rc<Blob>),
+    Enum(Rc<Enum>),
+    Namespace(usize),
+}
+
+/// What an expression made up of names refers to,
+/// not everything that can be named is a value.
+enum Item {
+    Value(Type),
+    Namespace(usize),
+    Enum(Rc<Enum>),
+    /// An enum and the index of one of its variants.
+    Variant(Rc<Enum>, usize),
+    Extern(usize),
+}
+
+/// What's known at a point in the program.
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    /// Nullable locals that can't be nil here.
+    narrowed: HashSet<usize>,
+    /// False after something that never finishes, like a 'ret'.
+    reachable: bool,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            narrowed: HashSet::new(),
+            reachable: true,
+        }
+    }
+
+    fn unreachable() -> Self {
+        Self {
+            narrowed: HashSet::new(),
+            reachable: false,
+        }
+    }
+
+    /// The state where two paths through the program meet.
+    fn merge(self, other: State) -> State {
+        match (self.reachable, other.reachable) {
+            (_, false) => self,
+            (false, true) => other,
+            (true, true) => State {
+                narrowed: self.narrowed.intersection(&other.narrowed).cloned().collect(),
+                reachable: true,
+            },
+        }
+    }
+}
+
+/// The states that jump out of a loop, or back to its start.
+#[derive(Default)]
+struct Loop {
+    breaks: Vec<State>,
+    continues: Vec<State>,
+}
+
+/// A function body, checked once the body it's written in is done.
+struct Deferred<'a> {
+    function: &'a Function,
+    module: usize,
+    /// The variables visible where the function is written.
+    captured: Vec<(String, usize)>,
+    type_params: Vec<String>,
+    params: Vec<Type>,
+    ret: Type,
+    /// The blob of a method, which is `self` in the body.
+    receiver: Option<Rc<Blob>>,
+}
+
+struct Checker<'a> {
+    paths: Vec<PathBuf>,
+    namespaces: Vec<HashMap<String, Name>>,
+    functions: &'a [(String, RustFunction)],
+    /// The declared types of all variables.
+    vars: Vec<Type>,
+    /// The types of all methods, blobs refer to them by index.
+    methods: Vec<Type>,
+    /// Enums by id, with all their variants.
+    enums: HashMap<usize, Rc<Enum>>,
+    errors: Vec<Error>,
+
+    module: usize,
+    /// The variables in scope, innermost last.
+    stack: Vec<(String, usize)>,
+    /// Where the variables of the current function start in the stack.
+    frame_start: usize,
+    type_params: Vec<String>,
+    ret: Type,
+    state: State,
+    loops: Vec<Loop>,
+    deferred: Vec<Deferred<'a>>,
+    /// Variables defined without a type, by the module and the span of
+    /// their name. Loops are checked more than once, the last time wins.
+    inferred: HashMap<(usize, Span), usize>,
+}
+
+/// Unknown types and type parameters can be anything, so
+/// operators allow them.
+fn lenient(ty: &Type) -> bool {
+    matches!(ty, Type::Unknown | Type::Generic(_))
+}
+
+fn maybe_nil(ty: &Type) -> bool {
+    matches!(ty, Type::Union(_)) && ty.is_nullable()
+}
+
+fn without_nil(ty: &Type) -> Type {
+    match ty {
+        Type::Union(tys) => {
+            Type::union_of(tys.iter().filter(|ty| !matches!(ty, Type::Void)).cloned().collect())
+        }
+        ty => ty.clone(),
+    }
+}
+
+/// Applies the operator to all combinations of the members of the
+/// operands, the result is the union of the combinations that work.
+fn each_member(a: &Type, b: &Type, f: fn(&Type, &Type) -> Option<Type>) -> Option<Type> {
+    let mut results = HashSet::new();
+    for a in a.members().iter() {
+        for b in b.members().iter() {
+            if let Some(ty) = f(a, b) {
+                results.insert(ty);
+            }
+        }
+    }
+    if results.is_empty() {
+        None
+    } else {
+        Some(Type::union_of(results))
+    }
+}
+
+fn elementwise(a: &[Type], b: &[Type], f: fn(&Type, &Type) -> Option<Type>) -> Option<Type> {
+    if a.len() != b.len() {
+        return None;
+    }
+    a.iter().zip(b.iter()).map(|(a, b)| f(a, b)).collect::<Option<Vec<_>>>().map(Type::Tuple)
+}
+
+fn add(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        (Type::String, Type::String) => Some(Type::String),
+        _ => numeric(a, b, add),
+    }
+}
+
+fn arithmetic(a: &Type, b: &Type) -> Option<Type> {
+    numeric(a, b, arithmetic)
+}
+
+/// The rules all arithmetic share, `f` is used for the elements of tuples.
+fn numeric(a: &Type, b: &Type, f: fn(&Type, &Type) -> Option<Type>) -> Option<Type> {
+    match (a, b) {
+        (Type::Union(_), _) | (_, Type::Union(_)) => each_member(a, b, f),
+        _ if lenient(a) || lenient(b) => Some(Type::Unknown),
+        (Type::Int, Type::Int) => Some(Type::Int),
+        (Type::Float, Type::Float) => Some(Type::Float),
+        (Type::Tuple(a), Type::Tuple(b)) => elementwise(a, b, f),
+        _ => None,
+    }
+}
+
+fn compare(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Union(_), _) | (_, Type::Union(_)) => each_member(a, b, compare),
+        _ if lenient(a) || lenient(b) => Some(Type::Bool),
+        (Type::Int, Type::Int)
+            | (Type::Float, Type::Float)
+            | (Type::String, Type::String)
+            | (Type::Bool, Type::Bool) => Some(Type::Bool),
+        (Type::Tuple(a), Type::Tuple(b)) => elementwise(a, b, compare).map(|_| Type::Bool),
+        _ => None,
+    }
+}
+
+fn equal(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        _ if lenient(a) || lenient(b) => Some(Type::Bool),
+        (Type::Union(_), Type::Void) | (Type::Void, Type::Union(_)) => Some(Type::Bool),
+        (Type::Union(tys), other) | (other, Type::Union(tys)) => {
+            // Anything can be compared with nil, so nil
+            // can't be what makes the comparison valid.
+            tys.iter()
+                .filter(|ty| !matches!(ty, Type::Void))
+                .find_map(|ty| equal(ty, other))
+        }
+        (Type::Void, _) | (_, Type::Void) => Some(Type::Bool),
+        (Type::Int, Type::Int)
+            | (Type::Float, Type::Float)
+            | (Type::String, Type::String)
+            | (Type::Bool, Type::Bool) => Some(Type::Bool),
+        (Type::Tuple(a), Type::Tuple(b)) => elementwise(a, b, equal).map(|_| Type::Bool),
+        (Type::List(a), Type::List(b)) => equal(a, b),
+        (Type::Dict(ak, av), Type::Dict(bk, bv)) => equal(ak, bk).and(equal(av, bv)),
+        (Type::Enum(a), Type::Enum(b)) if a == b => Some(Type::Bool),
+        _ => None,
+    }
+}
+
+fn neg(ty: &Type) -> Option<Type> {
+    match ty {
+        Type::Union(_) => each_member(ty, &Type::Void, |ty, _| neg(ty)),
+        ty if lenient(ty) => Some(Type::Unknown),
+        Type::Int => Some(Type::Int),
+        Type::Float => Some(Type::Float),
+        Type::Tuple(tys) => tys.iter().map(neg).collect::<Option<Vec<_>>>().map(Type::Tuple),
+        _ => None,
+    }
+}
+
+/// Values of the type can be converted with `as`.
+fn castable(ty: &Type) -> bool {
+    match ty {
+        Type::Union(tys) => tys.iter().all(castable),
+        ty => lenient(ty) || matches!(ty, Type::Int | Type::Float | Type::String),
+    }
+}
+
+/// Values of the type can change after they're made, so
+/// they can't be the keys of a dict.
+fn mutable(ty: &Type) -> bool {
+    match ty {
+        Type::List(_) | Type::Dict(..) | Type::Instance(_) => true,
+        Type::Tuple(tys) => tys.iter().any(mutable),
+        Type::Union(tys) => tys.iter().any(mutable),
+        _ => false,
+    }
+}
+
+fn not(ty: &Type) -> Option<Type> {
+    match ty {
+        Type::Union(_) => each_member(ty, &Type::Void, |ty, _| not(ty)),
+        ty if lenient(ty) => Some(Type::Bool),
+        Type::Bool => Some(Type::Bool),
+        Type::Tuple(tys) => tys.iter().map(not).collect::<Option<Vec<_>>>().map(Type::Tuple),
+        _ => None,
+    }
+}
+
+fn literal_value(expr: &Expression) -> Value {
+    match &expr.kind {
+        ExpressionKind::Int(i) => Value::Int(*i),
+        ExpressionKind::Float(f) => Value::Float(*f),
+        ExpressionKind::Bool(b) => Value::Bool(*b),
+        ExpressionKind::Str(s) => Value::String(Rc::new(s.clone())),
+        _ => Value::Nil,
+    }
+}
+
+impl<'a> Checker<'a> {
+    fn new(modules: &[Module], functions: &'a [(String, RustFunction)]) -> Self {
+        Self {
+            paths: modules.iter().map(|module| module.path.clone()).collect(),
+            namespaces: vec![HashMap::new(); modules.len()],
+            functions,
+            vars: Vec::new(),
+            methods: Vec::new(),
+            enums: HashMap::new(),
+            errors: Vec::new(),
+
+            module: 0,
+            stack: Vec::new(),
+            frame_start: 0,
+            type_params: Vec::new(),
+            ret: Type::Void,
+            state: State::new(),
+            loops: Vec::new(),
+            deferred: Vec::new(),
+            inferred: HashMap::new(),
+        }
+    }
+
+    fn error(&mut self, span: Span, kind: ErrorKind, message: Option<String>) {
+        let notes = kind.notes();
+        self.errors.push(Error {
+            kind,
+            file: self.paths[self.module].clone(),
+            span,
+            message,
+            notes,
+            stack: Vec::new(),
+        });
+    }
+
+    fn declare(&mut self, name: &str, ty: Type) {
+        self.stack.push((String::from(name), self.vars.len()));
+        self.vars.push(ty);
+    }
+
+    fn declare_global(&mut self, name: &str, ty: Type) {
+        self.namespaces[self.module].insert(String::from(name), Name::Global(self.vars.len()));
+        self.vars.push(ty);
+    }
+
+    /// The type of a variable where it's read.
+    fn var_type(&self, id: usize) -> Type {
+        if self.state.narrowed.contains(&id) {
+            without_nil(&self.vars[id])
+        } else {
+            self.vars[id].clone()
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Item {
+        let names = &self.namespaces[self.module];
+        if let Some(Name::Namespace(module)) = names.get(name) {
+            return Item::Namespace(*module);
+        }
+        if let Some(slot) = self.functions.iter().position(|(function, _)| function == name) {
+            return Item::Extern(slot);
+        }
+        if let Some((_, id)) = self.stack.iter().rev().find(|(var, _)| var == name) {
+            return Item::Value(self.var_type(*id));
+        }
+        self.named(self.module, name)
+    }
+
+    /// Looks up a name at the top of the given file.
+    fn named(&self, module: usize, name: &str) -> Item {
+        match self.namespaces[module].get(name) {
+            Some(Name::Global(id)) => Item::Value(self.vars[*id].clone()),
+            Some(Name::Blob(blob)) => Item::Value(Type::Blob(Rc::clone(blob))),
+            Some(Name::Enum(e)) => Item::Enum(Rc::clone(e)),
+            Some(Name::Namespace(module)) => Item::Namespace(*module),
+            None => Item::Value(Type::Unknown),
+        }
+    }
+
+    /// The local variable the expression reads, if it's declared in the
+    /// current function. Only those can be narrowed.
+    fn local(&self, expr: &Expression) -> Option<usize> {
+        let name = match &expr.kind {
+            ExpressionKind::Read(ident) => &ident.name,
+            _ => return None,
+        };
+        if matches!(self.namespaces[self.module].get(name), Some(Name::Namespace(_)))
+            || self.functions.iter().any(|(function, _)| function == name)
+        {
+            return None;
+        }
+        let pos = self.stack.iter().rposition(|(var, _)| var == name)?;
+        if pos < self.frame_start {
+            None
+        } else {
+            Some(self.stack[pos].1)
+        }
+    }
+
+    /// The variable the expression reads, wherever it's declared.
+    fn variable(&self, expr: &Expression) -> Option<usize> {
+        match &expr.kind {
+            ExpressionKind::Read(ident) => {
+                let name = &ident.name;
+                if matches!(self.namespaces[self.module].get(name), Some(Name::Namespace(_)))
+                    || self.functions.iter().any(|(function, _)| function == name)
+                {
+                    return None;
+                }
+                if let Some((_, id)) = self.stack.iter().rev().find(|(var, _)| var == name) {
+                    return Some(*id);
+                }
+                match self.namespaces[self.module].get(name) {
+                    Some(Name::Global(id)) => Some(*id),
+                    _ => None,
+                }
+            }
+            ExpressionKind::Access(inner, field) => {
+                let module = self.namespace_of(inner)?;
+                match self.namespaces[module].get(&field.name) {
+                    Some(Name::Global(id)) => Some(*id),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The file the expression names, if it's a path of namespaces.
+    fn namespace_of(&self, expr: &Expression) -> Option<usize> {
+        match &expr.kind {
+            ExpressionKind::Read(ident) => match self.namespaces[self.module].get(&ident.name) {
+                Some(Name::Namespace(module)) => Some(*module),
+                _ => None,
+            },
+            ExpressionKind::Access(inner, field) => {
+                let module = self.namespace_of(inner)?;
+                match self.namespaces[module].get(&field.name) {
+                    Some(Name::Namespace(module)) => Some(*module),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn resolve_type(&self, ty: &ast::Type) -> Type {
+        match &ty.kind {
+            TypeKind::Resolved(ty) => ty.clone(),
+            TypeKind::UserDefined(path) => {
+                if let [ident] = path.as_slice() {
+                    if self.type_params.contains(&ident.name) {
+                        return Type::Generic(ident.name.clone());
+                    }
+                }
+                let mut module = self.module;
+                for (i, ident) in path.iter().enumerate() {
+                    let last = i + 1 == path.len();
+                    match self.namespaces[module].get(&ident.name) {
+                        Some(Name::Namespace(next)) if !last => module = *next,
+                        Some(Name::Blob(blob)) if last => return Type::Instance(Rc::clone(blob)),
+                        Some(Name::Enum(e)) if last => return Type::Enum(Rc::clone(e)),
+                        _ => break,
+                    }
+                }
+                Type::Unknown
+            }
+            TypeKind::Union(tys) => {
+                let tys: HashSet<_> = tys.iter().map(|ty| self.resolve_type(ty)).collect();
+                if tys.len() == 1 {
+                    tys.into_iter().next().unwrap()
+                } else {
+                    Type::Union(tys)
+                }
+            }
+            TypeKind::Fn(params, ret) => {
+                Type::Function(params.iter().map(|ty| self.resolve_type(ty)).collect(),
+                               Box::new(self.resolve_type(ret)))
+            }
+            TypeKind::Tuple(tys) => Type::Tuple(tys.iter().map(|ty| self.resolve_type(ty)).collect()),
+            TypeKind::List(ty) => Type::List(Box::new(self.resolve_type(ty))),
+            TypeKind::Dict(k, v) => {
+                Type::Dict(Box::new(self.resolve_type(k)), Box::new(self.resolve_type(v)))
+            }
+        }
+    }
+
+    /// Checks that no dict written in the type has a key that can change.
+    fn check_keys(&mut self, ty: &ast::Type) {
+        match &ty.kind {
+            TypeKind::Resolved(_) | TypeKind::UserDefined(_) => {}
+            TypeKind::Union(tys) | TypeKind::Tuple(tys) => {
+                for ty in tys.iter() {
+                    self.check_keys(ty);
+                }
+            }
+            TypeKind::Fn(params, ret) => {
+                for ty in params.iter() {
+                    self.check_keys(ty);
+                }
+                self.check_keys(ret);
+            }
+            TypeKind::List(ty) => self.check_keys(ty),
+            TypeKind::Dict(k, v) => {
+                let key = self.resolve_type(k);
+                if mutable(&key) {
+                    error!(self, k.span, ErrorKind::MutableKey(key));
+                }
+                self.check_keys(k);
+                self.check_keys(v);
+            }
+        }
+    }
+
+    /// Resolves a type written in the code, that isn't part
+    /// of a function signature.
+    fn written_type(&mut self, ty: &ast::Type) -> Type {
+        self.check_keys(ty);
+        self.resolve_type(ty)
+    }
+
+    /// Resolves the signature of a function, together with the
+    /// type parameters visible inside it.
+    fn signature(&mut self, function: &Function) -> (Vec<String>, Vec<Type>, Type) {
+        let mut type_params = self.type_params.clone();
+        type_params.extend(function.type_params.iter().map(|param| param.name.clone()));
+        let outer = std::mem::replace(&mut self.type_params, type_params);
+        let params = function.params.iter().map(|(_, ty)| self.resolve_type(ty)).collect();
+        let ret = self.resolve_type(&function.ret);
+        let type_params = std::mem::replace(&mut self.type_params, outer);
+        (type_params, params, ret)
+    }
+
+    /// Declares everything at the top of the files that can be
+    /// used before it's written.
+    fn declare_names(&mut self, modules: &'a [Module]) {
+        // Ids are given out like the compiler does, all blobs before all
+        // enums, so the inferred types match the values at runtime.
+        let blobs = modules.iter()
+            .flat_map(|file| file.statements.iter())
+            .filter(|statement| matches!(statement.kind, StatementKind::Blob { .. }))
+            .count();
+        let mut blob_ids = 0..;
+        let mut enum_ids = blobs..;
+        for (module, file) in modules.iter().enumerate() {
+            for statement in file.statements.iter() {
+                let (ident, name) = match &statement.kind {
+                    StatementKind::Use { file: ident } => {
+                        let path = use_path(&file.path, &ident.name);
+                        match self.paths.iter().position(|other| *other == path) {
+                            Some(other) => (ident, Name::Namespace(other)),
+                            None => continue,
+                        }
+                    }
+                    StatementKind::Blob { name, .. } => {
+                        let blob = Blob::new(blob_ids.next().unwrap(), &name.name);
+                        (name, Name::Blob(Rc::new(blob)))
+                    }
+                    StatementKind::Enum { name, .. } => {
+                        let e = Enum::new(enum_ids.next().unwrap(), &name.name);
+                        (name, Name::Enum(Rc::new(e)))
+                    }
+                    _ => continue,
+                };
+                self.namespaces[module].insert(ident.name.clone(), name);
+            }
+        }
+
+        // The variants are added once all enums are declared, so
+        // payloads can be enums declared later.
+        for (module, file) in modules.iter().enumerate() {
+            self.module = module;
+            for statement in file.statements.iter() {
+                if let StatementKind::Enum { name, variants } = &statement.kind {
+                    let id = match self.namespaces[module].get(&name.name) {
+                        Some(Name::Enum(e)) => e.id,
+                        _ => continue,
+                    };
+                    let mut e = Enum::new(id, &name.name);
+                    for (variant, payload) in variants.iter() {
+                        let payload = payload.as_ref().map(|ty| self.written_type(ty));
+                        // Duplicates are reported by the compiler.
+                        let _ = e.add_variant(&variant.name, payload);
+                    }
+                    let e = Rc::new(e);
+                    self.enums.insert(id, Rc::clone(&e));
+                    self.namespaces[module].insert(name.name.clone(), Name::Enum(e));
+                }
+            }
+        }
+
+        for (module, file) in modules.iter().enumerate() {
+            self.module = module;
+            for statement in file.statements.iter() {
+                match &statement.kind {
+                    StatementKind::Blob { name, fields, methods } => {
+                        let blob = match self.namespaces[module].get(&name.name) {
+                            Some(Name::Blob(blob)) => Rc::clone(blob),
+                            _ => continue,
+                        };
+                        for field in fields.iter() {
+                            let ty = self.written_type(&field.ty);
+                            if let Some(default) = &field.default {
+                                let value = literal_value(default);
+                                let given = Type::from(&value);
+                                if !ty.fits(&given) {
+                                    error!(self, default.span, ErrorKind::TypeMismatch(ty.clone(), given),
+                                           "Default value for field '{}' has the wrong type", field.name.name);
+                                }
+                                blob.defaults.borrow_mut().insert(field.name.name.clone(), value);
+                            }
+                            let _ = blob.add_field(&field.name.name, ty);
+                        }
+                        for (method, function) in methods.iter() {
+                            let (_, mut params, ret) = self.signature(function);
+                            params.insert(0, Type::Instance(Rc::clone(&blob)));
+                            let slot = self.methods.len();
+                            self.methods.push(Type::Function(params, Box::new(ret)));
+                            let _ = blob.add_method(&method.name, slot);
+                        }
+                    }
+
+                    // Functions can be called before they're written.
+                    StatementKind::Definition {
+                        ident,
+                        kind: VarKind::Const,
+                        ty: None,
+                        value: Expression { kind: ExpressionKind::Function(function), .. },
+                    } => {
+                        let (_, params, ret) = self.signature(function);
+                        self.declare_global(&ident.name, Type::Function(params, Box::new(ret)));
+                    }
+
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Checks the values of everything at the top of the files, in order.
+    fn globals(&mut self, modules: &'a [Module]) {
+        for (module, file) in modules.iter().enumerate() {
+            self.module = module;
+            for statement in file.statements.iter() {
+                match &statement.kind {
+                    StatementKind::Definition { ident, ty: declared_ty, value, .. } => {
+                        let declared = matches!(self.namespaces[module].get(&ident.name),
+                                                Some(Name::Global(_)));
+                        let ty = self.definition(statement.span, declared_ty.as_ref(), value);
+                        if !declared {
+                            if declared_ty.is_none() {
+                                self.inferred.insert((module, ident.span), self.vars.len());
+                            }
+                            self.declare_global(&ident.name, ty);
+                        }
+                    }
+                    StatementKind::Blob { name, methods, .. } => {
+                        let blob = match self.namespaces[module].get(&name.name) {
+                            Some(Name::Blob(blob)) => Rc::clone(blob),
+                            _ => continue,
+                        };
+                        for (_, function) in methods.iter() {
+                            let (type_params, params, ret) = self.signature(function);
+                            self.deferred.push(Deferred {
+                                function,
+                                module,
+                                captured: Vec::new(),
+                                type_params,
+                                params,
+                                ret,
+                                receiver: Some(Rc::clone(&blob)),
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn function_body(&mut self, deferred: Deferred<'a>) {
+        self.module = deferred.module;
+        self.stack = deferred.captured;
+        self.frame_start = self.stack.len();
+        self.type_params = deferred.type_params;
+        self.ret = deferred.ret;
+        self.state = State::new();
+        self.loops.clear();
+
+        let function = deferred.function;
+        if let Some(blob) = deferred.receiver {
+            self.declare("self", Type::Instance(blob));
+        }
+        // Signatures are resolved more than once, but the bodies
+        // are only checked once, so this is where they're checked.
+        for (_, ty) in function.params.iter() {
+            self.check_keys(ty);
+        }
+        self.check_keys(&function.ret);
+        for ((name, _), ty) in function.params.iter().zip(deferred.params.into_iter()) {
+            self.declare(&name.name, ty);
+        }
+        self.block(&function.body);
+
+        if self.state.reachable {
+            // Falling out of a function returns nil.
+            let span = function.body.last().map(|statement| statement.span).unwrap_or(function.span);
+            self.check_return(span, Type::Void);
+        }
+
+        // Functions written inside this one are checked right after it.
+        let nested = std::mem::take(&mut self.deferred);
+        for deferred in nested {
+            self.function_body(deferred);
+        }
+    }
+
+    fn check_return(&mut self, span: Span, given: Type) {
+        if given != self.ret && !self.ret.fits(&given) {
+            error!(self, span, ErrorKind::TypeMismatch(self.ret.clone(), given),
+                   "Value does not match return type");
+        }
+        self.state.reachable = false;
+    }
+
+    /// The type of a new variable.
+    fn definition(&mut self, span: Span, declared: Option<&ast::Type>, value: &'a Expression) -> Type {
+        let given = self.expression(value);
+        match declared.map(|ty| self.written_type(ty)) {
+            None | Some(Type::Unknown) => given,
+            Some(ty) if ty.fits(&given) => ty,
+            Some(ty) => {
+                error!(self, span, ErrorKind::TypeMismatch(ty.clone(), given),
+                       "Cannot assign mismatching types");
+                ty
+            }
+        }
+    }
+
+    fn boolean(&mut self, span: Span, ty: Type) {
+        if !matches!(ty, Type::Bool | Type::Unknown) {
+            error!(self, span, ErrorKind::TypeError(Op::JmpFalse(0), vec![ty]),
+                   "Expected a bool");
+        }
+    }
+
+    fn block(&mut self, statements: &'a [Statement]) {
+        let len = self.stack.len();
+        for statement in statements.iter() {
+            self.statement(statement);
+        }
+        self.stack.truncate(len);
+    }
+
+    fn statement(&mut self, statement: &'a Statement) {
+        let span = statement.span;
+        match &statement.kind {
+            StatementKind::Use { .. }
+                | StatementKind::Blob { .. }
+                | StatementKind::Enum { .. } => {}
+
+            StatementKind::Definition { ident, ty: declared, value, .. } => {
+                let ty = self.definition(span, declared.as_ref(), value);
+                if declared.is_none() {
+                    self.inferred.insert((self.module, ident.span), self.vars.len());
+                }
+                self.declare(&ident.name, ty);
+            }
+
+            StatementKind::Assignment { op, target, value } => {
+                self.assignment(span, *op, target, value);
+            }
+
+            StatementKind::If { condition, pass, fail } => {
+                let (ty, when_true, when_false) = self.condition(condition);
+                self.boolean(condition.span, ty);
+
+                let entry = self.state.clone();
+                self.state.narrowed.extend(when_true);
+                self.block(pass);
+
+                let passed = std::mem::replace(&mut self.state, entry);
+                self.state.narrowed.extend(when_false);
+                self.block(fail);
+
+                let failed = std::mem::replace(&mut self.state, State::new());
+                self.state = passed.merge(failed);
+            }
+
+            StatementKind::For { init, condition, step, body } => {
+                let len = self.stack.len();
+                if let Some(init) = init {
+                    self.statement(init);
+                }
+                self.check_loop(Some(condition), body, Some(step), None);
+                self.stack.truncate(len);
+            }
+
+            StatementKind::ForIn { name, iterable, body } => {
+                let element = match self.expression(iterable) {
+                    Type::List(ty) => *ty,
+                    Type::Tuple(tys) => Type::union_of(tys.into_iter().collect()),
+                    Type::String => Type::String,
+                    Type::Range => Type::Int,
+                    Type::Unknown => Type::Unknown,
+                    ty => {
+                        error!(self, iterable.span, ErrorKind::TypeError(Op::IterNext(0), vec![ty]),
+                               "Cannot iterate over this type");
+                        Type::Unknown
+                    }
+                };
+                self.check_loop(None, body, None, Some((&name.name, element)));
+            }
+
+            StatementKind::Loop { condition, body } => {
+                self.check_loop(condition.as_ref(), body, None, None);
+            }
+
+            StatementKind::Match { value, arms, fallback } => {
+                self.match_statement(span, value, arms, fallback.as_deref());
+            }
+
+            StatementKind::Break => {
+                let state = self.state.clone();
+                if let Some(jumps) = self.loops.last_mut() {
+                    jumps.breaks.push(state);
+                }
+                self.state.reachable = false;
+            }
+
+            StatementKind::Continue => {
+                let state = self.state.clone();
+                if let Some(jumps) = self.loops.last_mut() {
+                    jumps.continues.push(state);
+                }
+                self.state.reachable = false;
+            }
+
+            StatementKind::Ret { value } => {
+                let given = match value {
+                    Some(value) => self.expression(value),
+                    None => Type::Void,
+                };
+                self.check_return(span, given);
+            }
+
+            StatementKind::Yield { value } => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+
+            StatementKind::Block { statements } => {
+                self.block(statements);
+            }
+
+            StatementKind::Print { value }
+                | StatementKind::StatementExpression { value } => {
+                self.expression(value);
+            }
+
+            StatementKind::Unreachable => {
+                self.state.reachable = false;
+            }
+        }
+    }
+
+    /// Checks a loop, where the start of the loop can be reached both
+    /// before it and from the end of its body. The loop is checked until
+    /// what's known at its start stops changing, and the errors are only
+    /// kept from the last time.
+    fn check_loop(
+        &mut self,
+        condition: Option<&'a Expression>,
+        body: &'a [Statement],
+        step: Option<&'a Statement>,
+        var: Option<(&str, Type)>,
+    ) {
+        let mut head = self.state.clone();
+        loop {
+            let errors = self.errors.len();
+            let deferred = self.deferred.len();
+            let (end, _) = self.loop_pass(&head, condition, body, step, &var);
+            self.errors.truncate(errors);
+            self.deferred.truncate(deferred);
+
+            let next = head.clone().merge(end);
+            if next == head {
+                break;
+            }
+            head = next;
+        }
+        let (_, exit) = self.loop_pass(&head, condition, body, step, &var);
+        self.state = exit;
+    }
+
+    /// Checks one pass through a loop. Returns the state at the end
+    /// of the pass and the state after the loop.
+    fn loop_pass(
+        &mut self,
+        head: &State,
+        condition: Option<&'a Expression>,
+        body: &'a [Statement],
+        step: Option<&'a Statement>,
+        var: &Option<(&str, Type)>,
+    ) -> (State, State) {
+        self.state = head.clone();
+        let len = self.stack.len();
+
+        let mut exit = State::unreachable();
+        if let Some(condition) = condition {
+            let (ty, when_true, when_false) = self.condition(condition);
+            self.boolean(condition.span, ty);
+            exit = self.state.clone();
+            exit.narrowed.extend(when_false);
+            self.state.narrowed.extend(when_true);
+        } else if var.is_some() {
+            exit = self.state.clone();
+        }
+        if let Some((name, ty)) = var {
+            self.declare(name, ty.clone());
+        }
+
+        self.loops.push(Loop::default());
+        self.block(body);
+        let jumps = self.loops.pop().unwrap();
+
+        let mut end = std::mem::replace(&mut self.state, State::new());
+        for state in jumps.continues {
+            end = end.merge(state);
+        }
+        if let Some(step) = step {
+            self.state = end;
+            self.statement(step);
+            end = std::mem::replace(&mut self.state, State::new());
+        }
+        for state in jumps.breaks {
+            exit = exit.merge(state);
+        }
+
+        self.stack.truncate(len);
+        (end, exit)
+    }
+
+    fn match_statement(
+        &mut self,
+        span: Span,
+        value: &'a Expression,
+        arms: &'a [ast::MatchArm],
+        fallback: Option<&'a [Statement]>,
+    ) {
+        let ty = self.expression(value);
+        let entry = self.state.clone();
+        let mut exit = State::unreachable();
+
+        // The types the arms match, and the variants of each enum.
+        let mut covered = Vec::new();
+        let mut variants: HashMap<usize, HashSet<usize>> = HashMap::new();
+        for arm in arms.iter() {
+            self.state = entry.clone();
+            let len = self.stack.len();
+            match &arm.pattern {
+                Pattern::Type(name, arm_ty) => {
+                    let arm_ty = self.written_type(arm_ty);
+                    self.narrow(arm.span, &ty, &arm_ty);
+                    covered.extend(arm_ty.members());
+                    self.declare(&name.name, arm_ty);
+                }
+                Pattern::Literal(literal) => {
+                    let literal_ty = self.expression(literal);
+                    self.narrow(arm.span, &ty, &literal_ty);
+                    if matches!(literal_ty, Type::Void) {
+                        covered.push(Type::Void);
+                    }
+                }
+                Pattern::Variant { ty: name, variant, binding } => {
+                    if let Item::Enum(e) = self.lookup(&name.name) {
+                        let enum_ty = Type::Enum(Rc::clone(&e));
+                        if !matches!(ty, Type::Unknown) && !ty.members().contains(&enum_ty) {
+                            error!(self, arm.span, ErrorKind::TypeMismatch(ty.clone(), enum_ty),
+                                   "The value can never be this variant");
+                        }
+                        if let Some(index) = e.variant(&variant.name) {
+                            variants.entry(e.id).or_default().insert(index);
+                            if let Some(binding) = binding {
+                                let payload = e.variants[index].1.clone().unwrap_or(Type::Void);
+                                self.declare(&binding.name, payload);
+                            }
+                        }
+                    }
+                }
+            }
+            self.block(&arm.body);
+            self.stack.truncate(len);
+            exit = exit.merge(std::mem::replace(&mut self.state, State::new()));
+        }
+
+        for (id, matched) in variants {
+            let e = &self.enums[&id];
+            if matched.len() == e.variants.len() {
+                covered.push(Type::Enum(Rc::clone(e)));
+            }
+        }
+
+        match fallback {
+            Some(body) => {
+                self.state = entry;
+                self.block(body);
+                exit = exit.merge(std::mem::replace(&mut self.state, State::new()));
+            }
+            None => {
+                let missing: Vec<_> = ty.members()
+                    .into_iter()
+                    .filter(|ty| !matches!(ty, Type::Unknown) && !covered.contains(ty))
+                    .collect();
+                if !missing.is_empty() {
+                    error!(self, span, ErrorKind::NonExhaustiveMatch(missing));
+                }
+                if arms.is_empty() {
+                    exit = entry;
+                }
+            }
+        }
+        self.state = exit;
+    }
+
+    /// Checks that the value can have the type of a match-arm.
+    fn narrow(&mut self, span: Span, ty: &Type, arm_ty: &Type) {
+        if matches!(ty, Type::Unknown) {
+            return;
+        }
+        let possible = ty.members();
+        if !arm_ty.members().iter().all(|ty| possible.contains(ty)) {
+            error!(self, span, ErrorKind::TypeMismatch(ty.clone(), arm_ty.clone()),
+                   "The value can never be of this type");
+        }
+    }
+
+    fn assignment(&mut self, span: Span, op: Option<BinOp>, target: &'a Expression, value: &'a Expression) {
+        if let Some(id) = self.variable(target) {
+            let current = op.map(|_| self.expression(target));
+            let mut ty = self.expression(value);
+            if let (Some(op), Some(current)) = (op, current) {
+                ty = self.binary(span, op, current, ty);
+            }
+
+            let var = self.vars[id].clone();
+            if !matches!(ty, Type::Unknown) && var != ty && !var.fits(&ty) {
+                error!(self, span, ErrorKind::TypeMismatch(var.clone(), ty),
+                       "Cannot assign to different type");
+            } else if self.local(target) == Some(id) && var.is_nullable() {
+                if ty.is_nullable() {
+                    self.state.narrowed.remove(&id);
+                } else if !matches!(ty, Type::Unknown) {
+                    self.state.narrowed.insert(id);
+                }
+            }
+            return;
+        }
+
+        match &target.kind {
+            ExpressionKind::Access(object, field) => {
+                let object = self.expression(object);
+                if maybe_nil(&object) {
+                    error!(self, span, ErrorKind::MaybeNil(object));
+                    self.expression(value);
+                    return;
+                }
+                let current = op.map(|_| self.field(target.span, &object, field));
+                let mut ty = self.expression(value);
+                if let (Some(op), Some(current)) = (op, current) {
+                    ty = self.binary(span, op, current, ty);
+                }
+
+                match &object {
+                    Type::Instance(blob) if blob.fields.borrow().contains_key(&field.name) => {
+                        let field_ty = blob.fields.borrow()[&field.name].1.clone();
+                        if !matches!(ty, Type::Unknown) && field_ty != ty && !field_ty.fits(&ty) {
+                            error!(self, span, ErrorKind::TypeMismatch(field_ty, ty),
+                                   "Types of field and variable do not match");
+                        }
+                    }
+                    Type::Unknown => {}
+                    _ => {
+                        error!(self, span, ErrorKind::UnknownField(Value::from(&object), field.name.clone()));
+                    }
+                }
+            }
+
+            ExpressionKind::Index(container, index) => {
+                let container_ty = self.expression(container);
+                let index_ty = self.expression(index);
+                let current = op.map(|_| self.index(target.span, container_ty.clone(), index_ty.clone(), index));
+                let mut ty = self.expression(value);
+                if let (Some(op), Some(current)) = (op, current) {
+                    ty = self.binary(span, op, current, ty);
+                }
+
+                match (&container_ty, &index_ty) {
+                    (Type::List(element), index) if index.fits(&Type::Int) => {
+                        if !matches!(**element, Type::Unknown) && !element.fits(&ty) {
+                            error!(self, span, ErrorKind::TypeMismatch(*element.clone(), ty),
+                                   "Cannot assign mismatching types");
+                        }
+                    }
+                    (Type::Dict(key, element), index) => {
+                        if !key.fits(index) {
+                            error!(self, span, ErrorKind::TypeMismatch(*key.clone(), index.clone()),
+                                   "Dict key has the wrong type");
+                        }
+                        if !element.fits(&ty) {
+                            error!(self, span, ErrorKind::TypeMismatch(*element.clone(), ty),
+                                   "Cannot assign mismatching types");
+                        }
+                    }
+                    (Type::Unknown, _) => {}
+                    _ => {
+                        error!(self, span, ErrorKind::TypeError(Op::AssignIndex, vec![container_ty, index_ty, ty]),
+                               "Cannot assign to an index of this type");
+                    }
+                }
+            }
+
+            // Constants can't be assigned to, which the compiler reports.
+            _ => {
+                self.expression(value);
+            }
+        }
+    }
+
+    /// Checks an expression that decides which way the program goes.
+    /// Also returns the locals that can't be nil if it's true, and
+    /// the ones that can't be nil if it's false.
+    fn condition(&mut self, expr: &'a Expression) -> (Type, HashSet<usize>, HashSet<usize>) {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Unary(UnOp::Not, value) => {
+                let (ty, when_true, when_false) = self.condition(value);
+                let ty = match not(&ty) {
+                    Some(ty) => ty,
+                    None => {
+                        error!(self, span, ErrorKind::TypeError(Op::Not, vec![ty]));
+                        Type::Unknown
+                    }
+                };
+                (ty, when_false, when_true)
+            }
+
+            ExpressionKind::Binary(op @ (BinOp::And | BinOp::Or), a, b) => {
+                let (a_ty, a_true, a_false) = self.condition(a);
+                // The right side is only evaluated if the left side
+                // didn't decide the answer.
+                let entry = self.state.clone();
+                if *op == BinOp::And {
+                    self.state.narrowed.extend(a_true.iter().cloned());
+                } else {
+                    self.state.narrowed.extend(a_false.iter().cloned());
+                }
+                let (b_ty, b_true, b_false) = self.condition(b);
+                self.state = entry;
+
+                let ty = self.binary(span, *op, a_ty, b_ty);
+                if *op == BinOp::And {
+                    let when_false = a_true.union(&b_false).cloned().collect::<HashSet<_>>();
+                    let when_false = a_false.intersection(&when_false).cloned().collect();
+                    (ty, a_true.union(&b_true).cloned().collect(), when_false)
+                } else {
+                    let when_true = a_false.union(&b_true).cloned().collect::<HashSet<_>>();
+                    let when_true = a_true.intersection(&when_true).cloned().collect();
+                    (ty, when_true, a_false.union(&b_false).cloned().collect())
+                }
+            }
+
+            ExpressionKind::Binary(op @ (BinOp::Equal | BinOp::NotEqual), a, b) => {
+                let a_ty = self.expression(a);
+                let b_ty = self.expression(b);
+                let ty = self.binary(span, *op, a_ty, b_ty);
+
+                let checked = match (&a.kind, &b.kind) {
+                    (_, ExpressionKind::Nil) => self.local(a),
+                    (ExpressionKind::Nil, _) => self.local(b),
+                    _ => None,
+                };
+                let mut facts = HashSet::new();
+                if let Some(id) = checked.filter(|id| self.vars[*id].is_nullable()) {
+                    facts.insert(id);
+                }
+                if *op == BinOp::NotEqual {
+                    (ty, facts, HashSet::new())
+                } else {
+                    (ty, HashSet::new(), facts)
+                }
+            }
+
+            ExpressionKind::Binary(op, a, b) => {
+                let a = self.expression(a);
+                let b = self.expression(b);
+                (self.binary(span, *op, a, b), HashSet::new(), HashSet::new())
+            }
+
+            _ => (self.expression(expr), HashSet::new(), HashSet::new()),
+        }
+    }
+
+    fn binary(&mut self, span: Span, op: BinOp, a: Type, b: Type) -> Type {
+        let (code, result) = match op {
+            BinOp::Equal | BinOp::NotEqual | BinOp::AssertEqual => (Op::Equal, equal(&a, &b)),
+            BinOp::And | BinOp::Or => {
+                let code = if op == BinOp::And { Op::And } else { Op::Or };
+                let valid = |ty: &Type| matches!(ty, Type::Bool) || lenient(ty);
+                (code, if valid(&a) && valid(&b) { Some(Type::Bool) } else { None })
+            }
+            _ => {
+                // Arithmetic and ordering need values that can't be nil.
+                if let Some(ty) = [&a, &b].iter().find(|ty| maybe_nil(ty)) {
+                    error!(self, span, ErrorKind::MaybeNil((*ty).clone()));
+                    return Type::Unknown;
+                }
+                match op {
+                    BinOp::Add => (Op::Add, add(&a, &b)),
+                    BinOp::Sub => (Op::Sub, arithmetic(&a, &b)),
+                    BinOp::Mul => (Op::Mul, arithmetic(&a, &b)),
+                    BinOp::Div => (Op::Div, arithmetic(&a, &b)),
+                    BinOp::Less | BinOp::GreaterEqual => (Op::Less, compare(&a, &b)),
+                    _ => (Op::Greater, compare(&a, &b)),
+                }
+            }
+        };
+        match result {
+            Some(ty) => ty,
+            None => {
+                error!(self, span, ErrorKind::TypeError(code, vec![a, b]));
+                Type::Unknown
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &'a Expression) -> Type {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Read(_) | ExpressionKind::Access(..) => match self.item(expr) {
+                Item::Value(ty) => ty,
+                Item::Variant(e, _) => Type::Enum(e),
+                Item::Namespace(_) | Item::Enum(_) | Item::Extern(_) => Type::Unknown,
+            },
+
+            ExpressionKind::Index(container, index) => {
+                let container_ty = self.expression(container);
+                let index_ty = self.expression(index);
+                self.index(span, container_ty, index_ty, index)
+            }
+
+            ExpressionKind::Call(callee, args) => self.call(span, callee, args),
+
+            ExpressionKind::Instance { blob, fields } => self.instance(span, blob, fields),
+
+            ExpressionKind::Spawn(callee, args) => {
+                let ty = self.expression(callee);
+                let args: Vec<_> = args.iter().map(|arg| self.expression(arg)).collect();
+                match &ty {
+                    Type::Function(params, _) => {
+                        let mut bindings = HashMap::new();
+                        let fits = params.len() == args.len()
+                            && params.iter().zip(args.iter()).all(|(a, b)| a.unify(b, &mut bindings));
+                        if !fits {
+                            error!(self, span, ErrorKind::ArgumentType(params.clone(), args));
+                        }
+                    }
+                    Type::Unknown => {}
+                    _ => {
+                        error!(self, span, ErrorKind::TypeError(Op::Spawn(args.len()), vec![ty.clone()]),
+                               "Only functions can be spawned");
+                    }
+                }
+                Type::Coroutine
+            }
+
+            ExpressionKind::Function(function) => {
+                let (type_params, params, ret) = self.signature(function);
+                self.deferred.push(Deferred {
+                    function,
+                    module: self.module,
+                    captured: self.stack.clone(),
+                    type_params,
+                    params: params.clone(),
+                    ret: ret.clone(),
+                    receiver: None,
+                });
+                Type::Function(params, Box::new(ret))
+            }
+
+            ExpressionKind::Binary(..) | ExpressionKind::Unary(UnOp::Not, _) => self.condition(expr).0,
+
+            ExpressionKind::Unary(UnOp::Neg, value) => {
+                let ty = self.expression(value);
+                if maybe_nil(&ty) {
+                    error!(self, span, ErrorKind::MaybeNil(ty));
+                    return Type::Unknown;
+                }
+                match neg(&ty) {
+                    Some(ty) => ty,
+                    None => {
+                        error!(self, span, ErrorKind::TypeError(Op::Neg, vec![ty]));
+                        Type::Unknown
+                    }
+                }
+            }
+
+            ExpressionKind::Cast(value, ty) => {
+                let from = self.expression(value);
+                let to = self.resolve_type(ty);
+                if maybe_nil(&from) {
+                    error!(self, span, ErrorKind::MaybeNil(from));
+                } else if !(castable(&from) && matches!(to, Type::Int | Type::Float | Type::String)) {
+                    error!(self, span, ErrorKind::TypeError(Op::Cast(0), vec![from, to.clone()]),
+                           "Only int, float and str can be cast to each other");
+                }
+                to
+            }
+
+            ExpressionKind::Tuple(values) => {
+                Type::Tuple(values.iter().map(|value| self.expression(value)).collect())
+            }
+
+            ExpressionKind::List(values) => {
+                let tys = values.iter().map(|value| self.expression(value)).collect();
+                Type::List(Box::new(Type::union_of(tys)))
+            }
+
+            ExpressionKind::Dict(pairs) => {
+                let mut keys = HashSet::new();
+                let mut values = HashSet::new();
+                for (key, value) in pairs.iter() {
+                    let key_ty = self.expression(key);
+                    if mutable(&key_ty) {
+                        error!(self, key.span, ErrorKind::MutableKey(key_ty.clone()));
+                    }
+                    keys.insert(key_ty);
+                    values.insert(self.expression(value));
+                }
+                Type::Dict(Box::new(Type::union_of(keys)), Box::new(Type::union_of(values)))
+            }
+
+            ExpressionKind::Int(_) => Type::Int,
+            ExpressionKind::Float(_) => Type::Float,
+            ExpressionKind::Bool(_) => Type::Bool,
+            ExpressionKind::Str(_) => Type::String,
+            ExpressionKind::Nil => Type::Void,
+        }
+    }
+
+    /// Finds what an expression refers to, which is only
+    /// something other than a value for paths of names.
+    fn item(&mut self, expr: &'a Expression) -> Item {
+        match &expr.kind {
+            ExpressionKind::Read(ident) => self.lookup(&ident.name),
+            ExpressionKind::Access(object, field) => match self.item(object) {
+                Item::Namespace(module) => self.named(module, &field.name),
+                Item::Enum(e) => match e.variant(&field.name) {
+                    Some(variant) => Item::Variant(e, variant),
+                    None => Item::Value(Type::Unknown),
+                },
+                Item::Value(ty) => Item::Value(self.field(expr.span, &ty, field)),
+                Item::Variant(..) | Item::Extern(_) => Item::Value(Type::Unknown),
+            },
+            _ => Item::Value(self.expression(expr)),
+        }
+    }
+
+    fn field(&mut self, span: Span, ty: &Type, field: &ast::Identifier) -> Type {
+        if maybe_nil(ty) {
+            error!(self, span, ErrorKind::MaybeNil(ty.clone()));
+            return Type::Unknown;
+        }
+        match ty {
+            Type::Instance(blob) => {
+                if let Some((_, ty)) = blob.fields.borrow().get(&field.name) {
+                    return ty.clone();
+                }
+                if let Some(slot) = blob.methods.borrow().get(&field.name) {
+                    // The instance is passed as the first argument.
+                    if let Type::Function(params, ret) = &self.methods[*slot] {
+                        return Type::Function(params[1..].to_vec(), ret.clone());
+                    }
+                }
+            }
+            Type::Unknown => return Type::Unknown,
+            _ => {}
+        }
+        error!(self, span, ErrorKind::UnknownField(Value::from(ty), field.name.clone()));
+        Type::Unknown
+    }
+
+    fn index(&mut self, span: Span, container: Type, index: Type, index_expr: &Expression) -> Type {
+        match container {
+            Type::List(ty) if index.fits(&Type::Int) => *ty,
+            Type::Tuple(tys) if index.fits(&Type::Int) => match index_expr.kind {
+                ExpressionKind::Int(i) if 0 <= i && (i as usize) < tys.len() => tys[i as usize].clone(),
+                _ => Type::union_of(tys.into_iter().collect()),
+            },
+            Type::Dict(key, value) => {
+                if !key.fits(&index) {
+                    error!(self, span, ErrorKind::TypeMismatch(*key, index),
+                           "Dict key has the wrong type");
+                }
+                *value
+            }
+            Type::Unknown => Type::Unknown,
+            container => {
+                error!(self, span, ErrorKind::TypeError(Op::Index, vec![container, index]),
+                       "Cannot index this type");
+                Type::Unknown
+            }
+        }
+    }
+
+    fn call(&mut self, span: Span, callee: &'a Expression, args: &'a [Expression]) -> Type {
+        let callee = self.item(callee);
+        let arg_tys: Vec<_> = args.iter().map(|arg| self.expression(arg)).collect();
+        match callee {
+            Item::Variant(e, variant) => {
+                if let (Some(payload), [given]) = (&e.variants[variant].1, arg_tys.as_slice()) {
+                    if !matches!(payload, Type::Unknown) && !payload.fits(given) {
+                        error!(self, span, ErrorKind::TypeMismatch(payload.clone(), given.clone()),
+                               "Wrong payload for '{}.{}'", e.name, e.variants[variant].0);
+                    }
+                }
+                Type::Enum(e)
+            }
+            Item::Extern(slot) => self.extern_call(span, slot, args, arg_tys),
+            Item::Value(ty) => match self.call_type(&ty, &arg_tys) {
+                Ok(ty) => ty,
+                Err(kind @ ErrorKind::TypeError(..)) => {
+                    error!(self, span, kind, "Only functions, blobs and coroutines can be called");
+                    Type::Unknown
+                }
+                Err(kind) => {
+                    error!(self, span, kind);
+                    Type::Unknown
+                }
+            },
+            Item::Namespace(_) | Item::Enum(_) => Type::Unknown,
+        }
+    }
+
+    fn call_type(&self, ty: &Type, args: &[Type]) -> Result<Type, ErrorKind> {
+        match ty {
+            Type::Unknown => Ok(Type::Unknown),
+            Type::Blob(blob) => {
+                if !args.is_empty() {
+                    return Err(ErrorKind::ArgumentCount(0, args.len()));
+                }
+                if let Some(field) = blob.missing_field(&HashSet::new()) {
+                    return Err(ErrorKind::UninitializedField(Value::Blob(Rc::clone(blob)), field));
+                }
+                Ok(Type::Instance(Rc::clone(blob)))
+            }
+            Type::Function(params, ret) => {
+                let mut bindings = HashMap::new();
+                let fits = params.len() == args.len()
+                    && params.iter().zip(args.iter()).all(|(a, b)| a.unify(b, &mut bindings));
+                if fits {
+                    Ok(ret.substitute(&bindings))
+                } else {
+                    Err(ErrorKind::ArgumentType(params.clone(), args.to_vec()))
+                }
+            }
+            // What a coroutine yields isn't known until it runs.
+            Type::Coroutine => {
+                if args.is_empty() {
+                    Ok(Type::Unknown)
+                } else {
+                    Err(ErrorKind::ArgumentCount(0, args.len()))
+                }
+            }
+            Type::Union(alternatives) => {
+                let returns: HashSet<_> = alternatives.iter()
+                    .filter_map(|alternative| self.call_type(alternative, args).ok())
+                    .collect();
+                if returns.is_empty() {
+                    Err(ErrorKind::TypeError(Op::Call(args.len()), vec![ty.clone()]))
+                } else {
+                    Ok(Type::union_of(returns))
+                }
+            }
+            _ => Err(ErrorKind::TypeError(Op::Call(args.len()), vec![ty.clone()])),
+        }
+    }
+
+    /// External functions check their own arguments, when given
+    /// values that stand in for the types.
+    fn extern_call(&mut self, span: Span, slot: usize, args: &[Expression], arg_tys: Vec<Type>) -> Type {
+        let (name, function) = &self.functions[slot];
+        let values: Vec<Value> = arg_tys.iter().map(Value::from).collect();
+        let result = function(&values, true);
+
+        // The first value pushed to an empty list decides its type.
+        if name == "push" {
+            if let ([list, _], [Type::List(element), value]) = (args, arg_tys.as_slice()) {
+                if matches!(**element, Type::Unknown) && !matches!(value, Type::Unknown) {
+                    if let Some(id) = self.variable(list) {
+                        self.vars[id] = Type::List(Box::new(value.clone()));
+                    }
+                }
+            }
+        }
+
+        match result {
+            Ok(value) => Type::from(&value),
+            Err(kind) => {
+                error!(self, span, kind);
+                Type::Unknown
+            }
+        }
+    }
+
+    fn instance(&mut self, span: Span, blob: &'a Expression, fields: &'a [(ast::Identifier, Expression)]) -> Type {
+        let blob = self.expression(blob);
+        let given: Vec<_> = fields.iter().map(|(name, value)| (name, self.expression(value))).collect();
+        let blob = match blob {
+            Type::Blob(blob) => blob,
+            Type::Unknown => return Type::Unknown,
+            ty => {
+                error!(self, span, ErrorKind::TypeError(Op::Construct(fields.len()), vec![ty]),
+                       "Only blobs can be constructed");
+                return Type::Unknown;
+            }
+        };
+
+        let mut names = HashSet::new();
+        for (name, given) in given {
+            let ty = blob.fields.borrow().get(&name.name).map(|(_, ty)| ty.clone());
+            match ty {
+                Some(ty) => {
+                    if !(matches!(ty, Type::Unknown) || ty.fits(&given)) {
+                        error!(self, name.span, ErrorKind::TypeMismatch(ty, given),
+                               "Wrong type for field '{}'", name.name);
+                    }
+                }
+                None => {
+                    error!(self, name.span, ErrorKind::UnknownField(Value::Blob(Rc::clone(&blob)), name.name.clone()));
+                }
+            }
+            names.insert(name.name.clone());
+        }
+        if let Some(field) = blob.missing_field(&names) {
+            error!(self, span, ErrorKind::UninitializedField(Value::Blob(Rc::clone(&blob)), field));
+        }
+        Type::Instance(blob)
+    }
+}
+
+/// Checks the types of a parsed program, before it's run.
+pub(crate) fn check(modules: &[Module], functions: &[(String, RustFunction)]) -> Result<Inferred, Vec<Error>> {
+    let mut checker = Checker::new(modules, functions);
+    checker.declare_names(modules);
+    checker.globals(modules);
+
+    let deferred = std::mem::take(&mut checker.deferred);
+    for function in deferred {
+        checker.function_body(function);
+    }
+
+    if checker.errors.is_empty() {
+        let inferred = checker.inferred.iter()
+            .map(|((module, span), id)| ((checker.paths[*module].clone(), *span), checker.vars[*id].clone()))
+            .collect();
+        Ok(inferred)
+    } else {
+        Err(checker.errors)
+    }
+}
@@ -7,13 +7,15 @@ use std::rc::Rc;
 use owo_colors::OwoColorize;
 
 use crate::{Block, BlockLinkState, Op, Prog, UpValue, Value, op};
-use crate::error::{Error, ErrorKind};
+use crate::error::{did_you_mean, Error, ErrorKind, StackFrame};
 use crate::RustFunction;
 use crate::Type;
 
 macro_rules! error {
     ( $thing:expr, $kind:expr) => {
-        return Err($thing.error($kind, None));
+        {
+            return Err($thing.error($kind, None));
+        }
     };
     ( $thing:expr, $kind:expr, $( $msg:expr ),*) => {
         {
@@ -25,7 +27,7 @@ macro_rules! error {
 
 macro_rules! one_op {
     ( $self:expr, $op:expr, $fun:expr ) => {
-        let a = $self.pop();
+        let a = $self.pop()?;
         let b = $fun(&a);
         if b.is_nil() {
             $self.push(b);
@@ -37,7 +39,7 @@ macro_rules! one_op {
 
 macro_rules! two_op {
     ( $self:expr, $op:expr, $fun:expr ) => {
-        let (a, b) = $self.poppop();
+        let (a, b) = $self.poppop()?;
         let c = $fun(&a, &b);
         if c.is_nil() {
             $self.push(c);
@@ -55,21 +57,62 @@ struct Frame {
     contains_upvalues: bool,
 }
 
+/// A function call that can be suspended with `yield` and resumed
+/// later. Holds its own frames and its part of the stack while it
+/// isn't running.
+#[derive(Debug, Default)]
+pub struct Coroutine {
+    /// Stack offsets are relative to the start of `stack`.
+    frames: Vec<Frame>,
+    stack: Vec<Value>,
+    /// Upvalues captured from `stack`, with relative slots.
+    /// They are closed while the coroutine is suspended.
+    upvalues: Vec<(usize, Rc<RefCell<UpValue>>)>,
+    running: bool,
+}
+
+impl Coroutine {
+    fn new(block: Rc<RefCell<Block>>, stack: Vec<Value>) -> Self {
+        Self {
+            frames: vec![Frame {
+                stack_offset: 0,
+                block,
+                ip: 0,
+                contains_upvalues: true,
+            }],
+            stack,
+            upvalues: Vec::new(),
+            running: false,
+        }
+    }
+
+    /// True if the coroutine has returned, and can't be resumed.
+    pub fn is_done(&self) -> bool {
+        self.frames.is_empty() && !self.running
+    }
+}
+
 pub struct VM {
     upvalues: HashMap<usize, Rc<RefCell<UpValue>>>,
 
     stack: Vec<Value>,
     frames: Vec<Frame>,
+    /// The running coroutines, and how many frames
+    /// there were when they were resumed.
+    coroutines: Vec<(Rc<RefCell<Coroutine>>, usize)>,
 
     constants: Vec<Value>,
     strings: Vec<String>,
 
     pub print_bytecode: bool,
     pub print_exec: bool,
-    runtime: bool,
-
+    /// Checks the types of values given to typed variables and fields,
+    /// and returned from external functions, while running. Variables
+    /// are only checked in programs compiled with `strict` set.
+    pub strict: bool,
 
     extern_functions: Vec<RustFunction>,
+    globals: HashMap<String, usize>,
 }
 
 #[derive(Eq, PartialEq)]
@@ -82,22 +125,31 @@ pub enum OpResult {
     Continue,
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
-    pub(crate) fn new() -> Self {
+    /// Creates an empty VM. Give it a program to run with [VM::init].
+    pub fn new() -> Self {
         Self {
             upvalues: HashMap::new(),
 
             stack: Vec::new(),
             frames: Vec::new(),
+            coroutines: Vec::new(),
 
             constants: Vec::new(),
             strings: Vec::new(),
 
             print_bytecode: false,
             print_exec: false,
-            runtime: false,
+            strict: false,
 
-            extern_functions: Vec::new()
+            extern_functions: Vec::new(),
+            globals: HashMap::new(),
         }
     }
 
@@ -117,17 +169,33 @@ impl VM {
         self.stack.push(value);
     }
 
-    fn pop(&mut self) -> Value {
+    fn pop(&mut self) -> Result<Value, Box<Error>> {
         match self.stack.pop() {
-            Some(x) => x,
-            None => self.crash_and_burn(),
+            Some(x) => Ok(x),
+            None => Err(self.invalid(String::from("Popped from an empty stack"))),
+        }
+    }
+
+    fn poppop(&mut self) -> Result<(Value, Value), Box<Error>> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        Ok((a, b))  // this matches the order they were on the stack
+    }
+
+    /// Pops the top `n` values, in the order they were pushed.
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Value>, Box<Error>> {
+        match self.stack.len().checked_sub(n) {
+            Some(start) => Ok(self.stack.split_off(start)),
+            None => Err(self.invalid(format!("Popped {} values from a stack of {}", n, self.stack.len()))),
         }
     }
 
-    fn poppop(&mut self) -> (Value, Value) {
-        let (a, b) = (self.stack.remove(self.stack.len() - 1),
-                      self.stack.remove(self.stack.len() - 1));
-        (b, a)  // this matches the order they were on the stack
+    /// The slot of the value `n` values down from the top of the stack.
+    fn below_top(&self, n: usize) -> Result<usize, Box<Error>> {
+        match self.stack.len().checked_sub(n.saturating_add(1)) {
+            Some(slot) => Ok(slot),
+            None => Err(self.invalid(format!("Looked {} values down a stack of {}", n, self.stack.len()))),
+        }
     }
 
     fn frame(&self) -> &Frame {
@@ -140,65 +208,150 @@ impl VM {
         &mut self.frames[last]
     }
 
-    fn constant(&self, slot: usize) -> &Value {
-        &self.constants[slot]
+    fn constant(&self, slot: usize) -> Result<&Value, Box<Error>> {
+        match self.constants.get(slot) {
+            Some(value) => Ok(value),
+            None => Err(self.invalid(format!("There's no constant in slot {}", slot))),
+        }
     }
 
-    fn ty(&self, slot: usize) -> &Type {
-        match &self.constants[slot] {
-            Value::Ty(ty) => ty,
-            _ => self.crash_and_burn(),
+    fn ty(&self, slot: usize) -> Result<&Type, Box<Error>> {
+        match self.constant(slot)? {
+            Value::Ty(ty) => Ok(ty),
+            value => Err(self.invalid(format!("Expected a type in slot {}, but found {}", slot, value))),
         }
     }
 
-    fn string(&self, slot: usize) -> &String {
-        &self.strings[slot]
+    fn string(&self, slot: usize) -> Result<&String, Box<Error>> {
+        match self.strings.get(slot) {
+            Some(string) => Ok(string),
+            None => Err(self.invalid(format!("There's no string in slot {}", slot))),
+        }
     }
 
-    fn op(&self) -> Op {
-        let ip = self.frame().ip;
-        self.frame().block.borrow().ops[ip]
+    fn op(&self) -> Result<Op, Box<Error>> {
+        let frame = match self.frames.last() {
+            Some(frame) => frame,
+            None => {
+                return Err(Box::new(Error::new_nowhere(ErrorKind::NothingToRun, None)));
+            }
+        };
+        let block = frame.block.borrow();
+        match block.ops.get(frame.ip) {
+            Some(op) => Ok(*op),
+            None => Err(self.invalid(format!("Ran past the end of '{}'", block.name))),
+        }
     }
 
-    fn print_stacktrace(&self) {
-        if !self.runtime { return; }
+    /// How many arguments the function takes.
+    fn arity(&self, block: &Block) -> Result<usize, Box<Error>> {
+        match &block.ty {
+            Type::Function(args, _) => Ok(args.len()),
+            ty => Err(self.invalid(format!("'{}' is called, but has the type '{}'", block.name, ty))),
+        }
+    }
 
-        println!("\n<{}>", "STACK".red());
-        for (i, frame) in self.frames.iter().enumerate() {
-            println!("  {:>3}. {}:{:<4} in {:10}",
-                i,
-                frame.block.borrow().file.display(),
-                frame.block.borrow().line(self.frame().ip),
-                frame.block.borrow().name.blue());
+    /// Captures the upvalues of a function created in the current frame.
+    fn capture_upvalues(&mut self, block: &Block) -> Result<Vec<Rc<RefCell<UpValue>>>, Box<Error>> {
+        let offset = self.frame().stack_offset;
+        let mut ups = Vec::new();
+        for (slot, is_up, _) in block.upvalues.iter() {
+            let up = if *is_up {
+                match self.stack.get(offset) {
+                    Some(Value::Function(local_ups, _)) => local_ups.get(*slot).map(Rc::clone),
+                    _ => None,
+                }
+            } else {
+                Some(Rc::clone(self.find_upvalue(offset + slot)))
+            };
+            match up {
+                Some(up) => ups.push(up),
+                None => {
+                    return Err(self.invalid(format!("'{}' captures upvalue {}, which doesn't exist",
+                                                    block.name, slot)));
+                }
+            }
         }
-        println!("")
+        Ok(ups)
+    }
+
+    /// The calls that are running, outermost first.
+    fn stacktrace(&self) -> Vec<StackFrame> {
+        self.frames.iter()
+            .map(|frame| {
+                let block = frame.block.borrow();
+                StackFrame {
+                    function: block.name.clone(),
+                    file: block.file.clone(),
+                    line: block.line(frame.ip),
+                }
+            })
+            .collect()
     }
 
-    /// Stop the program, violently
-    fn crash_and_burn(&self) -> ! {
-        self.print_stack();
-        println!("\n");
-        self.print_stacktrace();
-        self.frame().block.borrow().debug_print();
-        println!("    ip: {}, line: {}\n",
-            self.frame().ip.blue(),
-            self.frame().block.borrow().line(self.frame().ip).blue());
-        unreachable!();
+    /// Throws away all frames and everything they put on the stack,
+    /// used to get the VM into a callable state after an error.
+    fn unwind(&mut self) {
+        if let Some(frame) = self.frames.first() {
+            let base = frame.stack_offset;
+            for slot in base..self.stack.len() {
+                if self.upvalues.contains_key(&slot) {
+                    let value = self.stack[slot].clone();
+                    self.drop_upvalue(slot, value);
+                }
+            }
+            self.stack.truncate(base);
+        }
+        self.frames.clear();
+        // The frames of running coroutines are gone too.
+        for (co, _) in self.coroutines.drain(..) {
+            co.borrow_mut().running = false;
+        }
     }
 
-    fn error(&self, kind: ErrorKind, message: Option<String>) -> Error {
-        let frame = self.frames.last().unwrap();
-        self.print_stacktrace();
-        Error {
+    /// Errors are boxed while they're passed around inside the VM,
+    /// since they're large and almost every operation can fail.
+    fn error(&self, kind: ErrorKind, message: Option<String>) -> Box<Error> {
+        let frame = match self.frames.last() {
+            Some(frame) => frame,
+            None => return Box::new(Error::new_nowhere(kind, message)),
+        };
+        let notes = kind.notes();
+        Box::new(Error {
             kind,
             file: frame.block.borrow().file.clone(),
-            line: frame.block.borrow().line(frame.ip),
+            span: frame.block.borrow().span(frame.ip),
             message,
+            notes,
+            stack: self.stacktrace(),
+        })
+    }
+
+    /// In strict mode, checks that the value fits
+    /// where a value of the type is expected.
+    fn check_fits(&self, ty: &Type, value: &Value) -> Result<(), Box<Error>> {
+        if !self.strict {
+            return Ok(());
+        }
+        // Type parameters can be anything, both where the value is
+        // expected and in the type of a generic function.
+        let ty = ty.substitute(&HashMap::new());
+        let given = Type::from(value).substitute(&HashMap::new());
+        if ty.fits(&given) {
+            Ok(())
+        } else {
+            Err(self.error(ErrorKind::TypeMismatch(ty, given), None))
         }
     }
 
+    /// An error for a program the compiler would never output,
+    /// the message says what's wrong with it.
+    fn invalid(&self, message: String) -> Box<Error> {
+        self.error(ErrorKind::InvalidProgram, Some(message))
+    }
+
     /// Runs a single operation on the VM
-    fn eval_op(&mut self, op: Op) -> Result<OpResult, Error> {
+    fn eval_op(&mut self, op: Op) -> Result<OpResult, Box<Error>> {
         match op {
             Op::Illegal => {
                 error!(self, ErrorKind::InvalidProgram);
@@ -209,59 +362,84 @@ impl VM {
             }
 
             Op::Pop => {
-                self.pop();
+                self.pop()?;
             }
 
             Op::Tuple(size) => {
-                let values = self.stack.split_off(self.stack.len() - size);
+                let values = self.pop_n(size)?;
                 self.stack.push(Value::Tuple(Rc::new(values)));
             }
 
             Op::List(size) => {
-                let values = self.stack.split_off(self.stack.len() - size);
+                let values = self.pop_n(size)?;
                 self.stack.push(Value::List(Rc::new(RefCell::new(values))));
             }
 
+            Op::Dict(size) => {
+                let values = self.pop_n(size.saturating_mul(2))?;
+                // The typechecker rejects keys that can change, see ErrorKind::MutableKey.
+                #[allow(clippy::mutable_key_type)]
+                let mut dict = HashMap::new();
+                for pair in values.chunks(2) {
+                    dict.insert(pair[0].clone(), pair[1].clone());
+                }
+                self.stack.push(Value::Dict(Rc::new(RefCell::new(dict))));
+            }
+
             Op::PopUpvalue => {
-                let value = self.pop();
+                let value = self.pop()?;
                 let slot = self.stack.len();
                 self.drop_upvalue(slot, value);
             }
 
-            Op::Copy => {
-                let v = self.pop();
-                self.push(v.clone());
-                self.push(v);
+            Op::Copy(n) => {
+                let values = self.pop_n(n)?;
+                // Put them back, followed by the copies.
+                self.stack.extend_from_slice(&values);
+                self.stack.extend(values);
             }
 
             Op::Yield => {
+                let value = self.pop()?;
                 self.frame_mut().ip += 1;
-                return Ok(OpResult::Yield);
+                let (co, depth) = match self.coroutines.pop() {
+                    Some(running) => running,
+                    None => return Ok(OpResult::Yield),
+                };
+
+                let base = self.frames[depth].stack_offset;
+                let mut co = co.borrow_mut();
+                co.frames = self.frames.split_off(depth);
+                for frame in co.frames.iter_mut() {
+                    frame.stack_offset -= base;
+                }
+                for slot in base..self.stack.len() {
+                    if let Some(up) = self.upvalues.remove(&slot) {
+                        up.borrow_mut().close(self.stack[slot].clone());
+                        co.upvalues.push((slot - base, up));
+                    }
+                }
+                co.stack = self.stack.split_off(base);
+                co.running = false;
+                self.push(value);
             }
 
             Op::Constant(value) => {
-                let offset = self.frame().stack_offset;
-                let constant = self.constant(value).clone();
+                let constant = self.constant(value)?.clone();
                 let value = match constant {
                     Value::Function(ups, block) => {
                         if matches!(block.borrow().linking, BlockLinkState::Linked) {
                             Value::Function(ups.clone(), block)
+                        } else if block.borrow().needs_linking() {
+                            error!(self,
+                                   ErrorKind::InvalidProgram,
+                                   "Calling function '{}' before all captured variables are declared",
+                                   block.borrow().name);
                         } else {
-                            let mut ups = Vec::new();
-                            for (slot, is_up, _) in block.borrow().upvalues.iter() {
+                            if !block.borrow().upvalues.is_empty() {
                                 self.frame_mut().contains_upvalues = true;
-                                let up = if *is_up {
-                                    if let Value::Function(local_ups, _) = &self.stack[offset] {
-                                        Rc::clone(&local_ups[*slot])
-                                    } else {
-                                        unreachable!()
-                                    }
-                                } else {
-                                    let slot = self.frame().stack_offset + slot;
-                                    Rc::clone(self.find_upvalue(slot))
-                                };
-                                ups.push(up);
                             }
+                            let ups = self.capture_upvalues(&block.borrow())?;
                             Value::Function(Rc::new(ups), block)
                         }
                     },
@@ -271,24 +449,11 @@ impl VM {
             }
 
             Op::Link(slot) => {
-                let offset = self.frame().stack_offset;
-                let constant = self.constant(slot).clone();
+                let constant = self.constant(slot)?.clone();
                 let constant = match constant {
                     Value::Function(_, block) => {
-                        let mut ups = Vec::new();
-                        for (slot, is_up, _) in block.borrow().upvalues.iter() {
-                            let up = if *is_up {
-                                if let Value::Function(local_ups, _) = &self.stack[offset] {
-                                    Rc::clone(&local_ups[*slot])
-                                } else {
-                                    unreachable!()
-                                }
-                            } else {
-                                let slot = self.frame().stack_offset + slot;
-                                Rc::clone(self.find_upvalue(slot))
-                            };
-                            ups.push(up);
-                        }
+                        block.borrow_mut().link();
+                        let ups = self.capture_upvalues(&block.borrow())?;
                         Value::Function(Rc::new(ups), block)
                     },
                     value => error!(self,
@@ -299,8 +464,8 @@ impl VM {
             }
 
             Op::Index => {
-                let slot = self.stack.pop().unwrap();
-                let val = self.stack.pop().unwrap();
+                let slot = self.pop()?;
+                let val = self.pop()?;
                 match (val, slot) {
                     (Value::Tuple(v), Value::Int(slot)) => {
                         let slot = slot as usize;
@@ -322,6 +487,16 @@ impl VM {
                         }
                         self.stack.push(v[slot].clone());
                     }
+                    (Value::Dict(dict), key) => {
+                        let value = dict.borrow().get(&key).cloned();
+                        match value {
+                            Some(value) => self.stack.push(value),
+                            None => {
+                                self.stack.push(Value::Nil);
+                                error!(self, ErrorKind::UnknownKey(Value::Dict(dict), key));
+                            }
+                        }
+                    }
                     (val, slot) => {
                         self.stack.push(Value::Nil);
                         error!(self, ErrorKind::IndexError(val, slot.into()));
@@ -329,25 +504,108 @@ impl VM {
                 }
             }
 
+            Op::AssignIndex => {
+                let value = self.pop()?;
+                let slot = self.pop()?;
+                let val = self.pop()?;
+                match (val, slot) {
+                    (Value::List(rc_v), Value::Int(slot)) => {
+                        let slot = slot as usize;
+                        let len = rc_v.borrow().len();
+                        if len <= slot {
+                            error!(self, ErrorKind::IndexOutOfBounds(Value::List(rc_v), len, slot));
+                        }
+                        rc_v.borrow_mut()[slot] = value;
+                    }
+                    (Value::Dict(dict), key) => {
+                        dict.borrow_mut().insert(key, value);
+                    }
+                    (val, slot) => {
+                        error!(self, ErrorKind::IndexError(val, slot.into()));
+                    }
+                }
+            }
+
+            Op::IterNext(line) => {
+                let len = self.below_top(1)? + 2;
+                let index = match self.stack[len - 1] {
+                    Value::Int(index) => index as usize,
+                    _ => { error!(self, ErrorKind::InvalidProgram); }
+                };
+                let next = match &self.stack[len - 2] {
+                    Value::List(v) => {
+                        v.borrow().get(index).map(|x| (x.clone(), index + 1))
+                    }
+                    Value::Tuple(v) => {
+                        v.get(index).map(|x| (x.clone(), index + 1))
+                    }
+                    Value::String(s) => {
+                        match s.get(index..) {
+                            Some(rest) => rest.chars().next().map(|c| {
+                                (Value::String(Rc::new(c.to_string())), index + c.len_utf8())
+                            }),
+                            None => error!(self, ErrorKind::InvalidProgram,
+                                           "Cannot iterate from byte {} of a string", index),
+                        }
+                    }
+                    Value::Range(lo, hi) => {
+                        match lo.checked_add(index as i64) {
+                            Some(x) if x < *hi => Some((Value::Int(x), index + 1)),
+                            _ => None,
+                        }
+                    }
+                    value => {
+                        let value = value.clone();
+                        error!(self, ErrorKind::TypeError(op, vec![Type::from(value)]));
+                    }
+                };
+                match next {
+                    Some((value, index)) => {
+                        self.stack[len - 1] = Value::Int(index as i64);
+                        self.push(value);
+                    }
+                    None => {
+                        self.frame_mut().ip = line;
+                        return Ok(OpResult::Continue);
+                    }
+                }
+            }
+
             Op::Get(field) => {
-                let inst = self.pop();
-                let field = self.string(field);
-                if let Value::Instance(ty, values) = inst {
-                    let slot = ty.fields.get(field).unwrap().0;
-                    self.push(values.borrow()[slot].clone());
-                } else {
-                    error!(self, ErrorKind::UnknownField(inst, field.clone()));
+                let inst = self.pop()?;
+                let field = self.string(field)?;
+                let value = match &inst {
+                    Value::Instance(ty, values) => {
+                        if let Some((slot, _)) = ty.fields.borrow().get(field) {
+                            values.borrow().get(*slot).cloned()
+                        } else if let Some(slot) = ty.methods.borrow().get(field) {
+                            let method = self.constant(*slot)?.clone();
+                            Some(Value::BoundMethod(Box::new(inst.clone()), Box::new(method)))
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                match value {
+                    Some(value) => self.push(value),
+                    None => error!(self, ErrorKind::UnknownField(inst, field.clone())),
                 }
             }
 
             Op::Set(field) => {
-                let (inst, value) = self.poppop();
-                let field = self.string(field);
-                if let Value::Instance(ty, values) = inst {
-                    let slot = ty.fields.get(field).unwrap().0;
-                    values.borrow_mut()[slot] = value;
-                } else {
-                    error!(self, ErrorKind::UnknownField(inst, field.clone()));
+                let (inst, value) = self.poppop()?;
+                let field = self.string(field)?;
+                let slot = match &inst {
+                    Value::Instance(ty, _) => ty.fields.borrow().get(field).cloned(),
+                    _ => None,
+                };
+                match (&inst, slot) {
+                    (Value::Instance(_, values), Some((slot, ty))) if slot < values.borrow().len() => {
+                        self.check_fits(&ty, &value)?;
+                        values.borrow_mut()[slot] = value;
+                    }
+                    _ => error!(self, ErrorKind::UnknownField(inst, field.clone())),
                 }
             }
 
@@ -359,7 +617,17 @@ impl VM {
 
             Op::Mul => { two_op!(self, Op::Mul, op::mul); }
 
-            Op::Div => { two_op!(self, Op::Div, op::div); }
+            Op::Div => {
+                if matches!(self.stack.as_slice(), [.., Value::Int(_), Value::Int(0)]) {
+                    let (a, b) = self.poppop()?;
+                    error!(self, ErrorKind::ValueError(op, vec![a, b]), "Division by zero");
+                }
+                if matches!(self.stack.as_slice(), [.., Value::Int(i64::MIN), Value::Int(-1)]) {
+                    let (a, b) = self.poppop()?;
+                    error!(self, ErrorKind::ValueError(op, vec![a, b]), "Integer overflow");
+                }
+                two_op!(self, Op::Div, op::div);
+            }
 
             Op::Equal => { two_op!(self, Op::Equal, op::eq); }
 
@@ -379,7 +647,7 @@ impl VM {
             }
 
             Op::JmpFalse(line) => {
-                if matches!(self.pop(), Value::Bool(false)) {
+                if matches!(self.pop()?, Value::Bool(false)) {
                     self.frame_mut().ip = line;
                     return Ok(OpResult::Continue);
                 }
@@ -387,7 +655,7 @@ impl VM {
 
             Op::JmpNPop(line, to_pop) => {
                 let hi = self.stack.len();
-                let lo = hi - to_pop;
+                let lo = self.below_top(to_pop)? + 1;
                 for slot in lo..hi {
                     if self.upvalues.contains_key(&slot) {
                         let value = self.stack[slot].clone();
@@ -400,7 +668,7 @@ impl VM {
             }
 
             Op::Assert => {
-                if matches!(self.pop(), Value::Bool(false)) {
+                if matches!(self.pop()?, Value::Bool(false)) {
                     error!(self, ErrorKind::AssertFailed);
                 }
                 self.push(Value::Bool(true));
@@ -408,54 +676,127 @@ impl VM {
 
             Op::ReadUpvalue(slot) => {
                 let offset = self.frame().stack_offset;
-                let value = match &self.stack[offset] {
-                    Value::Function(ups, _) => {
-                        ups[slot].borrow().get(&self.stack)
+                let value = match self.stack.get(offset) {
+                    Some(Value::Function(ups, _)) => {
+                        ups.get(slot).and_then(|up| up.borrow().get(&self.stack))
                     }
-                    _ => unreachable!(),
+                    _ => None,
                 };
-                self.push(value);
+                match value {
+                    Some(value) => self.push(value),
+                    None => error!(self, ErrorKind::InvalidProgram, "Cannot read upvalue {}", slot),
+                }
             }
 
             Op::AssignUpvalue(slot) => {
                 let offset = self.frame().stack_offset;
-                let value = self.pop();
-                let slot = match &self.stack[offset] {
-                    Value::Function(ups, _) => Rc::clone(&ups[slot]),
-                    _ => unreachable!(),
+                let value = self.pop()?;
+                let up = match self.stack.get(offset) {
+                    Some(Value::Function(ups, _)) => ups.get(slot).map(Rc::clone),
+                    _ => None,
                 };
-                slot.borrow_mut().set(&mut self.stack, value);
+                if up.and_then(|up| up.borrow_mut().set(&mut self.stack, value)).is_none() {
+                    error!(self, ErrorKind::InvalidProgram, "Cannot assign upvalue {}", slot);
+                }
             }
 
             Op::ReadLocal(slot) => {
                 let slot = self.frame().stack_offset + slot;
-                self.push(self.stack[slot].clone());
+                match self.stack.get(slot) {
+                    Some(value) => self.push(value.clone()),
+                    None => error!(self, ErrorKind::InvalidProgram, "Cannot read local in slot {}", slot),
+                }
             }
 
             Op::AssignLocal(slot) => {
                 let slot = self.frame().stack_offset + slot;
-                self.stack[slot] = self.pop();
+                let value = self.pop()?;
+                match self.stack.get_mut(slot) {
+                    Some(local) => *local = value,
+                    None => error!(self, ErrorKind::InvalidProgram, "Cannot assign local in slot {}", slot),
+                }
+            }
+
+            Op::Cast(ty) => {
+                let value = self.pop()?;
+                let ty = self.ty(ty)?;
+                let cast = op::cast(&value, ty);
+                if cast.is_nil() {
+                    let message = format!("Cannot convert {} to '{}'", value, ty);
+                    error!(self, ErrorKind::ValueError(op, vec![value]), "{}", message);
+                }
+                self.push(cast);
+            }
+
+            Op::Define(ty) => {
+                if self.strict {
+                    let ty = self.ty(ty)?;
+                    match self.stack.last() {
+                        Some(value) => self.check_fits(ty, value)?,
+                        None => error!(self, ErrorKind::InvalidProgram, "Defined a variable on an empty stack"),
+                    }
+                }
+            }
+
+            Op::Is(ty) => {
+                let value = self.pop()?;
+                let ty = self.ty(ty)?;
+                let is = ty.fits(&Type::from(&value));
+                self.push(Value::Bool(is));
+            }
+
+            Op::Variant => {
+                let (variant, payload) = self.poppop()?;
+                match variant {
+                    Value::Variant(e, variant, _) => {
+                        self.push(Value::Variant(e, variant, Box::new(payload)));
+                    }
+                    _ => { error!(self, ErrorKind::InvalidProgram); }
+                }
             }
 
-            Op::Define(_) => {}
+            Op::IsVariant(slot) => {
+                let value = self.pop()?;
+                let is = match (&value, self.constant(slot)?) {
+                    (Value::Variant(a, a_variant, _), Value::Variant(b, b_variant, _)) => {
+                        a == b && a_variant == b_variant
+                    }
+                    _ => false,
+                };
+                self.push(Value::Bool(is));
+            }
+
+            Op::Payload(_) => {
+                match self.pop()? {
+                    Value::Variant(_, _, payload) => self.push(*payload),
+                    _ => { error!(self, ErrorKind::InvalidProgram); }
+                }
+            }
+
+            Op::NoMatch(_) => {
+                let value = self.pop()?;
+                error!(self, ErrorKind::NonExhaustiveMatch(vec![Type::from(value)]));
+            }
 
             Op::Call(num_args) => {
-                let new_base = self.stack.len() - 1 - num_args;
+                let new_base = self.below_top(num_args)?;
+                if let Value::BoundMethod(inst, method) = self.stack[new_base].clone() {
+                    // The method is called with the instance as its first argument.
+                    self.stack[new_base] = *method;
+                    self.stack.insert(new_base + 1, *inst);
+                    return self.eval_op(Op::Call(num_args + 1));
+                }
                 match self.stack[new_base].clone() {
                     Value::Blob(blob) => {
-                        let mut values = Vec::with_capacity(blob.fields.len());
-                        for _ in 0..values.capacity() {
-                            values.push(Value::Nil);
-                        }
-
-                        self.pop();
+                        let values = blob.initial_values();
+                        self.pop()?;
                         self.push(Value::Instance(blob, Rc::new(RefCell::new(values))));
                     }
                     Value::Function(_, block) => {
                         let inner = block.borrow();
-                        let args = inner.args();
-                        if args.len() != num_args {
-                            error!(self, ErrorKind::ArgumentCount(args.len(), num_args));
+                        let args = self.arity(&inner)?;
+                        if args != num_args {
+                            error!(self, ErrorKind::ArgumentCount(args, num_args));
                         }
 
                         #[cfg(debug_assertions)]
@@ -471,39 +812,144 @@ impl VM {
                         return Ok(OpResult::Continue);
                     }
                     Value::ExternFunction(slot) => {
-                        let extern_func = self.extern_functions[slot];
+                        let extern_func = match self.extern_functions.get(slot) {
+                            Some(extern_func) => *extern_func,
+                            None => error!(self, ErrorKind::InvalidProgram,
+                                           "There's no external function in slot {}", slot),
+                        };
                         let res = match extern_func(&self.stack[new_base+1..], false) {
                             Ok(value) => value,
                             Err(ek) => error!(self, ek, "Failed in external function"),
                         };
+                        if self.strict {
+                            // Asks the function what it should return, like the typechecker does.
+                            let args: Vec<_> = self.stack[new_base+1..].iter()
+                                .map(|arg| Value::from(Type::from(arg)))
+                                .collect();
+                            if let Ok(ret) = extern_func(&args, true) {
+                                self.check_fits(&Type::from(&ret), &res)?;
+                            }
+                        }
                         self.stack.truncate(new_base);
                         self.push(res);
                     }
-                    _ => {
-                        unreachable!()
+                    Value::Coroutine(co) => {
+                        if num_args != 0 {
+                            error!(self, ErrorKind::ArgumentCount(0, num_args));
+                        }
+                        let mut inner = co.borrow_mut();
+                        if inner.running {
+                            error!(self, ErrorKind::CannotResume, "The coroutine is already running");
+                        }
+                        if inner.frames.is_empty() {
+                            error!(self, ErrorKind::CannotResume, "The coroutine has returned");
+                        }
+
+                        // The coroutine takes the place of the value it's called through.
+                        self.stack.truncate(new_base);
+                        self.stack.append(&mut inner.stack);
+                        for (slot, up) in inner.upvalues.drain(..) {
+                            let slot = new_base + slot;
+                            let value = up.borrow_mut().reopen(slot);
+                            self.stack[slot] = value;
+                            self.upvalues.insert(slot, up);
+                        }
+                        let depth = self.frames.len();
+                        for mut frame in inner.frames.drain(..) {
+                            frame.stack_offset += new_base;
+                            self.frames.push(frame);
+                        }
+                        inner.running = true;
+                        drop(inner);
+
+                        self.coroutines.push((co, depth));
+                        return Ok(OpResult::Continue);
+                    }
+                    value => {
+                        error!(self, ErrorKind::ValueError(op, vec![value]),
+                               "Only functions, blobs and coroutines can be called");
+                    }
+                }
+            }
+
+            Op::Construct(num_fields) => {
+                let fields = self.pop_n(num_fields.saturating_mul(2))?;
+                let blob = match self.pop()? {
+                    Value::Blob(blob) => blob,
+                    value => error!(self, ErrorKind::ValueError(op, vec![value])),
+                };
+
+                let mut values = blob.initial_values();
+                let mut given = HashSet::new();
+                for field in fields.chunks(2) {
+                    let name = match &field[0] {
+                        Value::String(name) => name.as_ref(),
+                        value => error!(self, ErrorKind::InvalidProgram,
+                                        "Expected the name of a field, but found {}", value),
+                    };
+                    let slot = blob.fields.borrow().get(name).cloned();
+                    if let Some((_, ty)) = &slot {
+                        self.check_fits(ty, &field[1])?;
+                    }
+                    match slot.and_then(|(slot, _)| values.get_mut(slot)) {
+                        Some(value) => *value = field[1].clone(),
+                        None => error!(self, ErrorKind::UnknownField(Value::Blob(blob), name.clone())),
+                    }
+                    given.insert(name.clone());
+                }
+                if let Some(field) = blob.missing_field(&given) {
+                    error!(self, ErrorKind::UninitializedField(Value::Blob(blob), field));
+                }
+                self.push(Value::Instance(blob, Rc::new(RefCell::new(values))));
+            }
+
+            Op::Spawn(num_args) => {
+                let new_base = self.below_top(num_args)?;
+                match self.stack[new_base].clone() {
+                    Value::Function(_, block) => {
+                        let args = self.arity(&block.borrow())?;
+                        if args != num_args {
+                            error!(self, ErrorKind::ArgumentCount(args, num_args));
+                        }
+                        let stack = self.stack.split_off(new_base);
+                        let co = Coroutine::new(block, stack);
+                        self.push(Value::Coroutine(Rc::new(RefCell::new(co))));
+                    }
+                    value => {
+                        error!(self, ErrorKind::ValueError(op, vec![value]),
+                               "Only functions can be spawned");
                     }
                 }
             }
 
             Op::Print => {
-                println!("PRINT: {:?}", self.pop());
+                println!("PRINT: {:?}", self.pop()?);
             }
 
             Op::Return => {
+                let value = self.pop()?;
+                if self.stack.len() <= self.frame().stack_offset {
+                    error!(self, ErrorKind::InvalidProgram, "The function was popped before it returned");
+                }
                 let last = self.frames.pop().unwrap();
-                if self.frames.is_empty() {
-                    return Ok(OpResult::Done);
-                } else {
-                    self.stack[last.stack_offset] = self.pop();
-                    if last.contains_upvalues {
-                        for slot in last.stack_offset+1..self.stack.len() {
-                            if self.upvalues.contains_key(&slot) {
-                                let value = self.stack[slot].clone();
-                                self.drop_upvalue(slot, value);
-                            }
+                self.stack[last.stack_offset] = value;
+                // The outermost frame always closes its upvalues, since
+                // functions called later from the host may still use them.
+                if last.contains_upvalues || self.frames.is_empty() {
+                    for slot in last.stack_offset+1..self.stack.len() {
+                        if self.upvalues.contains_key(&slot) {
+                            let value = self.stack[slot].clone();
+                            self.drop_upvalue(slot, value);
                         }
                     }
-                    self.stack.truncate(last.stack_offset + 1);
+                }
+                self.stack.truncate(last.stack_offset + 1);
+                if self.frames.is_empty() {
+                    return Ok(OpResult::Done);
+                }
+                if matches!(self.coroutines.last(), Some((_, depth)) if *depth == self.frames.len()) {
+                    let (co, _) = self.coroutines.pop().unwrap();
+                    co.borrow_mut().running = false;
                 }
             }
         }
@@ -525,20 +971,27 @@ impl VM {
         println!("{:5} {:05} {:?}",
             self.frame().block.borrow().line(self.frame().ip).blue(),
             self.frame().ip.red(),
-            self.frame().block.borrow().ops[self.frame().ip]);
+            self.frame().block.borrow().ops.get(self.frame().ip));
     }
 
-    #[doc(hidden)]
+    /// Loads the program into the VM and prepares to run the outermost
+    /// block, which sets up the globals and calls `start`.
     pub fn init(&mut self, prog: &Prog) {
-        let block = Rc::clone(&prog.blocks[0]);
         self.constants = prog.constants.clone();
         self.strings = prog.strings.clone();
+        self.globals = prog.globals.clone();
 
         self.extern_functions = prog.functions.clone();
+        self.upvalues.clear();
         self.stack.clear();
         self.frames.clear();
-        self.runtime = true;
+        self.coroutines.clear();
 
+        // Running a program without blocks is an error.
+        let block = match prog.blocks.first() {
+            Some(block) => Rc::clone(block),
+            None => return,
+        };
         self.push(Value::Function(Rc::new(Vec::new()), Rc::clone(&block)));
 
         self.frames.push(Frame {
@@ -552,372 +1005,95 @@ impl VM {
     /// Simulates the program.
     pub fn run(&mut self) -> Result<OpResult, Error> {
         if self.print_bytecode {
-            println!("\n    [[{}]]\n", "RUNNING".red());
-            self.frame().block.borrow().debug_print();
+            if let Some(frame) = self.frames.last() {
+                println!("\n    [[{}]]\n", "RUNNING".red());
+                frame.block.borrow().debug_print();
+            }
         }
 
         loop {
             #[cfg(debug_assertions)]
-            if self.print_exec {
+            if self.print_exec && !self.frames.is_empty() {
                 self.print_stack()
             }
 
-            let op = self.eval_op(self.op())?;
+            let op = match self.op().and_then(|op| self.eval_op(op)) {
+                Ok(op) => op,
+                Err(e) => {
+                    self.unwind();
+                    return Err(*e);
+                }
+            };
             if matches!(op, OpResult::Done | OpResult::Yield) {
                 return Ok(op);
             }
         }
     }
 
-    /// Checks the current operation for type errors.
-    fn check_op(&mut self, op: Op) -> Result<(), Error> {
-        match op {
-            Op::Unreachable => {}
-
-            Op::Jmp(_line) => {}
-
-            Op::Yield => {}
-
-            Op::Constant(value) => {
-                match self.constant(value).clone() {
-                    Value::Function(_, block) => {
-                        self.push(Value::Function(Rc::new(Vec::new()), block.clone()));
-                        if !matches!(block.borrow().linking, BlockLinkState::Linked) {
-                            if block.borrow().needs_linking() {
-                                error!(self,
-                                       ErrorKind::InvalidProgram,
-                                       "Calling function '{}' before all captured variables are declared",
-                                               block.borrow().name);
-                            }
-
-                            let mut types = Vec::new();
-                            for (slot, is_up, ty) in block.borrow().upvalues.iter() {
-                                if *is_up {
-                                    types.push(ty.clone());
-                                } else {
-                                    types.push(Type::from(&self.stack[*slot]));
-                                }
-                            }
-
-                            let mut block_mut = block.borrow_mut();
-                            for (i, (_, is_up, ty)) in block_mut.upvalues.iter_mut().enumerate() {
-                                if *is_up { continue; }
-
-                                let suggestion = &types[i];
-                                if matches!(ty, Type::Unknown) {
-                                    *ty = suggestion.clone();
-                                } else {
-                                    if ty != suggestion {
-                                        error!(self, ErrorKind::CannotInfer(ty.clone(), suggestion.clone()));
-                                    }
-                                }
-                            };
-                        }
-                    },
-                    value => {
-                        self.push(value.clone());
-                    }
-                }
-            }
-
-            Op::Get(field) => {
-                let inst = self.pop();
-                let field = self.string(field);
-                if let Value::Instance(ty, _) = inst {
-                    let value = Value::from(ty.fields.get(field).unwrap().1.clone());
-                    self.push(value);
-                } else {
-                    let field = field.clone();
-                    self.push(Value::Nil);
-                    error!(self, ErrorKind::UnknownField(inst, field));
-                }
-            }
-
-            Op::Set(field) => {
-                let (inst, value) = self.poppop();
-                let field = self.string(field);
-
-                if let Value::Instance(ty, _) = inst {
-                    let ty = &ty.fields.get(field).unwrap().1;
-                    let expected = Type::from(&value);
-                    if ty != &expected {
-                        error!(self, ErrorKind::TypeMismatch(expected, ty.clone()),
-                               "Types of field and variable do not match");
-                    }
-                } else {
-                    error!(self, ErrorKind::UnknownField(inst, field.clone()));
-                }
-            }
-
-            Op::PopUpvalue => {
-                self.pop();
-            }
-
-            Op::ReadUpvalue(slot) => {
-                let value = Value::from(&self.frame().block.borrow().upvalues[slot].2);
-                self.push(value);
-            }
-
-            Op::AssignUpvalue(slot) => {
-                let var = self.frame().block.borrow().upvalues[slot].2.clone();
-                let up = self.pop().into();
-                if var != up {
-                    error!(self, ErrorKind::TypeMismatch(up, var),
-                           "Captured varibles type doesn't match upvalue");
-                }
-            }
-
-            Op::AssignLocal(slot) => {
-                let slot = self.frame().stack_offset + slot;
-                let curr = Type::from(&self.stack[slot]);
-                let other = Type::from(self.pop());
-                if curr != other {
-                    error!(self, ErrorKind::TypeMismatch(curr, other),
-                           "Cannot assign to different type");
-                }
-            }
-
-            Op::Return => {
-                let a = self.pop();
-                let inner = self.frame().block.borrow();
-                let ret = inner.ret();
-                if Type::from(&a) != *ret {
-
-                    error!(self, ErrorKind::TypeMismatch(ret.clone(), a.into()),
-                           "Value does not match return type");
-                }
-            }
-
-            Op::Print => {
-                self.pop();
-            }
-
-            Op::Define(ty) => {
-                let ty = self.ty(ty);
-                let top_type = self.stack.last().unwrap().into();
-                match (ty, top_type) {
-                    (Type::Unknown, top_type)
-                        if top_type != Type::Unknown => {}
-                    (a, b) if a.fits(&b) => {
-                        let last = self.stack.len() - 1;
-                        self.stack[last] = Value::from(a);
-                    }
-                    (a, b) => {
-                        error!(self, ErrorKind::TypeMismatch(a.clone(), b.clone()),
-                               "Cannot assign mismatching types");
-                    }
-                }
-            }
-
-            Op::Link(slot) => {
-                match self.constant(slot).clone() {
-                    Value::Function(_, block) => {
-                        block.borrow_mut().link();
-
-                        let mut types = Vec::new();
-                        for (slot, is_up, ty) in block.borrow().upvalues.iter() {
-                            if *is_up {
-                                types.push(ty.clone());
-                            } else {
-                                types.push(Type::from(&self.stack[*slot]));
-                            }
-                        }
-
-                        let mut block_mut = block.borrow_mut();
-                        for (i, (_, is_up, ty)) in block_mut.upvalues.iter_mut().enumerate() {
-                            if *is_up { continue; }
-
-                            let suggestion = &types[i];
-                            if matches!(ty, Type::Unknown) {
-                                *ty = suggestion.clone();
-                            } else {
-                                if ty != suggestion {
-                                    error!(self, ErrorKind::CannotInfer(ty.clone(), suggestion.clone()));
-                                }
-                            }
-                        }
-                    }
-                    value => {
-                        error!(self,
-                            ErrorKind::TypeError(op, vec![Type::from(&value)]),
-                            "Cannot link non-function {:?}", value);
-                    }
-                };
-            }
-
-            Op::Index => {
-                let (a, b) = self.poppop();
-                match (Type::from(a), Type::from(b)) {
-                    (Type::List(a), b) if b.fits(&Type::Int) => {
-                        self.push(Value::from(a.as_ref()));
-                    }
-                    (Type::Tuple(a), b) if b.fits(&Type::Int) => {
-                        self.push(
-                            Value::Union(a.iter().map(|x| Value::from(x)).collect())
-                        );
-                    }
-                    _ => {
-                        self.push(Value::Nil);
-                    }
-                }
-            }
-
-            Op::Call(num_args) => {
-                let new_base = self.stack.len() - 1 - num_args;
-                let callable = &self.stack[new_base];
-
-                let call_callable = |callable: &Value| {
-                    let args = &self.stack[new_base+1..];
-                    let args: Vec<_> = args.iter().map(|x| x.into()).collect();
-                    match callable {
-                        Value::Blob(blob) => {
-                            let mut values = Vec::with_capacity(blob.fields.len());
-                            for _ in 0..values.capacity() {
-                                values.push(Value::Nil);
-                            }
-
-                            for (slot, ty) in blob.fields.values() {
-                                values[*slot] = ty.into();
-                            }
-
-                            Ok(Value::Instance(blob.clone(), Rc::new(RefCell::new(values))))
-                        }
-
-                        Value::Function(_, block) => {
-                            let inner = block.borrow();
-                            let fargs = inner.args();
-                            if fargs != &args {
-                                Err(ErrorKind::ArgumentType(args.clone(), args))
-                            } else {
-                                Ok(inner.ret().into())
-                            }
-
-                        }
-
-                        Value::ExternFunction(slot) => {
-                            let extern_func = self.extern_functions[*slot];
-                            extern_func(&self.stack[new_base+1..], true)
-                        }
-
-                        _ => {
-                            Err(ErrorKind::InvalidProgram)
-                        }
-                    }
-                };
-
-                let mut err = None;
-                self.stack[new_base] = match callable {
-                    Value::Union(alts) => {
-                        let mut returns = HashSet::new();
-                        for alt in alts.iter() {
-                            if let Ok(res) = call_callable(&alt) {
-                                returns.insert(res);
-                            }
-                        }
-                        if returns.is_empty() {
-                            err = Some(ErrorKind::InvalidProgram);
-                            Value::Nil
-                        } else {
-                            Value::Union(returns)
-                        }
-                    },
-                    _ => {
-                        match call_callable(callable) {
-                            Err(e) => {
-                                err = Some(e);
-                                Value::Nil
-                            },
-                            Ok(v) => v
-                        }
-                    }
-                };
-                self.stack.truncate(new_base + 1);
-                if err.is_some() {
-                    error!(self, err.unwrap());
-                }
-            }
-
-            Op::JmpFalse(_) => {
-                match self.pop() {
-                    Value::Bool(_) => {},
-                    a => { error!(self, ErrorKind::TypeError(op, vec![a.into()])) },
-                }
-            }
-
-            Op::JmpNPop(_, _) => {}
+    /// Calls the global function `name` with the given arguments, and
+    /// runs until it returns or yields. The program should have been run
+    /// to completion with [VM::run] first, so all globals are set.
+    ///
+    /// Returns the value the function returned, or `None` if it yielded.
+    /// A yielded call is continued with [VM::resume].
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Option<Value>, Error> {
+        if !self.frames.is_empty() {
+            return Err(Error::new_nowhere(ErrorKind::VMBusy,
+                Some(format!("Cannot call '{}', the VM is still running", name))));
+        }
 
+        let function = match self.globals.get(name).and_then(|slot| self.constants.get(*slot)) {
+            Some(function @ Value::Function(_, _)) => function.clone(),
             _ => {
-                self.eval_op(op)?;
-                return Ok(())
+                let mut error = Error::new_nowhere(ErrorKind::UnknownFunction(name.to_string()), None);
+                let functions = self.globals.iter()
+                    .filter(|(_, slot)| matches!(self.constants.get(**slot), Some(Value::Function(..))))
+                    .map(|(name, _)| name.as_str());
+                error.notes.extend(did_you_mean(name, functions));
+                return Err(error);
+            }
+        };
+        let block = match &function {
+            Value::Function(_, block) => Rc::clone(block),
+            _ => unreachable!(),
+        };
+
+        let params = match &block.borrow().ty {
+            Type::Function(params, _) => params.clone(),
+            ty => {
+                return Err(Error::new_nowhere(ErrorKind::InvalidProgram,
+                    Some(format!("'{}' is called, but has the type '{}'", name, ty))));
             }
+        };
+        if params.len() != args.len() {
+            return Err(Error::new_nowhere(ErrorKind::ArgumentCount(params.len(), args.len()),
+                Some(format!("When calling '{}'", name))));
         }
-        self.frame_mut().ip += 1;
-        Ok(())
-    }
-
-    fn typecheck_block(&mut self, block: Rc<RefCell<Block>>) -> Vec<Error> {
-        self.stack.clear();
-        self.frames.clear();
-
-        self.push(Value::Function(Rc::new(Vec::new()), Rc::clone(&block)));
-        for arg in block.borrow().args() {
-            self.push(arg.into());
+        let given: Vec<Type> = args.iter().map(Type::from).collect();
+        if params.iter().zip(given.iter()).any(|(param, arg)| !param.fits(arg)) {
+            return Err(Error::new_nowhere(ErrorKind::ArgumentType(params, given),
+                Some(format!("When calling '{}'", name))));
         }
 
+        let stack_offset = self.stack.len();
+        self.push(function);
+        self.stack.extend_from_slice(args);
         self.frames.push(Frame {
-            stack_offset: 0,
+            stack_offset,
             block,
             ip: 0,
-            contains_upvalues: false,
+            contains_upvalues: true,
         });
-
-        if self.print_bytecode {
-            println!("\n    [[{} - {}]]\n", "TYPECHECKING".purple(), self.frame().block.borrow().name);
-            self.frame().block.borrow().debug_print();
-        }
-
-        let mut errors = Vec::new();
-        loop {
-            let ip = self.frame().ip;
-            if ip >= self.frame().block.borrow().ops.len() {
-                break;
-            }
-
-            #[cfg(debug_assertions)]
-            if self.print_exec {
-                self.print_stack()
-            }
-
-            if let Err(e) = self.check_op(self.op()) {
-                errors.push(e);
-                self.frame_mut().ip += 1;
-            }
-
-            if !self.stack.is_empty() {
-                let ident = self.pop().identity();
-                self.push(ident);
-            }
-        }
-        errors
+        self.resume()
     }
 
-    // Checks the program for type errors.
-    pub(crate) fn typecheck(&mut self, prog: &Prog) -> Result<(), Vec<Error>> {
-        let mut errors = Vec::new();
-
-        self.constants = prog.constants.clone();
-        self.strings = prog.strings.clone();
-        self.runtime = false;
-
-        self.extern_functions = prog.functions.clone();
-        for block in prog.blocks.iter() {
-            errors.append(&mut self.typecheck_block(Rc::clone(block)));
-        }
-
-        if errors.is_empty() {
-            Ok(())
-        } else {
-            Err(errors)
+    /// Continues running after a yield. Returns the value the outermost
+    /// function returned, or `None` if it yielded again.
+    pub fn resume(&mut self) -> Result<Option<Value>, Error> {
+        match self.run()? {
+            OpResult::Done => Ok(Some(self.pop().map_err(|e| *e)?)),
+            OpResult::Yield => Ok(None),
+            OpResult::Continue => unreachable!(),
         }
     }
 }